@@ -36,15 +36,31 @@ pub struct GatewayEvent {
 #[derive(Debug)]
 pub enum Event {
     Hello(Hello),
+    Ready(Ready),
+    Resumed,
+    /// Discord wants a heartbeat right away
+    Heartbeat,
+    HeartbeatAck,
+    /// We should reconnect and resume
+    Reconnect,
+    /// Whether or not the session can be resumed
+    InvalidSession(bool),
     MessageCreate(Message),
     MessageDelete(MessageDelete),
     MessageUpdate(Message),
     MessageReactionAdd(MessageReactionAdd),
+    MessageReactionRemove(MessageReactionRemove),
     PresenceUpdate(PresenceUpdate),
     SessionsReplace(Vec<SessionsReplace>),
     MessageAck(MessageAck),
 }
 
+// TODO: Ready has a lot more fields, but all we need is the session for resuming
+#[derive(Debug, Deserialize)]
+pub struct Ready {
+    pub session_id: String,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct MessageAck {
     pub channel_id: String,
@@ -71,6 +87,7 @@ pub struct Message {
     pub edited_timestamp: Option<String>,
     pub tts: Option<bool>,
     pub mention_everyone: Option<bool>,
+    pub attachments: Option<Vec<crate::Attachment>>,
 }
 #[derive(Debug, Deserialize)]
 pub struct MessageDelete {
@@ -88,6 +105,15 @@ pub struct MessageReactionAdd {
     pub emoji: crate::Emoji,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct MessageReactionRemove {
+    pub user_id: Snowflake,
+    pub channel_id: Snowflake,
+    pub message_id: Snowflake,
+    pub guild_id: Option<Snowflake>,
+    pub emoji: crate::Emoji,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct PresenceUpdate {
     pub user: PartialUser,
@@ -224,6 +250,12 @@ impl<'de> Deserialize<'de> for GatewayEvent {
                 let t = t.ok_or_else(|| serde::de::Error::missing_field("t"))?;
                 let d: Option<Event> = match op {
                     0 => match (t, d) {
+                        (Some("READY"), Some(d)) => {
+                            let inner = serde_json::from_value(d)
+                                .map_err(|e| serde::de::Error::custom(e))?;
+                            Some(Event::Ready(inner))
+                        }
+                        (Some("RESUMED"), _) => Some(Event::Resumed),
                         (Some("MESSAGE_CREATE"), Some(d)) => {
                             let inner = serde_json::from_value(d)
                                 .map_err(|e| serde::de::Error::custom(e))?;
@@ -234,6 +266,11 @@ impl<'de> Deserialize<'de> for GatewayEvent {
                                 .map_err(|e| serde::de::Error::custom(e))?;
                             Some(Event::MessageReactionAdd(inner))
                         }
+                        (Some("MESSAGE_REACTION_REMOVE"), Some(d)) => {
+                            let inner = serde_json::from_value(d)
+                                .map_err(|e| serde::de::Error::custom(e))?;
+                            Some(Event::MessageReactionRemove(inner))
+                        }
                         (Some("MESSAGE_UPDATE"), Some(d)) => {
                             let inner = serde_json::from_value(d)
                                 .map_err(|e| serde::de::Error::custom(e))?;
@@ -259,12 +296,8 @@ impl<'de> Deserialize<'de> for GatewayEvent {
                                 .map_err(|e| serde::de::Error::custom(e))?;
                             Some(Event::SessionsReplace(inner))
                         }
-                        _ => {
-                            return Err(serde::de::Error::custom(format!(
-                                "Unable to deserialize message with type {:?}",
-                                t
-                            )))
-                        }
+                        // There are a lot of dispatch events we don't care about
+                        _ => None,
                     },
                     10 => {
                         if let Some(d) = d {
@@ -275,17 +308,12 @@ impl<'de> Deserialize<'de> for GatewayEvent {
                             None
                         }
                     }
-                    11 => None,
-                    /*
-                    1 => {
-                        if let Some(d) = d {
-                            let inner = serde_json::from_value::<Heartbeat>(d).map_err(|e| serde::de::Error::custom(e))?;
-                            Some(Event::Heartbeat(inner))
-                        } else {
-                            None
-                        }
-                    }
-                    */
+                    1 => Some(Event::Heartbeat),
+                    7 => Some(Event::Reconnect),
+                    9 => Some(Event::InvalidSession(
+                        d.and_then(|d| d.as_bool()).unwrap_or(false),
+                    )),
+                    11 => Some(Event::HeartbeatAck),
                     _ => {
                         return Err(serde::de::Error::custom(format!(
                             "Unrecognized opcode {}",
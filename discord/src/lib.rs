@@ -7,13 +7,11 @@ pub mod gateway;
 pub const BASE_URL: &'static str = "https://discordapp.com/api";
 
 #[derive(Deserialize)]
-#[serde(deny_unknown_fields)]
 pub struct GatewayResponse {
     pub url: String,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
-#[serde(deny_unknown_fields)]
 pub struct Error {
     // for rate limits
     pub global: Option<bool>,
@@ -25,7 +23,6 @@ pub struct Error {
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
-#[serde(deny_unknown_fields)]
 pub struct User {
     pub id: Snowflake,
     pub username: String,
@@ -52,7 +49,6 @@ pub struct GuildMember {
 }
 
 #[derive(Clone, Debug, Deserialize)]
-#[serde(deny_unknown_fields)]
 pub struct Role {
     pub color: u32,
     pub hoist: bool,
@@ -64,7 +60,8 @@ pub struct Role {
     pub position: u64,
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+// Snowflakes start with a timestamp, so they sort by when they were made
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Snowflake(u64);
 
 impl ::std::fmt::Display for Snowflake {
@@ -73,6 +70,14 @@ impl ::std::fmt::Display for Snowflake {
     }
 }
 
+impl ::std::str::FromStr for Snowflake {
+    type Err = ::std::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(Snowflake)
+    }
+}
+
 use serde::de::{self, Visitor};
 use std::fmt;
 struct SnowflakeVisitor;
@@ -114,7 +119,6 @@ impl Serialize for Snowflake {
 }
 
 #[derive(Clone, Debug, Deserialize)]
-#[serde(deny_unknown_fields)]
 pub struct Guild {
     pub id: Snowflake,
     pub name: String,
@@ -124,7 +128,6 @@ pub struct Guild {
 }
 
 #[derive(Clone, Debug, Deserialize)]
-#[serde(deny_unknown_fields)]
 pub struct Channel {
     pub id: Snowflake,
     #[serde(rename = "type")]
@@ -195,7 +198,6 @@ impl<'de> ::serde::Deserialize<'de> for ChannelType {
 }
 
 #[derive(Clone, Debug, Deserialize)]
-#[serde(deny_unknown_fields)]
 pub struct Overwrite {
     pub id: Snowflake,
     #[serde(rename = "type")]
@@ -220,7 +222,6 @@ struct MessageAck {
 }
 
 #[derive(Clone, Debug, Deserialize)]
-#[serde(deny_unknown_fields)]
 pub struct Message<'a> {
     pub id: Snowflake,
     pub channel_id: Snowflake,
@@ -249,7 +250,6 @@ pub struct Message<'a> {
 }
 
 #[derive(Clone, Debug, Deserialize)]
-#[serde(deny_unknown_fields)]
 pub struct Attachment {
     pub id: Snowflake,
     pub filename: String,
@@ -261,7 +261,6 @@ pub struct Attachment {
 }
 
 #[derive(Clone, Debug, Deserialize)]
-#[serde(deny_unknown_fields)]
 pub struct Embed {
     pub title: Option<String>,
     #[serde(rename = "type")]
@@ -280,7 +279,6 @@ pub struct Embed {
 }
 
 #[derive(Clone, Debug, Deserialize)]
-#[serde(deny_unknown_fields)]
 pub struct Footer {
     pub text: String,
     pub icon_url: Option<String>,
@@ -288,7 +286,6 @@ pub struct Footer {
 }
 
 #[derive(Clone, Debug, Deserialize)]
-#[serde(deny_unknown_fields)]
 pub struct EmbedField {
     pub inline: bool,
     pub name: String,
@@ -296,7 +293,6 @@ pub struct EmbedField {
 }
 
 #[derive(Clone, Debug, Deserialize)]
-#[serde(deny_unknown_fields)]
 pub struct Image {
     pub height: u64,
     pub url: String,
@@ -305,7 +301,6 @@ pub struct Image {
 }
 
 #[derive(Clone, Debug, Deserialize)]
-#[serde(deny_unknown_fields)]
 pub struct Provider {
     pub name: String,
     pub url: Option<String>,
@@ -314,7 +309,6 @@ pub struct Provider {
 }
 
 #[derive(Clone, Debug, Deserialize)]
-#[serde(deny_unknown_fields)]
 pub struct Video {
     pub height: u64,
     pub width: u64,
@@ -323,7 +317,6 @@ pub struct Video {
 }
 
 #[derive(Clone, Debug, Deserialize)]
-#[serde(deny_unknown_fields)]
 pub struct EmbedAuthor {
     pub url: Option<String>,
     pub name: String,
@@ -333,23 +326,21 @@ pub struct EmbedAuthor {
 }
 
 #[derive(Clone, Debug, Deserialize)]
-#[serde(deny_unknown_fields)]
 pub struct Reaction {
-    count: u64,
-    me: bool,
-    emoji: Emoji,
+    pub count: u64,
+    pub me: bool,
+    pub emoji: Emoji,
 }
 
 #[derive(Clone, Debug, Deserialize)]
-#[serde(deny_unknown_fields)]
 pub struct Emoji {
-    id: Option<Snowflake>,
-    name: String,
-    roles: Option<Vec<Role>>,
-    user: Option<User>,
-    require_colons: Option<bool>,
-    managed: Option<bool>,
-    animated: Option<bool>,
+    pub id: Option<Snowflake>,
+    pub name: String,
+    pub roles: Option<Vec<Role>>,
+    pub user: Option<User>,
+    pub require_colons: Option<bool>,
+    pub managed: Option<bool>,
+    pub animated: Option<bool>,
 }
 
 // All this was taken from spacemaniac/discord-rs
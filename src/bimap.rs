@@ -23,8 +23,13 @@ where
     pub fn insert<IL: Into<L>, IR: Into<R>>(&mut self, left: IL, right: IR) {
         let the_left = left.into();
         let the_right = right.into();
-        self.left_to_right
-            .insert(the_left.clone(), the_right.clone());
+        // Forget the old name of anything that's being renamed
+        if let Some(old_right) = self
+            .left_to_right
+            .insert(the_left.clone(), the_right.clone())
+        {
+            self.right_to_left.remove(&old_right);
+        }
         self.right_to_left.insert(the_right, the_left);
     }
 
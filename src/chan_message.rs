@@ -1,54 +1,48 @@
-use crate::conn::{self, DateTime};
+use crate::conn::{self, DateTime, MessageId};
 use chrono::Timelike;
 
-lazy_static::lazy_static! {
-    static ref COLORS: Vec<::termion::color::AnsiValue> = {
-        let mut c = Vec::with_capacity(45);
-        for r in 1..6 {
-            for g in 1..6 {
-                for b in 1..6 {
-                    if r < 2 || g < 2 || b < 2 {
-                        c.push(::termion::color::AnsiValue::rgb(r, g, b));
-                    }
-                }
-            }
-        }
-        c
-    };
-}
-
-fn djb2(input: &str) -> u64 {
-    let mut hash: u64 = 5381;
-
-    for c in input.bytes() {
-        hash = (hash << 5).wrapping_add(hash).wrapping_add(u64::from(c));
-    }
-    hash
-}
-
+#[derive(Clone)]
 pub struct ChanMessage {
+    id: MessageId,
     formatted_width: Option<usize>,
     pub raw: String,
     formatted: String,
     sender: String,
     timestamp: DateTime,
     reactions: Vec<(String, usize)>,
+    thread: Option<MessageId>,
+    broadcast: bool,
+    reply_count: usize,
+    edited: bool,
+    /// Someone else wrote it with us in mind, worked out when it arrives rather than on every
+    /// redraw
+    mentions_us: bool,
 }
 
 impl From<conn::Message> for ChanMessage {
     fn from(message: conn::Message) -> ChanMessage {
         ChanMessage {
+            id: message.id,
             formatted_width: None,
             raw: message.contents,
             formatted: String::new(),
             sender: message.sender,
             timestamp: message.timestamp,
             reactions: message.reactions,
+            thread: message.thread,
+            broadcast: message.broadcast,
+            reply_count: message.reply_count,
+            edited: message.edited,
+            mentions_us: false,
         }
     }
 }
 
 impl ChanMessage {
+    pub fn id(&self) -> &MessageId {
+        &self.id
+    }
+
     // Prevent mutating the timestamp but make it visible
     pub fn timestamp(&self) -> &DateTime {
         &self.timestamp
@@ -58,8 +52,30 @@ impl ChanMessage {
         &self.sender
     }
 
-    pub fn color(&self) -> termion::color::AnsiValue {
-        COLORS[djb2(&self.sender) as usize % COLORS.len()]
+    pub fn thread(&self) -> Option<&MessageId> {
+        self.thread.as_ref()
+    }
+
+    pub fn broadcast(&self) -> bool {
+        self.broadcast
+    }
+
+    pub fn mentions_us(&self) -> bool {
+        self.mentions_us
+    }
+
+    pub fn set_mentions_us(&mut self, mentions_us: bool) {
+        self.mentions_us = mentions_us;
+    }
+
+    pub fn add_reply(&mut self) {
+        self.reply_count += 1;
+        self.formatted_width = None;
+    }
+
+    pub fn remove_reply(&mut self) {
+        self.reply_count = self.reply_count.saturating_sub(1);
+        self.formatted_width = None;
     }
 
     pub fn add_reaction(&mut self, reaction: &str) {
@@ -85,6 +101,7 @@ impl ChanMessage {
     // TODO: This should probably just go away if we have immutable message history
     pub fn edit_to(&mut self, contents: String) {
         self.raw = contents;
+        self.edited = true;
         self.formatted_width = None;
     }
 
@@ -104,7 +121,7 @@ impl ChanMessage {
         self.formatted.clear();
         let indent_str = "    ";
         // 2 for the `: ` after the name, 8 for the time
-        let sender_spacer = " ".repeat(self.sender.chars().count() + 2 + 8);
+        let sender_spacer = " ".repeat(crate::curses::display_width(&self.sender) + 2 + 8);
         let wrapper = Wrapper::with_splitter(width, NoHyphenation)
             .subsequent_indent(indent_str)
             .initial_indent(indent_str)
@@ -114,7 +131,13 @@ impl ChanMessage {
             .initial_indent(&sender_spacer)
             .break_words(true);
 
-        for (l, line) in self.raw.lines().enumerate() {
+        let text = if self.edited {
+            std::borrow::Cow::Owned(format!("{} (edited)", self.raw))
+        } else {
+            std::borrow::Cow::Borrowed(&self.raw)
+        };
+
+        for (l, line) in text.lines().enumerate() {
             // wrap_iter produces nothing on an empty line, so we have to supply the required newline
             if line == "" {
                 self.formatted.push('\n');
@@ -176,6 +199,19 @@ impl ChanMessage {
             self.formatted.pop();
         }
 
+        // Replies aren't shown in the channel, so point out where they are
+        if self.broadcast {
+            let _ = write!(self.formatted, "\n{}replied to a thread", indent_str);
+        } else if self.reply_count == 1 {
+            let _ = write!(self.formatted, "\n{}1 reply", indent_str);
+        } else if self.reply_count > 1 {
+            let _ = write!(
+                self.formatted,
+                "\n{}{} replies",
+                indent_str, self.reply_count
+            );
+        }
+
         &self.formatted
     }
 }
@@ -1,4 +1,11 @@
-use termion;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use futures::channel::mpsc::UnboundedSender;
+use futures::future::BoxFuture;
+use futures::stream::StreamExt;
+use log::error;
+use tokio::task::JoinHandle;
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct DateTime(::chrono::DateTime<::chrono::Utc>);
@@ -46,93 +53,428 @@ impl DateTime {
     }
 }
 
+macro_rules! make_handle {
+    ($(#[$attr:meta])* $name:ident) => {
+        $(#[$attr])*
+        ///
+        /// What's inside is up to each backend, the frontend only ever compares them.
+        #[derive(Clone, Debug, PartialEq, Eq, Hash)]
+        pub struct $name(String);
+
+        impl From<String> for $name {
+            fn from(id: String) -> $name {
+                $name(id)
+            }
+        }
+
+        impl<'a> From<&'a str> for $name {
+            fn from(id: &'a str) -> $name {
+                $name(id.to_string())
+            }
+        }
+
+        impl ::std::fmt::Display for $name {
+            fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+                write!(f, "{}", self.0)
+            }
+        }
+
+        impl $name {
+            pub fn as_str(&self) -> &str {
+                &self.0
+            }
+        }
+    };
+}
+
+make_handle!(
+    /// Identifies a server, unique across all connections
+    ServerId
+);
+make_handle!(
+    /// Identifies a channel within a server
+    ChannelId
+);
+make_handle!(
+    /// Identifies a message within a channel
+    MessageId
+);
+
+impl From<::slack::TeamId> for ServerId {
+    fn from(id: ::slack::TeamId) -> ServerId {
+        ServerId(id.to_string())
+    }
+}
+
+impl From<::slack::ConversationId> for ChannelId {
+    fn from(id: ::slack::ConversationId) -> ChannelId {
+        ChannelId(id.to_string())
+    }
+}
+
+impl From<::slack::Timestamp> for MessageId {
+    fn from(ts: ::slack::Timestamp) -> MessageId {
+        MessageId(ts.to_string())
+    }
+}
+
+impl From<::discord::Snowflake> for ServerId {
+    fn from(id: ::discord::Snowflake) -> ServerId {
+        ServerId(id.to_string())
+    }
+}
+
+impl From<::discord::Snowflake> for ChannelId {
+    fn from(id: ::discord::Snowflake) -> ChannelId {
+        ChannelId(id.to_string())
+    }
+}
+
+impl From<::discord::Snowflake> for MessageId {
+    fn from(id: ::discord::Snowflake) -> MessageId {
+        MessageId(id.to_string())
+    }
+}
+
 pub struct Message {
-    pub server: String,
-    pub channel: String,
+    pub id: MessageId,
+    pub server: ServerId,
+    pub channel: ChannelId,
     pub sender: String,
     pub contents: String,
     pub timestamp: DateTime,
     pub reactions: Vec<(String, usize)>,
+    /// The message that started the thread this is a reply in
+    pub thread: Option<MessageId>,
+    /// A reply that was also sent to the channel
+    pub broadcast: bool,
+    pub reply_count: usize,
+    /// Has been changed since it was sent
+    pub edited: bool,
 }
 
 #[derive(Clone, Copy, Debug, PartialOrd, PartialEq, Eq, Ord)]
 pub enum ChannelType {
     Normal,
     DirectMessage,
+    /// Where a backend puts what it found for a search, copies of messages that live elsewhere
+    Search,
 }
 
 /// Events that a connection can send to a frontend
 pub enum ConnEvent {
     Message(Message),
     MessageEdited {
-        server: String,
-        channel: String,
+        server: ServerId,
+        channel: ChannelId,
+        id: MessageId,
         contents: String,
-        timestamp: DateTime,
+    },
+    MessageDeleted {
+        server: ServerId,
+        channel: ChannelId,
+        id: MessageId,
     },
     HistoryLoaded {
-        server: String,
-        channel: String,
+        server: ServerId,
+        channel: ChannelId,
         messages: Vec<Message>,
     },
+    /// Every message in a thread, including the one that started it
+    ThreadLoaded {
+        server: ServerId,
+        channel: ChannelId,
+        thread: MessageId,
+        messages: Vec<Message>,
+    },
+    /// Everything a server-side search found, which replaces whatever is in the channel the
+    /// server keeps results in
+    ///
+    /// These are copies of messages from other channels, so unlike history they aren't stored.
+    SearchResults {
+        server: ServerId,
+        channel: ChannelId,
+        messages: Vec<Message>,
+    },
+    /// A link to a message that was asked for with `TuiEvent::GetPermalink`
+    Permalink {
+        server: ServerId,
+        url: String,
+    },
     Input(termion::event::Event),
+    /// Text pasted into the terminal, which arrives all at once instead of as keypresses
+    Paste(String),
     Error(String),
     ServerConnected(crate::tui::Server),
+    /// The connection was lost, and the backend is trying to get it back
+    ServerDisconnected {
+        server: ServerId,
+    },
+    /// The connection is back, anything sent while it was down may have been missed
+    ServerReconnected {
+        server: ServerId,
+    },
     MarkChannelRead {
-        server: String,
-        channel: String,
+        server: ServerId,
+        channel: ChannelId,
         read_at: Option<DateTime>,
         latest: Option<DateTime>,
     },
     ReactionAdded {
-        server: String,
-        channel: String,
-        timestamp: DateTime,
+        server: ServerId,
+        channel: ChannelId,
+        id: MessageId,
         reaction: String,
     },
     ReactionRemoved {
-        server: String,
-        channel: String,
-        timestamp: DateTime,
+        server: ServerId,
+        channel: ChannelId,
+        id: MessageId,
         reaction: String,
     },
     Resize,
+    /// A burst of notifications is over, and whatever it held back can be sent
+    NotificationsDue,
 }
 
 /// Messages sent by a frontend to a connection
 #[derive(Debug)]
 pub enum TuiEvent {
     SendMessage {
-        server: String,
-        channel: String,
+        server: ServerId,
+        channel: ChannelId,
         contents: String,
+        /// Reply in this thread instead of the channel, or in both if `broadcast` is set
+        thread: Option<MessageId>,
+        broadcast: bool,
     },
     MarkRead {
-        server: String,
-        channel: String,
+        server: ServerId,
+        channel: ChannelId,
     },
     Command {
-        server: String,
-        channel: String,
+        server: ServerId,
+        channel: ChannelId,
         command: String,
     },
     AddReaction {
-        server: String,
-        channel: String,
+        server: ServerId,
+        channel: ChannelId,
         reaction: String,
-        timestamp: DateTime,
+        id: MessageId,
     },
     SendTyping {
-        server: String,
-        channel: String,
+        server: ServerId,
+        channel: ChannelId,
     },
+    /// Fetch the messages in a channel, all of those newer than `after` if it is provided and
+    /// otherwise just the latest ones
     GetHistory {
-        channel: String,
+        channel: ChannelId,
+        after: Option<MessageId>,
     },
+    GetThread {
+        channel: ChannelId,
+        thread: MessageId,
+    },
+    /// Replace the contents of one of our own messages
+    EditMessage {
+        server: ServerId,
+        channel: ChannelId,
+        id: MessageId,
+        contents: String,
+    },
+    DeleteMessage {
+        server: ServerId,
+        channel: ChannelId,
+        id: MessageId,
+    },
+    /// Look up a link to a message, which comes back as a `ConnEvent::Permalink`
+    GetPermalink {
+        server: ServerId,
+        channel: ChannelId,
+        id: MessageId,
+    },
+}
+
+/// The names a server knows about, for completing words as they're typed
+#[derive(Clone, Debug, Default)]
+pub struct Completions {
+    pub users: Vec<String>,
+    pub channels: Vec<String>,
+    pub emoji: Vec<String>,
+}
+
+impl Completions {
+    pub fn autocomplete(&self, word: &str) -> Vec<String> {
+        match word.chars().next() {
+            Some('@') => self
+                .users
+                .iter()
+                .filter(|name| name.starts_with(&word[1..]))
+                .map(|s| String::from("@") + s)
+                .collect(),
+            Some('#') => self
+                .channels
+                .iter()
+                .filter(|name| name.starts_with(&word[1..]))
+                .map(|s| String::from("#") + s)
+                .collect(),
+            Some(':') => self
+                .emoji
+                .iter()
+                .filter(|name| name.starts_with(&word[1..]))
+                .map(|s| format!(":{}:", s))
+                .collect(),
+            Some('+') => match word.get(2..) {
+                Some(start) if !start.is_empty() => self
+                    .emoji
+                    .iter()
+                    .filter(|name| name.starts_with(start))
+                    .map(|s| format!("+:{}:", s))
+                    .collect(),
+                _ => Vec::new(),
+            },
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// A handle to the latest `Completions` of a server
+///
+/// Autocompletion should happen instantly, so instead of asking the backend we read whichever
+/// snapshot it last published with `update`.
+#[derive(Clone, Default)]
+pub struct Completer(Arc<RwLock<Arc<Completions>>>);
+
+impl Completer {
+    pub fn update(&self, completions: Completions) {
+        *self.0.write().unwrap() = Arc::new(completions);
+    }
+
+    pub fn snapshot(&self) -> Arc<Completions> {
+        self.0.read().unwrap().clone()
+    }
+}
+
+/// Optional features that a backend may or may not support
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Capabilities {
+    pub reactions: bool,
+    pub typing: bool,
+    pub history: bool,
+    pub commands: bool,
+    pub threads: bool,
+    pub edits: bool,
+    pub deletes: bool,
+    /// Handles a `search <query>` command, on top of the search across loaded messages
+    pub search: bool,
+    pub permalinks: bool,
 }
 
-// This is very derpy
-// Don't want to do this with channels, because autocompletion should happen instantly
-pub trait Completer: Send {
-    fn autocomplete(&self, _word: &str) -> Vec<String>;
+/// A chat service that omnichat can connect to
+///
+/// Each entry in the config file under a backend's name is turned into its `Config` and handed
+/// to `connect`. From then on, every `TuiEvent` sent to that server is passed to `handle_event`.
+pub trait Backend: Sized + Send + 'static {
+    type Config: serde::de::DeserializeOwned + Send + 'static;
+
+    /// Establish the connection and announce it with a `ConnEvent::ServerConnected`, whose
+    /// `Server` must use `events` as its sender.
+    fn connect(
+        config: Self::Config,
+        sender: UnboundedSender<ConnEvent>,
+        events: UnboundedSender<TuiEvent>,
+    ) -> BoxFuture<'static, Result<Self, ()>>;
+
+    fn handle_event(&mut self, event: TuiEvent) -> BoxFuture<'_, ()>;
+
+    fn capabilities() -> Capabilities;
+
+    /// Called once the frontend has gone away, no more events will be delivered after this
+    fn shutdown(&mut self) -> BoxFuture<'_, ()>;
+}
+
+type Launcher = fn(toml::Value, UnboundedSender<ConnEvent>) -> Result<JoinHandle<()>, String>;
+
+/// Every backend that can be enabled from the config file, by the name of its table
+const BACKENDS: &[(&str, Launcher)] = &[
+    ("slack", launch::<crate::slack_conn::SlackBackend>),
+    ("discord", launch::<crate::discord_conn::DiscordBackend>),
+];
+
+fn launch<B: Backend>(
+    config: toml::Value,
+    sender: UnboundedSender<ConnEvent>,
+) -> Result<JoinHandle<()>, String> {
+    let config = config.try_into::<B::Config>().map_err(|e| e.to_string())?;
+
+    Ok(tokio::spawn(async move {
+        let (events, mut events_from_tui) = futures::channel::mpsc::unbounded();
+        let mut backend = match B::connect(config, sender, events).await {
+            Ok(b) => b,
+            Err(()) => return,
+        };
+        while let Some(event) = events_from_tui.next().await {
+            backend.handle_event(event).await;
+        }
+        backend.shutdown().await;
+    }))
+}
+
+/// Check that every table left over in the config file is named after a backend, and written as
+/// a list of entries, or describe everything that's wrong with them
+pub fn check_backends(config: &HashMap<String, toml::Value>) -> Result<(), Vec<String>> {
+    let names = BACKENDS
+        .iter()
+        .map(|(backend, _)| format!("[[{}]]", backend))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let mut errors = Vec::new();
+    for (name, value) in config {
+        if !BACKENDS.iter().any(|(backend, _)| backend == name) {
+            errors.push(format!(
+                "unknown section \"{}\", the backends are {}",
+                name, names
+            ));
+        } else if !value.is_array() {
+            errors.push(format!(
+                "entries for {} must be written as [[{}]]",
+                name, name
+            ));
+        }
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+/// Start a connection for every backend entry in the config file
+///
+/// The config must have been checked with `check_backends` first.
+pub fn launch_all(
+    config: HashMap<String, toml::Value>,
+    sender: &UnboundedSender<ConnEvent>,
+) -> Vec<JoinHandle<()>> {
+    let mut handles = Vec::new();
+    for (name, value) in config {
+        // check_backends has already rejected unknown sections and entries that aren't arrays
+        let launcher = match BACKENDS.iter().find(|(backend, _)| *backend == name) {
+            Some((_, launcher)) => launcher,
+            None => unreachable!("unknown backend {} passed check_backends", name),
+        };
+        let entries = match value {
+            toml::Value::Array(entries) => entries,
+            _ => unreachable!("non-array entry for {} passed check_backends", name),
+        };
+        for entry in entries {
+            match launcher(entry, sender.clone()) {
+                Ok(handle) => handles.push(handle),
+                Err(e) => error!("Invalid config for {}: {}", name, e),
+            }
+        }
+    }
+    handles
 }
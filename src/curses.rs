@@ -1,45 +1,244 @@
-#[derive(Clone, Debug)]
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+/// One column of the screen
+///
+/// Holds a whole grapheme cluster. Wide clusters like CJK and most emoji take up two columns, so
+/// the cell after them is left with empty `text` to show that it's covered.
+#[derive(Clone, Debug, PartialEq)]
 pub struct Cell {
-    pub text: char,
+    pub text: String,
     pub style: Style,
 }
 
-#[derive(Clone, Debug)]
+impl Cell {
+    fn is_continuation(&self) -> bool {
+        self.text.is_empty()
+    }
+
+    fn is_wide(&self) -> bool {
+        self.text.width() > 1
+    }
+}
+
+/// How many columns some text takes up on screen
+pub fn display_width(text: &str) -> usize {
+    text.graphemes(true).map(grapheme_width).sum()
+}
+
+// Clusters are drawn in at most two columns, whatever the width of their parts adds up to
+fn grapheme_width(grapheme: &str) -> usize {
+    std::cmp::min(grapheme.width(), 2)
+}
+
+/// Cut text down to at most `width` columns, without splitting a grapheme cluster
+pub fn truncate_to_width(text: &str, width: usize) -> &str {
+    let mut used = 0;
+    for (i, grapheme) in text.grapheme_indices(true) {
+        used += grapheme_width(grapheme);
+        if used > width {
+            return &text[..i];
+        }
+    }
+    text
+}
+
+/// Cut text down to exactly `width` columns, padding it with spaces if it's too short
+pub fn pad_to_width(text: &str, width: usize) -> String {
+    let text = truncate_to_width(text, width);
+    let mut padded = String::from(text);
+    padded.push_str(&" ".repeat(width - display_width(text)));
+    padded
+}
+
+/// A color from the 256-color palette, or a 24-bit one
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Color {
+    Ansi(u8),
+    Rgb(u8, u8, u8),
+}
+
+impl From<termion::color::AnsiValue> for Color {
+    fn from(value: termion::color::AnsiValue) -> Self {
+        Color::Ansi(value.0)
+    }
+}
+
+impl Color {
+    /// The closest color in the 256-color palette
+    fn to_ansi256(self) -> u8 {
+        match self {
+            Color::Ansi(c) => c,
+            Color::Rgb(r, g, b) => nearest_ansi256(r, g, b),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct Style {
-    pub foreground: termion::color::AnsiValue,
-    pub background: termion::color::AnsiValue,
+    pub foreground: Color,
+    pub background: Color,
     pub bold: bool,
+    pub dim: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub reverse: bool,
+    pub strikethrough: bool,
 }
 
 impl Style {
-    fn update_to(&mut self, other: &Style, output: &mut String) {
-        use std::fmt::Write;
-        if self.foreground.0 != other.foreground.0 {
-            write!(output, "{}", termion::color::Fg(other.foreground)).unwrap();
-            self.foreground = other.foreground;
+    /// Plain text in these colors, with none of the other attributes
+    pub fn new(foreground: Color, background: Color) -> Self {
+        Style {
+            foreground,
+            background,
+            bold: false,
+            dim: false,
+            italic: false,
+            underline: false,
+            reverse: false,
+            strikethrough: false,
         }
-        if self.background.0 != other.background.0 {
-            write!(output, "{}", termion::color::Bg(other.background)).unwrap();
-            self.background = other.background;
+    }
+}
+
+/// How many colors the terminal can show
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorSupport {
+    /// Only the other attributes, for terminals without color or when `NO_COLOR` is set
+    None,
+    Ansi16,
+    Ansi256,
+    TrueColor,
+}
+
+impl ColorSupport {
+    /// Respect https://no-color.org, and otherwise go by what the terminal says it is
+    pub fn detect() -> Self {
+        let var = |name| std::env::var(name).unwrap_or_default();
+        let colorterm = var("COLORTERM");
+        if !var("NO_COLOR").is_empty() || var("TERM") == "dumb" {
+            ColorSupport::None
+        } else if colorterm == "truecolor" || colorterm == "24bit" {
+            ColorSupport::TrueColor
+        } else if var("TERM").contains("256color") || !colorterm.is_empty() {
+            ColorSupport::Ansi256
+        } else {
+            ColorSupport::Ansi16
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "auto" => Some(ColorSupport::detect()),
+            "none" => Some(ColorSupport::None),
+            "16" => Some(ColorSupport::Ansi16),
+            "256" => Some(ColorSupport::Ansi256),
+            "truecolor" => Some(ColorSupport::TrueColor),
+            _ => None,
         }
-        // Turn bold off
-        if self.bold && !other.bold {
-            write!(output, "{}", termion::style::NoBold).unwrap();
+    }
+
+    /// Write the escape sequence for a foreground (30) or background (40) color
+    pub fn write_color(self, base: u8, color: Color, output: &mut String) {
+        use std::fmt::Write;
+        match (self, color) {
+            (ColorSupport::None, _) => {}
+            (ColorSupport::Ansi16, _) => match to_ansi16(color.to_ansi256()) {
+                c @ 0..=7 => write!(output, "\x1b[{}m", base + c).unwrap(),
+                c => write!(output, "\x1b[{}m", base + 60 + c - 8).unwrap(),
+            },
+            (ColorSupport::TrueColor, Color::Rgb(r, g, b)) => {
+                write!(output, "\x1b[{};2;{};{};{}m", base + 8, r, g, b).unwrap()
+            }
+            (ColorSupport::Ansi256, _) | (ColorSupport::TrueColor, Color::Ansi(_)) => {
+                write!(output, "\x1b[{};5;{}m", base + 8, color.to_ansi256()).unwrap()
+            }
         }
-        // Turn bold on
-        if !self.bold && other.bold {
-            write!(output, "{}", termion::style::Bold).unwrap();
+    }
+}
+
+/// The closest of the 16 basic colors to a color from the 256-color palette
+fn to_ansi16(color: u8) -> u8 {
+    match color {
+        0..=15 => color,
+        16..=231 => {
+            let c = color - 16;
+            let (r, g, b) = (c / 36, c / 6 % 6, c % 6);
+            let brightest = r.max(g).max(b);
+            if brightest == 0 {
+                return 0;
+            }
+            // Each channel is either on or off, depending on how it compares to the others
+            let on = |v: u8| (v * 2 > brightest) as u8;
+            let bright = if brightest >= 4 { 8 } else { 0 };
+            on(r) | on(g) << 1 | on(b) << 2 | bright
         }
-        self.bold = other.bold;
+        _ => match color - 232 {
+            0..=5 => 0,
+            6..=11 => 8,
+            12..=17 => 7,
+            _ => 15,
+        },
+    }
+}
+
+/// The closest color in the 256-color palette's color cube or grayscale ramp
+fn nearest_ansi256(r: u8, g: u8, b: u8) -> u8 {
+    const LEVELS: [i32; 6] = [0, 95, 135, 175, 215, 255];
+    let level = |v: u8| {
+        (0..6)
+            .min_by_key(|&i| (LEVELS[i] - i32::from(v)).abs())
+            .unwrap()
+    };
+    let distance = |(r2, g2, b2): (i32, i32, i32)| {
+        (i32::from(r) - r2).pow(2) + (i32::from(g) - g2).pow(2) + (i32::from(b) - b2).pow(2)
+    };
+
+    let (lr, lg, lb) = (level(r), level(g), level(b));
+    let cube = (LEVELS[lr], LEVELS[lg], LEVELS[lb]);
+    let average = (i32::from(r) + i32::from(g) + i32::from(b)) / 3;
+    let shade = ((average - 8).max(0) / 10).min(23);
+    let gray = 8 + shade * 10;
+
+    if distance((gray, gray, gray)) < distance(cube) {
+        232 + shade as u8
+    } else {
+        16 + 36 * lr as u8 + 6 * lg as u8 + lb as u8
     }
 }
 
-impl PartialEq for Cell {
-    fn eq(&self, rhs: &Cell) -> bool {
-        self.style.foreground.0 == rhs.style.foreground.0
-            && self.style.background.0 == rhs.style.background.0
-            && self.style.bold == rhs.style.bold
-            && self.text == rhs.text
+impl Style {
+    fn update_to(&mut self, other: &Style, colors: ColorSupport, output: &mut String) {
+        use std::fmt::Write;
+        if self.foreground != other.foreground {
+            colors.write_color(30, other.foreground, output);
+            self.foreground = other.foreground;
+        }
+        if self.background != other.background {
+            colors.write_color(40, other.background, output);
+            self.background = other.background;
+        }
+        // Bold and dim are turned off together, so one may need to be turned back on
+        if (self.bold && !other.bold) || (self.dim && !other.dim) {
+            output.push_str("\x1b[22m");
+            self.bold = false;
+            self.dim = false;
+        }
+        let attributes = [
+            (self.bold, other.bold, 1, 22),
+            (self.dim, other.dim, 2, 22),
+            (self.italic, other.italic, 3, 23),
+            (self.underline, other.underline, 4, 24),
+            (self.reverse, other.reverse, 7, 27),
+            (self.strikethrough, other.strikethrough, 9, 29),
+        ];
+        for &(current, wanted, on, off) in &attributes {
+            if current != wanted {
+                write!(output, "\x1b[{}m", if wanted { on } else { off }).unwrap();
+            }
+        }
+        *self = other.clone();
     }
 }
 
@@ -76,60 +275,95 @@ impl Screen {
     pub fn set(&mut self, mut row: u16, mut col: u16, cell: Cell) {
         row -= 1;
         col -= 1;
-        self.cells
-            .get_mut((row * self.width + col) as usize)
-            .map(|c| *c = Some(cell));
+        let index = (row * self.width + col) as usize;
+        // Drawing over half of a wide cluster leaves its other half blank, continuations are
+        // always drawn right after the cluster they belong to so the half before is taken care of
+        match self.cells.get(index) {
+            Some(Some(old)) if old.is_continuation() && !cell.is_continuation() && index > 0 => {
+                self.blank(index - 1);
+            }
+            Some(Some(old)) if old.is_wide() => {
+                self.blank(index + 1);
+            }
+            _ => {}
+        }
+        self.cells.get_mut(index).map(|c| *c = Some(cell));
+    }
+
+    fn blank(&mut self, index: usize) {
+        if let Some(Some(cell)) = self.cells.get_mut(index) {
+            cell.text = String::from(" ");
+        }
     }
 
+    /// Draw text starting at a column, returning how many columns it took up
     pub fn set_str(
         &mut self,
         row: u16,
         col: u16,
-        fg: termion::color::AnsiValue,
-        bg: termion::color::AnsiValue,
+        fg: Color,
+        bg: Color,
         bold: bool,
         text: &str,
-    ) {
-        // TODO: grapheme clusters
-        for (c, chr) in text.chars().enumerate() {
+    ) -> u16 {
+        let style = Style {
+            bold,
+            ..Style::new(fg, bg)
+        };
+        self.set_styled(row, col, &style, text)
+    }
+
+    /// Draw text starting at a column, returning how many columns it took up
+    pub fn set_styled(&mut self, row: u16, col: u16, style: &Style, text: &str) -> u16 {
+        let mut c = col;
+        for grapheme in text.graphemes(true) {
+            let width = grapheme_width(grapheme) as u16;
+            if width == 0 {
+                continue;
+            }
+            // A wide cluster that doesn't fit at the end of the row becomes a space
+            let text = if width == 2 && c == self.width {
+                " "
+            } else {
+                grapheme
+            };
             self.set(
                 row,
-                col + c as u16,
+                c,
                 Cell {
-                    style: Style {
-                        foreground: fg,
-                        background: bg,
-                        bold,
-                    },
-                    text: chr,
+                    style: style.clone(),
+                    text: String::from(text),
                 },
             );
+            if width == 2 && c < self.width {
+                self.set(
+                    row,
+                    c + 1,
+                    Cell {
+                        style: style.clone(),
+                        text: String::new(),
+                    },
+                );
+            }
+            c += width;
         }
+        c - col
     }
 
-    pub fn update_from(&mut self, other: &Screen) -> String {
+    /// Describe how to turn this screen into another, drawing empty cells in the default style
+    pub fn update_from(
+        &mut self,
+        other: &Screen,
+        default_style: &Style,
+        colors: ColorSupport,
+    ) -> String {
         use std::fmt::Write;
-        use termion::color::{AnsiValue, Bg, Fg};
         use termion::cursor::Goto;
         let mut output = String::new();
-        let default_style = Style {
-            foreground: AnsiValue::rgb(5, 5, 5),
-            background: AnsiValue::rgb(0, 0, 0),
-            bold: false,
-        };
-        let mut current_style = Style {
-            foreground: AnsiValue::rgb(5, 5, 5),
-            background: AnsiValue::rgb(0, 0, 0),
-            bold: false,
-        };
-        write!(
-            output,
-            "{}{}{}",
-            Fg(current_style.foreground),
-            Bg(current_style.background),
-            termion::style::Reset
-        )
-        .unwrap();
+        let mut current_style = default_style.clone();
+        write!(output, "{}", termion::style::Reset).unwrap();
+        colors.write_color(30, current_style.foreground, &mut output);
+        colors.write_color(40, current_style.background, &mut output);
 
         for row in 1..other.height + 1 {
             let mut previous_col = 0;
@@ -138,6 +372,15 @@ impl Screen {
                 let prev = self.get(row, col);
                 let new = other.get(row, col);
                 if prev != new {
+                    // The wide cluster before this was just drawn over it
+                    if let Some(cell) = new {
+                        if cell.is_continuation() {
+                            if previous_col == col - 1 {
+                                previous_col = col;
+                            }
+                            continue;
+                        }
+                    }
                     if !wrote_goto_row {
                         write!(output, "{}", termion::cursor::Goto(1, row)).unwrap();
                         wrote_goto_row = true;
@@ -148,12 +391,12 @@ impl Screen {
                     }
                     match (prev, new) {
                         (Some(_), None) => {
-                            current_style.update_to(&default_style, &mut output);
+                            current_style.update_to(default_style, colors, &mut output);
                             output.push(' ');
                         }
                         (_, Some(new)) => {
-                            current_style.update_to(&new.style, &mut output);
-                            output.push(new.text);
+                            current_style.update_to(&new.style, colors, &mut output);
+                            output.push_str(&new.text);
                         }
                         (None, None) => {}
                     };
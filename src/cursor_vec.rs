@@ -43,6 +43,10 @@ impl<T> CursorVec<T> {
         unsafe { self.vec.get_unchecked_mut(0) }
     }
 
+    pub fn get_index_mut(&mut self, index: usize) -> Option<&mut T> {
+        self.vec.get_mut(index)
+    }
+
     pub fn push(&mut self, item: T) {
         self.vec.push(item)
     }
@@ -51,10 +55,6 @@ impl<T> CursorVec<T> {
         self.vec.iter()
     }
 
-    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut T> {
-        self.vec.iter_mut()
-    }
-
     pub fn tell(&self) -> usize {
         self.index
     }
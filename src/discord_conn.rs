@@ -1,20 +1,37 @@
-use crate::bimap::BiMap;
-use crate::conn::{self, ConnEvent, DateTime};
+use crate::conn::{
+    Backend, Capabilities, ChannelId, ChannelType, ConnEvent, DateTime, Message, MessageId,
+    TuiEvent,
+};
+use crate::line_editor::LineEditor;
+use discord::Snowflake;
 use log::error;
-use std::sync::mpsc::SyncSender;
-
-use futures::{Future, Stream};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use futures::channel::mpsc::UnboundedSender;
+use futures::channel::oneshot;
+use futures::future::{BoxFuture, FutureExt};
+use futures::lock::Mutex;
+use futures::sink::SinkExt;
+use weeqwest::Request;
 
 pub struct DiscordConn {
     token: String,
-    guild_name: String,
-    guild_id: discord::Snowflake,
-    channel_ids: BiMap<String, discord::Snowflake>,
-    last_message_ids: BiMap<String, discord::Snowflake>,
-    tui_sender: std::sync::mpsc::SyncSender<ConnEvent>,
+    guilds: Vec<Snowflake>,
+    // Every channel we display, and the guild it belongs to
+    channels: HashMap<Snowflake, Snowflake>,
+    last_message_ids: HashMap<Snowflake, Snowflake>,
+    tui_sender: UnboundedSender<ConnEvent>,
     last_typing_message: chrono::DateTime<chrono::Utc>,
+    // Who we are, so that mentions of us can be shown by name
+    my_id: Snowflake,
+    my_name: String,
 }
 
+// The most messages Discord will hand out in one request
+const HISTORY_PAGE_SIZE: usize = 100;
+
 fn format_json(text: &[u8]) -> String {
     ::serde_json::from_slice::<::serde_json::Value>(text)
         .and_then(|v| ::serde_json::to_string_pretty(&v))
@@ -23,401 +40,719 @@ fn format_json(text: &[u8]) -> String {
 
 macro_rules! deserialize_or_log {
     ($response:expr, $type:ty) => {{
-        ::serde_json::from_slice::<$type>(&$response.bytes())
-            .map_err(|e| error!("{}\n{:#?}", format_json(&$response.bytes()), e))
+        if $response.status().is_success() {
+            ::serde_json::from_slice::<$type>(&$response.body())
+                .map_err(|e| error!("{}\n{:#?}", format_json(&$response.body()), e))
+        } else {
+            match ::serde_json::from_slice::<::discord::Error>(&$response.body()) {
+                Ok(e) => {
+                    error!("{:#?}", e);
+                    Err(())
+                }
+                Err(e) => {
+                    error!("{}\n{:#?}", format_json(&$response.body()), e);
+                    Err(())
+                }
+            }
+        }
     }};
 }
 
-/*
-pub fn permissions_in(
-    chan: &::discord::Channel,
-    guild: Option<&::discord::Guild>,
-    roles: &[::discord::Snowflake],
-) -> ::discord::Permissions {
-    let mut perms = match guild {
-        Some(guild) => {
-            let perms = guild.permissions;
-            if (perms.contains(::discord::Permissions::ADMINISTRATOR)) || guild.owner {
-                ::discord::Permissions::all()
-            } else {
-                perms
-            }
-        }
-        None => ::discord::Permissions::all(),
-    };
+async fn discord_get(token: &str, endpoint: &str) -> Result<weeqwest::Response, ()> {
+    let request = Request::get(&format!("{}{}", ::discord::BASE_URL, endpoint))
+        .map_err(|e| error!("{:#?}", e))?
+        .header("Authorization", token);
+    weeqwest::send(&request)
+        .await
+        .map_err(|e| error!("{:#?}", e))
+}
+
+fn parse_timestamp(timestamp: &str) -> DateTime {
+    ::chrono::DateTime::parse_from_rfc3339(timestamp)
+        .map(|d| d.with_timezone(&::chrono::Utc).into())
+        .unwrap_or_else(|_| DateTime::now())
+}
 
-    // Role overrides
-    for overwrite in chan
+fn permissions_in(
+    channel: &discord::Channel,
+    guild: &discord::Guild,
+    me: &discord::GuildMember,
+) -> discord::Permissions {
+    use discord::Permissions;
+
+    if guild.owner || guild.permissions.contains(Permissions::ADMINISTRATOR) {
+        return Permissions::all();
+    }
+    let mut perms = guild.permissions;
+
+    // The @everyone role shares its id with the guild, and is overridden by all other roles
+    for overwrite in channel
         .permission_overwrites
         .iter()
-        .filter(|owrite| roles.iter().any(|r| *r == owrite.id))
+        .filter(|o| o.id == guild.id)
     {
+        perms.remove(overwrite.deny);
         perms.insert(overwrite.allow);
+    }
+
+    let mut allow = Permissions::empty();
+    let mut deny = Permissions::empty();
+    for overwrite in channel
+        .permission_overwrites
+        .iter()
+        .filter(|o| me.roles.contains(&o.id))
+    {
+        allow.insert(overwrite.allow);
+        deny.insert(overwrite.deny);
+    }
+    perms.remove(deny);
+    perms.insert(allow);
+
+    // Overwrites for the member themselves take precedence over everything else
+    for overwrite in channel
+        .permission_overwrites
+        .iter()
+        .filter(|o| o.id == me.user.id)
+    {
         perms.remove(overwrite.deny);
+        perms.insert(overwrite.allow);
     }
 
     perms
 }
-*/
 
-use weeqwest::Request;
+/// Everything we need to pick up where we left off after the gateway connection drops
+struct Session {
+    id: Option<String>,
+    seq: Option<u64>,
+    reconnecting: bool,
+}
 
-impl DiscordConn {
-    pub fn create_on(token: &str, sender: SyncSender<ConnEvent>, server: &str) -> Result<(), ()> {
-        let mut client = weeqwest::Client::new();
-
-        let me_resp = client
-            .send(
-                Request::get(&format!("{}/users/@me", ::discord::BASE_URL))
-                    .unwrap()
-                    .header("Authorization", token),
-            )
-            .wait()
-            .map_err(|e| error!("{:#?}", e))?;
-        let me = deserialize_or_log!(me_resp, ::discord::User)?;
-
-        let guild_resp = client
-            .send(
-                Request::get(&format!("{}{}", ::discord::BASE_URL, "/users/@me/guilds"))
-                    .unwrap()
-                    .header("Authorization", token),
-            )
-            .wait()
-            .map_err(|e| error!("{:#?}", e))?;
-        let guilds = deserialize_or_log!(guild_resp, Vec<::discord::Guild>)?;
-
-        let guild = guilds.into_iter().find(|g| g.name == server).unwrap();
-        let guild_name = String::from(guild.name.as_str());
-
-        let me_resp = client
-            .send(
-                Request::get(&format!(
-                    "{}/guilds/{}/members/{}",
-                    ::discord::BASE_URL,
-                    guild.id,
-                    me.id
-                ))
-                .unwrap()
-                .header("Authorization", token),
-            )
-            .wait()
-            .map_err(|e| error!("{:#?}", e))?;
-
-        let me = deserialize_or_log!(me_resp, discord::GuildMember)?;
-        let mut my_roles = me.roles;
-
-        let channels_resp = client
-            .send(
-                Request::get(&format!(
-                    "{}/guilds/{}/channels",
-                    ::discord::BASE_URL,
-                    guild.id
-                ))
-                .unwrap()
-                .header("Authorization", token),
-            )
-            .wait()
-            .map_err(|e| error!("{:#?}", e))?;
-        let channels = deserialize_or_log!(channels_resp, Vec<::discord::Channel>)?;
-
-        let roles_resp = client
-            .send(
-                Request::get(&format!(
-                    "{}/guilds/{}/roles",
-                    ::discord::BASE_URL,
-                    guild.id
-                ))
-                .unwrap()
-                .header("Authorization", token),
-            )
-            .wait()
-            .map_err(|e| error!("{:#?}", e))?;
-        let roles = deserialize_or_log!(roles_resp, Vec<::discord::Role>)?;
-        let everyone_role = roles
-            .into_iter()
-            .find(|r| r.name == "@everyone")
-            .unwrap()
-            .id;
+impl Session {
+    /// Resume the session if we have one, otherwise start a new one
+    fn login(&self, token: &str) -> weebsocket::Message {
+        let payload = match (&self.id, self.seq) {
+            (Some(session_id), Some(seq)) => serde_json::json! {{
+                "op": 6,
+                "d": {
+                    "token": token,
+                    "session_id": session_id,
+                    "seq": seq,
+                }
+            }},
+            _ => serde_json::json! {{
+                "op": 2,
+                "d": {
+                    "token": token,
+                    "properties": {
+                        "$os": "Linux",
+                        "$browser": "Discord Client",
+                        "$device": "Firefox",
+                        "$client_version": "0.0.9",
+                        "$release_channel": "unknown",
+                    },
+                    "large_threshold": 250,
+                    "compress": false,
+                    "v": 6,
+                }
+            }},
+        };
+        weebsocket::Message::Text(payload.to_string())
+    }
 
-        my_roles.push(everyone_role);
+    fn heartbeat(&self) -> weebsocket::Message {
+        weebsocket::Message::Text(serde_json::json! {{"op": 1, "d": self.seq}}.to_string())
+    }
+}
 
-        let channels: Vec<_> = channels
-            .into_iter()
-            .filter(|c| c.ty == 0)
-            /*
-            .filter(|c| {
-                permissions_in(c, Some(&guild), &my_roles)
-                    .contains(::discord::Permissions::READ_MESSAGES)
-            })
-            */
-            .filter(|c| c.name.is_some())
-            .collect();
+/// Connect to the gateway again, backing off exponentially
+async fn reconnect(url: &str) -> weebsocket::Client {
+    let mut delay = std::time::Duration::from_secs(1);
+    loop {
+        tokio::time::delay_for(delay).await;
+        match weebsocket::Client::connect(url).await {
+            Ok(websocket) => return websocket,
+            Err(e) => error!("Couldn't connect to the Discord gateway: {:#?}", e),
+        }
+        delay = std::cmp::min(delay * 2, std::time::Duration::from_secs(64));
+    }
+}
 
-        let mut channel_ids: BiMap<String, discord::Snowflake> = BiMap::new();
-        let mut last_message_ids: BiMap<String, discord::Snowflake> = BiMap::new();
-        for channel in &channels {
-            let name = channel.name.clone().unwrap();
-            channel_ids.insert(name.clone(), channel.id);
-            last_message_ids.insert(name, channel.last_message_id.unwrap());
+async fn tick(heartbeat: &mut Option<tokio::time::Interval>) {
+    match heartbeat {
+        Some(interval) => {
+            interval.tick().await;
         }
+        None => futures::future::pending().await,
+    }
+}
 
-        // This is how the TUI sends me events
-        let (tx, events_from_tui) = std::sync::mpsc::sync_channel(100);
+/// Handle gateway events until the connection is lost or we're shut down
+///
+/// Returns true if we should reconnect.
+async fn run_gateway(
+    websocket: &mut weebsocket::Client,
+    token: &str,
+    session: &mut Session,
+    conn: &Mutex<DiscordConn>,
+    shutdown: &mut oneshot::Receiver<()>,
+) -> bool {
+    use discord::gateway::{Event, GatewayEvent, Hello, Ready};
+
+    // We don't heartbeat until Discord tells us how often to
+    let mut heartbeat = None;
+    let mut acked = true;
+    loop {
+        let reply = futures::select! {
+            ws_msg = websocket.recv().fuse() => match ws_msg {
+                Ok(weebsocket::Message::Text(text)) => {
+                    let event = match ::serde_json::from_str::<GatewayEvent>(&text) {
+                        Ok(event) => event,
+                        Err(e) => {
+                            error!(
+                                "Unrecognized Discord message: {}\n{:#?}",
+                                format_json(text.as_bytes()),
+                                e
+                            );
+                            continue;
+                        }
+                    };
+                    if event.s.is_some() {
+                        session.seq = event.s;
+                    }
+                    match event.d {
+                        Some(Event::Hello(Hello { heartbeat_interval, .. })) => {
+                            heartbeat = Some(tokio::time::interval(
+                                std::time::Duration::from_millis(heartbeat_interval),
+                            ));
+                            acked = true;
+                            Some(session.login(token))
+                        }
+                        Some(Event::Ready(Ready { session_id })) => {
+                            session.id = Some(session_id);
+                            if session.reconnecting {
+                                session.reconnecting = false;
+                                conn.lock().await.set_connected(true).await;
+                            }
+                            None
+                        }
+                        Some(Event::Resumed) => {
+                            session.reconnecting = false;
+                            conn.lock().await.set_connected(true).await;
+                            None
+                        }
+                        Some(Event::Heartbeat) => Some(session.heartbeat()),
+                        Some(Event::HeartbeatAck) => {
+                            acked = true;
+                            None
+                        }
+                        Some(Event::Reconnect) => return true,
+                        Some(Event::InvalidSession(resumable)) => {
+                            if !resumable {
+                                session.id = None;
+                                session.seq = None;
+                            }
+                            // Discord asks that we wait a bit before trying again
+                            tokio::time::delay_for(std::time::Duration::from_secs(3)).await;
+                            Some(session.login(token))
+                        }
+                        Some(event) => {
+                            conn.lock().await.process_gateway_event(event).await;
+                            None
+                        }
+                        None => None,
+                    }
+                }
+                Ok(weebsocket::Message::Ping(data)) => Some(weebsocket::Message::Pong(data)),
+                Ok(weebsocket::Message::Close(_)) => {
+                    error!("Discord websocket closed");
+                    return true;
+                }
+                Ok(weebsocket::Message::Pong(_)) | Ok(weebsocket::Message::Binary(_)) => {
+                    error!("unrecognized message");
+                    None
+                }
+                Err(e) => {
+                    error!("Discord websocket error: {:#?}", e);
+                    return true;
+                }
+            },
+            _ = tick(&mut heartbeat).fuse() => {
+                // If the last heartbeat never got an ACK the connection is dead, even if the
+                // socket hasn't noticed yet
+                if !acked {
+                    error!("Discord stopped acknowledging heartbeats");
+                    return true;
+                }
+                acked = false;
+                Some(session.heartbeat())
+            }
+            _ = &mut *shutdown => return false,
+        };
+
+        if let Some(reply) = reply {
+            if let Err(e) = websocket.send(&reply).await {
+                error!("Discord websocket error: {:#?}", e);
+                return true;
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct DiscordConfig {
+    token: String,
+    /// Names of the guilds to show, all of them if this is empty
+    #[serde(default)]
+    guilds: Vec<String>,
+}
+
+pub struct DiscordBackend {
+    inner: Arc<Mutex<DiscordConn>>,
+    shutdown: Option<oneshot::Sender<()>>,
+}
+
+impl Backend for DiscordBackend {
+    type Config = DiscordConfig;
+
+    fn connect(
+        config: DiscordConfig,
+        sender: UnboundedSender<ConnEvent>,
+        events: UnboundedSender<TuiEvent>,
+    ) -> BoxFuture<'static, Result<Self, ()>> {
+        DiscordConn::create_on(config, sender, events).boxed()
+    }
+
+    fn handle_event(&mut self, event: TuiEvent) -> BoxFuture<'_, ()> {
+        async move {
+            let mut conn = self.inner.lock().await;
+            match event {
+                TuiEvent::SendMessage {
+                    channel, contents, ..
+                } => conn.send_message(&channel, &contents),
+                TuiEvent::SendTyping { channel, .. } => conn.send_typing(&channel),
+                TuiEvent::MarkRead { channel, .. } => conn.mark_read(&channel),
+                TuiEvent::GetHistory { channel, after } => {
+                    conn.get_history(&channel, after.as_ref()).await
+                }
+                TuiEvent::EditMessage {
+                    channel,
+                    id,
+                    contents,
+                    ..
+                } => conn.edit_message(&channel, &id, &contents),
+                TuiEvent::DeleteMessage { channel, id, .. } => conn.delete_message(&channel, &id),
+                TuiEvent::GetPermalink { channel, id, .. } => {
+                    conn.get_permalink(&channel, &id).await
+                }
+                _ => error!("unsupported event {:?}", event),
+            }
+        }
+        .boxed()
+    }
 
-        let now = crate::conn::DateTime::now();
-        let _ = sender.send(ConnEvent::ServerConnected(crate::tui::Server {
-            channels: channels
-                .iter()
-                .map(|c| crate::tui::Channel {
+    fn capabilities() -> Capabilities {
+        Capabilities {
+            reactions: false,
+            typing: true,
+            history: true,
+            commands: false,
+            threads: false,
+            edits: true,
+            deletes: true,
+            search: false,
+            permalinks: true,
+        }
+    }
+
+    fn shutdown(&mut self) -> BoxFuture<'_, ()> {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        futures::future::ready(()).boxed()
+    }
+}
+
+impl DiscordConn {
+    async fn create_on(
+        config: DiscordConfig,
+        mut sender: UnboundedSender<ConnEvent>,
+        tui_send: UnboundedSender<TuiEvent>,
+    ) -> Result<DiscordBackend, ()> {
+        let DiscordConfig {
+            token,
+            guilds: wanted_guilds,
+        } = config;
+
+        let me_response = discord_get(&token, "/users/@me").await?;
+        let me = deserialize_or_log!(me_response, ::discord::User)?;
+
+        let guilds_response = discord_get(&token, "/users/@me/guilds").await?;
+        let guilds = deserialize_or_log!(guilds_response, Vec<::discord::Guild>)?;
+
+        for name in &wanted_guilds {
+            if !guilds.iter().any(|g| &g.name == name) {
+                error!("You are not a member of a Discord guild named {}", name);
+            }
+        }
+        let guilds: Vec<_> = guilds
+            .into_iter()
+            .filter(|g| wanted_guilds.is_empty() || wanted_guilds.contains(&g.name))
+            .collect();
+
+        // Fetch the channels of every guild at once, we need our roles to know which we can see
+        let guild_channels = futures::future::join_all(guilds.iter().map(|guild| {
+            let token = &token;
+            let me = &me;
+            async move {
+                let member_response =
+                    discord_get(token, &format!("/guilds/{}/members/{}", guild.id, me.id)).await?;
+                let member = deserialize_or_log!(member_response, ::discord::GuildMember)?;
+                let channels_response =
+                    discord_get(token, &format!("/guilds/{}/channels", guild.id)).await?;
+                let channels = deserialize_or_log!(channels_response, Vec<::discord::Channel>)?;
+                Ok::<_, ()>(
+                    channels
+                        .into_iter()
+                        .filter(|c| c.ty == 0 && c.name.is_some())
+                        .filter(|c| {
+                            permissions_in(c, guild, &member)
+                                .contains(::discord::Permissions::READ_MESSAGES)
+                        })
+                        .collect::<Vec<_>>(),
+                )
+            }
+        }))
+        .await;
+
+        let gateway_response = discord_get(&token, "/gateway").await?;
+        let gateway = deserialize_or_log!(gateway_response, ::discord::GatewayResponse)?;
+        let gateway_url = format!("{}/?v=6&encoding=json", gateway.url);
+        let mut websocket = weebsocket::Client::connect(&gateway_url)
+            .await
+            .map_err(|e| error!("Couldn't connect to the Discord gateway: {:#?}", e))?;
+
+        let mut channels = HashMap::new();
+        let mut last_message_ids = HashMap::new();
+        let mut servers = Vec::new();
+        let mut guild_ids = Vec::new();
+        for (guild, guild_channels) in guilds.into_iter().zip(guild_channels) {
+            let guild_channels = match guild_channels {
+                Ok(c) => c,
+                Err(()) => {
+                    error!("Couldn't load the channels in {}", guild.name);
+                    continue;
+                }
+            };
+
+            let now = DateTime::now();
+            let mut tui_channels = Vec::new();
+            for channel in guild_channels {
+                channels.insert(channel.id, guild.id);
+                if let Some(last_message_id) = channel.last_message_id {
+                    last_message_ids.insert(channel.id, last_message_id);
+                }
+                tui_channels.push(crate::tui::Channel {
+                    id: channel.id.into(),
                     messages: Vec::new(),
-                    name: c.name.clone().unwrap_or_else(|| String::from("NONAME")),
+                    name: channel.name.unwrap_or_default(),
                     read_at: now,
                     latest: now,
                     has_history: false,
+                    synced_through: None,
                     message_scroll_offset: 0,
-                    message_buffer: String::new(),
-                    channel_type: crate::conn::ChannelType::Normal,
-                })
-                .collect(),
-            completer: None,
-            name: guild_name.clone(),
-            sender: tx,
-            channel_scroll_offset: 0,
-            current_channel: 0,
-        }));
+                    input: LineEditor::default(),
+                    channel_type: ChannelType::Normal,
+                    threads: HashMap::new(),
+                    open_thread: None,
+                });
+            }
 
-        let mut history_responses = Vec::new();
-        for channel in channels.into_iter().filter(|c| c.name.is_some()) {
-            let channel_name = channel.name.unwrap();
-            let token = token.to_string();
-
-            history_responses.push((
-                channel_name,
-                client.send(
-                    Request::get(&format!(
-                        "{}/channels/{}/messages?limit=100",
-                        ::discord::BASE_URL,
-                        channel.id
-                    ))
-                    .unwrap()
-                    .header("Authorization", token.as_str()),
-                ),
+            guild_ids.push(guild.id);
+            servers.push(crate::tui::Server::new(
+                guild.id.into(),
+                guild.name,
+                Some(me.username.clone()),
+                tui_channels,
+                None,
+                tui_send.clone(),
+                DiscordBackend::capabilities(),
             ));
         }
 
-        for (channel, history_resp) in history_responses {
-            let history_resp = history_resp.wait().unwrap();
-
-            let _ = deserialize_or_log!(history_resp, Vec<::discord::Message>).map(|history| {
-                let messages = history
-                    .into_iter()
-                    .map(|message| {
-                        let timestamp = ::chrono::DateTime::parse_from_rfc3339(&message.timestamp)
-                            .map(|d| d.with_timezone(&::chrono::Utc))
-                            .map(|d| d.into())
-                            .unwrap_or_else(|_| DateTime::now());
-
-                        crate::conn::Message {
-                            sender: message.author.username,
-                            server: guild_name.clone(),
-                            timestamp,
-                            contents: String::from(message.content),
-                            channel: channel.clone(),
-                            reactions: Vec::new(),
-                        }
-                    })
-                    .collect();
-
-                let _ = sender.send(ConnEvent::HistoryLoaded {
-                    server: guild_name.clone(),
-                    channel,
-                    messages,
-                });
-            });
-        }
-
-        use std::sync::Arc;
-        use std::sync::RwLock;
-        let (tx, rx) = futures::sync::mpsc::channel(1);
-        let connection = Arc::new(RwLock::new(DiscordConn {
-            token: token.to_string(),
-            guild_name: guild_name.clone(),
-            guild_id: guild.id,
-            channel_ids: channel_ids.clone(),
+        let connection = Arc::new(Mutex::new(DiscordConn {
+            token: token.clone(),
+            guilds: guild_ids,
+            channels,
             last_message_ids,
             tui_sender: sender.clone(),
-            last_typing_message: chrono::offset::Utc::now(),
+            last_typing_message: chrono::Utc::now(),
+            my_id: me.id,
+            my_name: me.username.clone(),
         }));
 
-        let tconnection = connection.clone();
-        std::thread::spawn(move || {
-            for ev in events_from_tui.iter() {
-                match ev {
-                    conn::TuiEvent::SendMessage {
-                        channel, contents, ..
-                    } => tconnection
-                        .read()
-                        .unwrap()
-                        .send_message(&channel, &contents),
-                    conn::TuiEvent::SendTyping { channel, .. } => {
-                        tconnection.write().unwrap().send_typing(&channel)
-                    }
-                    conn::TuiEvent::MarkRead { channel, .. } => {
-                        tconnection.read().unwrap().mark_read(&channel)
-                    }
-                    _ => error!("unsupported event {:?}", ev),
+        for server in servers {
+            sender
+                .send(ConnEvent::ServerConnected(server))
+                .await
+                .unwrap();
+        }
+
+        let thread_conn = connection.clone();
+        let (shutdown, mut shutdown_recv) = oneshot::channel();
+
+        tokio::spawn(async move {
+            let mut session = Session {
+                id: None,
+                seq: None,
+                reconnecting: false,
+            };
+            while run_gateway(
+                &mut websocket,
+                &token,
+                &mut session,
+                &thread_conn,
+                &mut shutdown_recv,
+            )
+            .await
+            {
+                session.reconnecting = true;
+                thread_conn.lock().await.set_connected(false).await;
+                futures::select! {
+                    new_websocket = reconnect(&gateway_url).fuse() => websocket = new_websocket,
+                    _ = shutdown_recv => break,
                 }
             }
         });
 
-        // Spin off a thread that will feed message events back to the TUI
-        // websocket does not support the new tokio :(
-        let i_token = token.to_string();
-        std::thread::spawn(move || {
-            use discord::gateway::{Event, GatewayEvent, Hello};
-
-            let url_resp = client
-                .send(
-                    Request::get(&format!("{}{}", ::discord::BASE_URL, "/gateway"))
-                        .unwrap()
-                        .header("Authorization", &i_token),
-                )
-                .wait()
-                .map_err(|e| error!("{:#?}", e))
-                .unwrap();
-
-            let resp = deserialize_or_log!(url_resp, ::discord::GatewayResponse).unwrap();
-
-            use futures::sink::Sink;
-            use websocket::result::WebSocketError;
-            use websocket::OwnedMessage::{Close, Ping, Pong, Text};
-            let runner = ::websocket::ClientBuilder::new(&resp.url)
-                .unwrap()
-                .async_connect_secure(None)
-                .and_then(|(duplex, _)| {
-                    let (sink, stream) = duplex.split();
-                    stream
-                        // Maps a message to maybe a response
-                        .filter_map(|message| match message {
-                            Close(_) => {
-                                error!("websocket closed");
-                                None
-                            }
-                            Ping(m) => Some(Pong(m)),
-                            Text(text) => {
-                                let msg = ::serde_json::from_str::<GatewayEvent>(&text);
-                                match msg {
-                                    Ok(GatewayEvent {
-                                        d:
-                                            Some(Event::Hello(Hello {
-                                                heartbeat_interval: interval,
-                                                ..
-                                            })),
-                                        ..
-                                    }) => {
-                                        let itx = tx.clone();
-                                        std::thread::spawn(move || loop {
-                                            let itx = itx.clone();
-                                            itx.send(Text("{\"op\":1,\"d\":null}".to_string()))
-                                                .wait()
-                                                .map_err(|e| error!("{:#?}", e))
-                                                .unwrap();
-                                            std::thread::sleep(std::time::Duration::from_millis(
-                                                interval as u64,
-                                            ));
-                                        });
-                                        let identify = serde_json::json! {{
-                                            "op": 2,
-                                            "d": {
-                                                "token": i_token,
-                                                "properties": {
-                                                    "$os": "Linux",
-                                                    "$browser": "Discord Client",
-                                                    "$device": "Firefox",
-                                                    "$client_version": "0.0.9",
-                                                    "$release_channel": "unknown",
-                                                },
-                                                "large_threshold": 250,
-                                                "compress": true,
-                                                "v": 6,
-                                            }
-                                        }}
-                                        .to_string();
-                                        Some(Text(identify))
-                                    }
-                                    Ok(gateway_message) => {
-                                        connection.read().unwrap().handle_websocket(gateway_message)
-                                    }
-                                    e => {
-                                        error!(
-                                            "Unrecognized Discord message: {}\n{:#?}",
-                                            format_json(text.as_bytes()),
-                                            e
-                                        );
-                                        None
-                                    }
-                                }
-                            }
-                            _ => None,
-                        })
-                        .select(rx.map_err(|_| WebSocketError::NoDataAvailable))
-                        .forward(sink)
-                });
-            ::tokio::runtime::current_thread::block_on_all(runner).unwrap();
-        });
+        Ok(DiscordBackend {
+            inner: connection,
+            shutdown: Some(shutdown),
+        })
+    }
 
-        Ok(())
+    /// Tell the TUI whether each of our guilds is reachable
+    async fn set_connected(&mut self, connected: bool) {
+        for guild_id in &self.guilds {
+            let server = (*guild_id).into();
+            let event = if connected {
+                ConnEvent::ServerReconnected { server }
+            } else {
+                ConnEvent::ServerDisconnected { server }
+            };
+            self.tui_sender.send(event).await.unwrap();
+        }
     }
 
-    fn handle_websocket(
-        &self,
-        message: discord::gateway::GatewayEvent,
-    ) -> Option<websocket::OwnedMessage> {
+    async fn process_gateway_event(&mut self, event: discord::gateway::Event) {
         use discord::gateway::*;
-        match message {
-            GatewayEvent {
-                d:
-                    Some(Event::MessageCreate(Message {
-                        content: Some(content),
-                        author: Some(discord::User { username, .. }),
-                        channel_id,
-                        guild_id: Some(guild_id),
-                        ..
-                    })),
+        match event {
+            Event::MessageCreate(Message {
+                id,
+                content: Some(content),
+                author: Some(discord::User { username, .. }),
+                channel_id,
+                timestamp,
+                attachments,
                 ..
-            } => {
-                if self.guild_id == guild_id {
-                    self.channel_ids
-                        .get_left(&channel_id)
-                        .map(|channel| {
-                            self.tui_sender
-                                .send(ConnEvent::Message(conn::Message {
-                                    server: self.guild_name.clone(),
-                                    channel: channel.clone(),
-                                    contents: content,
-                                    reactions: Vec::new(),
-                                    sender: username.clone(),
-                                    timestamp: DateTime::now(),
-                                }))
-                                .unwrap();
+            }) => {
+                if let Some(guild_id) = self.channels.get(&channel_id) {
+                    self.last_message_ids.insert(channel_id, id);
+                    self.tui_sender
+                        .send(ConnEvent::Message(crate::conn::Message {
+                            id: id.into(),
+                            server: (*guild_id).into(),
+                            channel: channel_id.into(),
+                            contents: self.message_contents(
+                                content,
+                                attachments.as_deref().unwrap_or_default(),
+                            ),
+                            reactions: Vec::new(),
+                            thread: None,
+                            broadcast: false,
+                            reply_count: 0,
+                            edited: false,
+                            sender: username,
+                            timestamp: timestamp
+                                .as_ref()
+                                .map(|t| parse_timestamp(t))
+                                .unwrap_or_else(DateTime::now),
+                        }))
+                        .await
+                        .unwrap();
+                }
+            }
+            Event::MessageUpdate(Message {
+                id,
+                content: Some(content),
+                channel_id,
+                ..
+            }) => {
+                if let Some(guild_id) = self.channels.get(&channel_id) {
+                    self.tui_sender
+                        .send(ConnEvent::MessageEdited {
+                            server: (*guild_id).into(),
+                            channel: channel_id.into(),
+                            id: id.into(),
+                            contents: self.show_my_mentions(content),
                         })
-                        .or_else(|| {
-                            error!(
-                                "Unrecognized channel id in {:?} from {:?}",
-                                channel_id, username
-                            );
-                            None
-                        });
+                        .await
+                        .unwrap();
+                }
+            }
+            Event::MessageDelete(MessageDelete { id, channel_id, .. }) => {
+                if let Some(guild_id) = self.channels.get(&channel_id) {
+                    self.tui_sender
+                        .send(ConnEvent::MessageDeleted {
+                            server: (*guild_id).into(),
+                            channel: channel_id.into(),
+                            id: id.into(),
+                        })
+                        .await
+                        .unwrap();
+                }
+            }
+            Event::MessageReactionAdd(MessageReactionAdd {
+                channel_id,
+                message_id,
+                emoji,
+                ..
+            }) => {
+                if let Some(guild_id) = self.channels.get(&channel_id) {
+                    self.tui_sender
+                        .send(ConnEvent::ReactionAdded {
+                            server: (*guild_id).into(),
+                            channel: channel_id.into(),
+                            id: message_id.into(),
+                            reaction: emoji.name,
+                        })
+                        .await
+                        .unwrap();
+                }
+            }
+            Event::MessageReactionRemove(MessageReactionRemove {
+                channel_id,
+                message_id,
+                emoji,
+                ..
+            }) => {
+                if let Some(guild_id) = self.channels.get(&channel_id) {
+                    self.tui_sender
+                        .send(ConnEvent::ReactionRemoved {
+                            server: (*guild_id).into(),
+                            channel: channel_id.into(),
+                            id: message_id.into(),
+                            reaction: emoji.name,
+                        })
+                        .await
+                        .unwrap();
                 }
             }
             _ => {}
         }
-        None
     }
 
-    fn send_message(&self, channel: &str, content: &str) {
-        let channel = channel.to_string();
-        let id = *self.channel_ids.get_right(channel.as_str()).unwrap();
+    /// Find the Discord channel behind one of our channel handles
+    fn channel_id(&self, channel: &ChannelId) -> Option<Snowflake> {
+        let id = channel.as_str().parse().ok()?;
+        self.channels.get(&id).map(|_| id)
+    }
+
+    async fn get_history(&self, channel: &ChannelId, after: Option<&MessageId>) {
+        let channel_id = match self.channel_id(channel) {
+            Some(id) => id,
+            None => {
+                error!("Unknown channel: {}", channel);
+                return;
+            }
+        };
+        let guild_id = self.channels[&channel_id];
+
+        let mut messages = Vec::new();
+        let mut after = after.map(MessageId::to_string);
+        loop {
+            let mut endpoint = format!(
+                "/channels/{}/messages?limit={}",
+                channel_id, HISTORY_PAGE_SIZE
+            );
+            if let Some(after) = &after {
+                endpoint.push_str(&format!("&after={}", after));
+            }
+            let history_response = match discord_get(&self.token, &endpoint).await {
+                Ok(r) => r,
+                Err(()) => return,
+            };
+            let page = match deserialize_or_log!(history_response, Vec<::discord::Message>) {
+                Ok(page) => page,
+                Err(()) => return,
+            };
+            let full_page = page.len() == HISTORY_PAGE_SIZE;
+            // Without somewhere to start from, the latest page is all we want. Otherwise each
+            // page starts right after the newest message of the last one.
+            let newest = page.iter().map(|m| m.id).max();
+            messages.extend(page.into_iter().map(|message| {
+                Message {
+                    id: message.id.into(),
+                    server: guild_id.into(),
+                    channel: channel.clone(),
+                    sender: message.author.username,
+                    timestamp: parse_timestamp(message.timestamp),
+                    reactions: message
+                        .reactions
+                        .unwrap_or_default()
+                        .into_iter()
+                        .map(|r| (r.emoji.name, r.count as usize))
+                        .collect(),
+                    contents: self
+                        .message_contents(message.content.into_owned(), &message.attachments),
+                    thread: None,
+                    broadcast: false,
+                    reply_count: 0,
+                    edited: message.edited_timestamp.is_some(),
+                }
+            }));
+            match newest {
+                Some(newest) if after.is_some() && full_page => after = Some(newest.to_string()),
+                _ => break,
+            }
+        }
+
+        let mut tui_send = self.tui_sender.clone();
+        tui_send
+            .send(ConnEvent::HistoryLoaded {
+                messages,
+                server: guild_id.into(),
+                channel: channel.clone(),
+            })
+            .await
+            .unwrap();
+    }
+
+    /// The text we show for a message, with its attachments as links on their own lines
+    fn message_contents(&self, mut content: String, attachments: &[discord::Attachment]) -> String {
+        use std::fmt::Write;
+        for attachment in attachments {
+            let _ = write!(content, "\n{}", attachment.url);
+        }
+        self.show_my_mentions(content.trim().to_string())
+    }
+
+    // Mentions arrive as <@id>, or <@!id> if we have a nickname, but we look for our name
+    fn show_my_mentions(&self, content: String) -> String {
+        let name = format!("@{}", self.my_name);
+        content
+            .replace(&format!("<@{}>", self.my_id), &name)
+            .replace(&format!("<@!{}>", self.my_id), &name)
+    }
+
+    fn send_message(&self, channel: &ChannelId, content: &str) {
+        let id = match self.channel_id(channel) {
+            Some(id) => id,
+            None => {
+                error!("Unknown channel: {}", channel);
+                return;
+            }
+        };
         let token = self.token.clone();
         let body = serde_json::json! {{
             "content": content,
@@ -425,46 +760,165 @@ impl DiscordConn {
         }}
         .to_string();
 
-        std::thread::spawn(move || {
-            let request =
-                weeqwest::Request::post(&format!("{}/channels/{}/messages", discord::BASE_URL, id))
-                    .unwrap()
-                    .header("Authorization", &token)
-                    .json(body);
-            if let Ok(response) = weeqwest::send(&request) {
-                if let Err(e) = serde_json::from_slice::<discord::Message>(response.bytes()) {
-                    error!("{:#?}", e);
+        // The message will show up when Discord echoes it back over the gateway
+        tokio::spawn(async move {
+            let request = Request::post(&format!("{}/channels/{}/messages", discord::BASE_URL, id))
+                .unwrap()
+                .header("Authorization", &token)
+                .json(body);
+            if let Ok(response) = weeqwest::send(&request)
+                .await
+                .map_err(|e| error!("{:#?}", e))
+            {
+                let _ = deserialize_or_log!(response, ::discord::Message);
+            }
+        });
+    }
+
+    fn edit_message(&self, channel: &ChannelId, message: &MessageId, content: &str) {
+        let id = match self.channel_id(channel) {
+            Some(id) => id,
+            None => {
+                error!("Unknown channel: {}", channel);
+                return;
+            }
+        };
+        let message_id: Snowflake = match message.as_str().parse() {
+            Ok(id) => id,
+            Err(_) => {
+                error!("Invalid Discord message id {}", message);
+                return;
+            }
+        };
+        let token = self.token.clone();
+        let body = serde_json::json! {{
+            "content": content,
+        }}
+        .to_string();
+
+        // The edit will show up when Discord sends a MESSAGE_UPDATE over the gateway
+        tokio::spawn(async move {
+            let request = Request::patch(&format!(
+                "{}/channels/{}/messages/{}",
+                discord::BASE_URL,
+                id,
+                message_id
+            ))
+            .unwrap()
+            .header("Authorization", &token)
+            .json(body);
+            if let Ok(response) = weeqwest::send(&request)
+                .await
+                .map_err(|e| error!("{:#?}", e))
+            {
+                let _ = deserialize_or_log!(response, ::discord::Message);
+            }
+        });
+    }
+
+    fn delete_message(&self, channel: &ChannelId, message: &MessageId) {
+        let id = match self.channel_id(channel) {
+            Some(id) => id,
+            None => {
+                error!("Unknown channel: {}", channel);
+                return;
+            }
+        };
+        let message_id: Snowflake = match message.as_str().parse() {
+            Ok(id) => id,
+            Err(_) => {
+                error!("Invalid Discord message id {}", message);
+                return;
+            }
+        };
+        let token = self.token.clone();
+
+        // The message will be removed when Discord sends a MESSAGE_DELETE over the gateway
+        tokio::spawn(async move {
+            let request = Request::delete(&format!(
+                "{}/channels/{}/messages/{}",
+                discord::BASE_URL,
+                id,
+                message_id
+            ))
+            .unwrap()
+            .header("Authorization", &token);
+            if let Ok(response) = weeqwest::send(&request)
+                .await
+                .map_err(|e| error!("{:#?}", e))
+            {
+                if !response.status().is_success() {
+                    error!("Couldn't delete message: {}", format_json(&response.body()));
                 }
             }
         });
     }
 
-    fn send_typing(&mut self, channel: &str) {
+    async fn get_permalink(&mut self, channel: &ChannelId, message: &MessageId) {
+        let id = match self.channel_id(channel) {
+            Some(id) => id,
+            None => {
+                error!("Unknown channel: {}", channel);
+                return;
+            }
+        };
+        let url = format!(
+            "https://discord.com/channels/{}/{}/{}",
+            self.channels[&id], id, message
+        );
+        let server = self.channels[&id].into();
+        self.tui_sender
+            .send(ConnEvent::Permalink { server, url })
+            .await
+            .unwrap();
+    }
+
+    fn send_typing(&mut self, channel: &ChannelId) {
         let now = chrono::Utc::now();
         if (now - self.last_typing_message) < chrono::Duration::seconds(3) {
             return;
         } else {
             self.last_typing_message = now;
         }
-        let id = *self.channel_ids.get_right(channel).unwrap();
+        let id = match self.channel_id(channel) {
+            Some(id) => id,
+            None => {
+                error!("Unknown channel: {}", channel);
+                return;
+            }
+        };
         let token = self.token.clone();
-        std::thread::spawn(move || {
-            let req =
-                weeqwest::Request::post(&format!("{}/channels/{}/typing", discord::BASE_URL, id))
-                    .unwrap()
-                    .header("Content-Length", "0")
-                    .header("Authorization", &token);
-            let _ = weeqwest::send(&req).map_err(|e| error!("{:#?}", e));
+        tokio::spawn(async move {
+            let request = Request::post(&format!("{}/channels/{}/typing", discord::BASE_URL, id))
+                .unwrap()
+                .header("Content-Length", "0")
+                .header("Authorization", &token);
+            let _ = weeqwest::send(&request)
+                .await
+                .map_err(|e| error!("{:#?}", e));
         });
     }
 
-    fn mark_read(&self, channel: &str) {
-        let id = *self.channel_ids.get_right(channel).unwrap();
-        let last_message_id = *self.last_message_ids.get_right(channel).unwrap();
+    fn mark_read(&self, channel: &ChannelId) {
+        let id = match self.channel_id(channel) {
+            Some(id) => id,
+            None => {
+                error!(
+                    "Tried to mark unread for channel {} but channel does not exist",
+                    channel
+                );
+                return;
+            }
+        };
+        // Channels that have never had a message in them have nothing to mark
+        let last_message_id = match self.last_message_ids.get(&id) {
+            Some(id) => *id,
+            None => return,
+        };
         let token = self.token.clone();
         let body = serde_json::json! {{"token": token}}.to_string();
-        std::thread::spawn(move || {
-            let req = weeqwest::Request::post(&format!(
+        tokio::spawn(async move {
+            let request = Request::post(&format!(
                 "{}/channels/{}/messages/{}/ack",
                 discord::BASE_URL,
                 id,
@@ -473,7 +927,9 @@ impl DiscordConn {
             .unwrap()
             .header("Authorization", &token)
             .json(body);
-            let _ = weeqwest::send(&req).map_err(|e| error!("{:#?}", e));
+            let _ = weeqwest::send(&request)
+                .await
+                .map_err(|e| error!("{:#?}", e));
         });
     }
 }
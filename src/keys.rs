@@ -0,0 +1,212 @@
+use std::collections::HashMap;
+use termion::event::Key;
+
+/// Something a key can be bound to in the `[keys]` section of the config file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    Quit,
+    NextServer,
+    PreviousServer,
+    NextChannel,
+    PreviousChannel,
+    NextUnread,
+    PreviousUnread,
+    ScrollUp,
+    ScrollDown,
+    SelectMessages,
+    Compose,
+    /// Start editing our most recent message, if nothing is typed
+    EditLast,
+}
+
+// The names actions go by in the config file
+const ACTIONS: &[(&str, Action)] = &[
+    ("quit", Action::Quit),
+    ("next_server", Action::NextServer),
+    ("previous_server", Action::PreviousServer),
+    ("next_channel", Action::NextChannel),
+    ("previous_channel", Action::PreviousChannel),
+    ("next_unread", Action::NextUnread),
+    ("previous_unread", Action::PreviousUnread),
+    ("scroll_up", Action::ScrollUp),
+    ("scroll_down", Action::ScrollDown),
+    ("select_messages", Action::SelectMessages),
+    ("compose", Action::Compose),
+    ("edit_last", Action::EditLast),
+];
+
+const DEFAULT_BINDINGS: &[(Key, Action)] = &[
+    (Key::Ctrl('c'), Action::Quit),
+    (Key::Ctrl('d'), Action::NextServer),
+    (Key::Ctrl('a'), Action::PreviousServer),
+    (Key::Down, Action::NextChannel),
+    (Key::Up, Action::PreviousChannel),
+    (Key::PageDown, Action::NextUnread),
+    (Key::Ctrl('s'), Action::NextUnread),
+    (Key::PageUp, Action::PreviousUnread),
+    (Key::Ctrl('q'), Action::ScrollUp),
+    (Key::Ctrl('e'), Action::ScrollDown),
+    (Key::Ctrl('x'), Action::SelectMessages),
+    (Key::Ctrl('o'), Action::Compose),
+    (Key::Alt('e'), Action::EditLast),
+];
+
+const NAMED_KEYS: &[(&str, Key)] = &[
+    ("up", Key::Up),
+    ("down", Key::Down),
+    ("left", Key::Left),
+    ("right", Key::Right),
+    ("pageup", Key::PageUp),
+    ("pagedown", Key::PageDown),
+    ("home", Key::Home),
+    ("end", Key::End),
+    ("insert", Key::Insert),
+    ("delete", Key::Delete),
+    ("backspace", Key::Backspace),
+    ("backtab", Key::BackTab),
+    ("esc", Key::Esc),
+];
+
+/// Which action each key chord runs
+///
+/// Starts out with the default bindings, which the `[keys]` section of the config file adds to
+/// or overrides. It maps chords like `"ctrl-n"`, `"alt-j"`, `"pageup"` or `"f5"` to an action
+/// name, or to `"none"` to free up a chord that's bound by default.
+pub struct Keymap {
+    bindings: HashMap<Key, Action>,
+}
+
+impl Keymap {
+    /// Apply the config's bindings over the defaults, or describe everything that's wrong with them
+    pub fn from_config(config: &HashMap<String, String>) -> Result<Self, Vec<String>> {
+        let mut bindings: HashMap<Key, Action> = DEFAULT_BINDINGS.iter().copied().collect();
+        let mut errors = Vec::new();
+        for (chord, action) in config {
+            let key = match parse_chord(chord) {
+                Ok(key) => key,
+                Err(e) => {
+                    errors.push(e);
+                    continue;
+                }
+            };
+            if action == "none" {
+                bindings.remove(&key);
+            } else if let Some((_, action)) = ACTIONS.iter().find(|(name, _)| name == action) {
+                bindings.insert(key, *action);
+            } else {
+                errors.push(format!(
+                    "\"{}\" is bound to unknown action \"{}\", the actions are none, {}",
+                    chord,
+                    action,
+                    ACTIONS
+                        .iter()
+                        .map(|(name, _)| *name)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ));
+            }
+        }
+        if errors.is_empty() {
+            Ok(Keymap { bindings })
+        } else {
+            Err(errors)
+        }
+    }
+
+    pub fn action(&self, key: Key) -> Option<Action> {
+        self.bindings.get(&key).copied()
+    }
+}
+
+fn parse_chord(chord: &str) -> Result<Key, String> {
+    fn single_char(text: &str) -> Option<char> {
+        let mut chars = text.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => Some(c),
+            _ => None,
+        }
+    }
+
+    let invalid = |reason: &str| format!("\"{}\" is not a valid key, {}", chord, reason);
+    let lower = chord.to_lowercase();
+    // Only the modifier is case-insensitive, alt-J and alt-j are different keys
+    let modified = |prefix: &str| {
+        chord
+            .get(prefix.len()..)
+            .filter(|_| lower.starts_with(prefix))
+    };
+
+    if let Some(rest) = modified("ctrl-") {
+        // Terminals can't tell ctrl-A from ctrl-a
+        single_char(&rest.to_lowercase())
+            .map(Key::Ctrl)
+            .ok_or_else(|| invalid("ctrl- goes with a single character"))
+    } else if let Some(rest) = modified("alt-") {
+        single_char(rest)
+            .map(Key::Alt)
+            .ok_or_else(|| invalid("alt- goes with a single character"))
+    } else if let Some(c) = single_char(chord) {
+        Ok(Key::Char(c))
+    } else if let Some((_, key)) = NAMED_KEYS.iter().find(|(name, _)| *name == lower) {
+        Ok(*key)
+    } else if let Some(n) = lower.strip_prefix('f').and_then(|n| n.parse().ok()) {
+        Ok(Key::F(n))
+    } else {
+        Err(invalid(
+            "keys look like ctrl-a, alt-a, a, f1, up, down, left, right, pageup, pagedown, home, \
+             end, insert, delete, backspace, backtab or esc",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_modified_named_and_function_keys() {
+        assert_eq!(parse_chord("ctrl-n"), Ok(Key::Ctrl('n')));
+        assert_eq!(parse_chord("Ctrl-N"), Ok(Key::Ctrl('n')));
+        assert_eq!(parse_chord("alt-j"), Ok(Key::Alt('j')));
+        assert_eq!(parse_chord("ALT-J"), Ok(Key::Alt('J')));
+        assert_eq!(parse_chord("x"), Ok(Key::Char('x')));
+        assert_eq!(parse_chord("-"), Ok(Key::Char('-')));
+        assert_eq!(parse_chord("PageUp"), Ok(Key::PageUp));
+        assert_eq!(parse_chord("f5"), Ok(Key::F(5)));
+        assert_eq!(parse_chord("F12"), Ok(Key::F(12)));
+    }
+
+    #[test]
+    fn rejects_malformed_chords() {
+        assert!(parse_chord("ctrl-").is_err());
+        assert!(parse_chord("ctrl-ab").is_err());
+        assert!(parse_chord("alt-").is_err());
+        assert!(parse_chord("shift-a").is_err());
+        assert!(parse_chord("fx").is_err());
+        assert!(parse_chord("").is_err());
+    }
+
+    #[test]
+    fn config_overrides_and_frees_defaults() {
+        let mut config = HashMap::new();
+        config.insert("ctrl-n".to_string(), "next_server".to_string());
+        config.insert("ctrl-a".to_string(), "none".to_string());
+        let keymap = Keymap::from_config(&config).unwrap();
+        assert_eq!(keymap.action(Key::Ctrl('n')), Some(Action::NextServer));
+        assert_eq!(keymap.action(Key::Ctrl('a')), None);
+        assert_eq!(keymap.action(Key::Ctrl('d')), Some(Action::NextServer));
+    }
+
+    #[test]
+    fn reports_every_problem_in_the_config() {
+        let mut config = HashMap::new();
+        config.insert("ctrl-n".to_string(), "fly".to_string());
+        config.insert("hyper-x".to_string(), "quit".to_string());
+        let errors = Keymap::from_config(&config).err().unwrap();
+        assert_eq!(errors.len(), 2);
+        assert!(errors.iter().any(|e| e.contains("unknown action \"fly\"")));
+        assert!(errors
+            .iter()
+            .any(|e| e.contains("\"hyper-x\" is not a valid key")));
+    }
+}
@@ -0,0 +1,408 @@
+use unicode_segmentation::UnicodeSegmentation;
+
+// How many sent messages each channel remembers
+const MAX_HISTORY: usize = 100;
+
+/// The message being typed into a channel, and the ones that were sent from it before
+///
+/// The cursor is a byte offset that only ever sits between grapheme clusters, so no edit can
+/// split a character or separate one from its combining marks. Messages can span several lines,
+/// and moving to the start or end, or cutting to them, works on the line the cursor is in.
+#[derive(Default)]
+pub struct LineEditor {
+    buffer: String,
+    cursor: usize,
+    history: Vec<String>,
+    /// Which history entry is shown while going through them, and the draft it replaced
+    browsing: Option<(usize, String)>,
+}
+
+fn is_word(grapheme: &str) -> bool {
+    matches!(grapheme.chars().next(), Some(c) if c.is_alphanumeric())
+}
+
+fn is_space(grapheme: &str) -> bool {
+    grapheme.chars().all(char::is_whitespace)
+}
+
+impl LineEditor {
+    pub fn text(&self) -> &str {
+        &self.buffer
+    }
+
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    /// Replace everything, leaving the cursor at the end
+    pub fn set_text(&mut self, text: String) {
+        self.buffer = text;
+        self.cursor = self.buffer.len();
+    }
+
+    pub fn clear(&mut self) {
+        self.set_text(String::new());
+        self.browsing = None;
+    }
+
+    /// Empty the editor to send what was in it, remembering it in the history
+    pub fn take(&mut self) -> String {
+        let text = std::mem::take(&mut self.buffer);
+        self.cursor = 0;
+        self.browsing = None;
+        if self.history.last() != Some(&text) {
+            self.history.push(text.clone());
+            let excess = self.history.len().saturating_sub(MAX_HISTORY);
+            self.history.drain(..excess);
+        }
+        text
+    }
+
+    pub fn insert(&mut self, c: char) {
+        self.buffer.insert(self.cursor, c);
+        self.cursor += c.len_utf8();
+        self.snap_cursor();
+    }
+
+    pub fn insert_str(&mut self, text: &str) {
+        self.buffer.insert_str(self.cursor, text);
+        self.cursor += text.len();
+        self.snap_cursor();
+    }
+
+    /// The start of the word under the cursor, up to the cursor, which is what gets completed
+    pub fn word_before_cursor(&self) -> &str {
+        &self.buffer[self.completion_start()..self.cursor]
+    }
+
+    /// Swap the whole word under the cursor for a completion of it, and move past it
+    pub fn replace_word(&mut self, word: &str) {
+        let start = self.completion_start();
+        let end = self.buffer[self.cursor..]
+            .find(char::is_whitespace)
+            .map_or(self.buffer.len(), |i| self.cursor + i);
+        self.buffer.replace_range(start..end, word);
+        self.cursor = start + word.len();
+    }
+
+    // Completed words run until whitespace, so that they can include things like @ and :
+    fn completion_start(&self) -> usize {
+        self.buffer[..self.cursor]
+            .trim_end_matches(|c: char| !c.is_whitespace())
+            .len()
+    }
+
+    // What was inserted may have joined the cluster after it, so move past the whole thing
+    fn snap_cursor(&mut self) {
+        self.cursor = self
+            .buffer
+            .grapheme_indices(true)
+            .map(|(i, g)| i + g.len())
+            .find(|&end| end >= self.cursor)
+            .unwrap_or(self.buffer.len());
+    }
+
+    fn line_start(&self, offset: usize) -> usize {
+        self.buffer[..offset].rfind('\n').map_or(0, |i| i + 1)
+    }
+
+    fn line_end(&self, offset: usize) -> usize {
+        self.buffer[offset..]
+            .find('\n')
+            .map_or(self.buffer.len(), |i| offset + i)
+    }
+
+    // Where the cursor would be after going `column` graphemes into the line, or its end
+    fn offset_in_line(&self, line_start: usize, column: usize) -> usize {
+        let line_end = self.line_end(line_start);
+        self.buffer[line_start..line_end]
+            .grapheme_indices(true)
+            .nth(column)
+            .map_or(line_end, |(i, _)| line_start + i)
+    }
+
+    fn column(&self) -> usize {
+        self.buffer[self.line_start(self.cursor)..self.cursor]
+            .graphemes(true)
+            .count()
+    }
+
+    fn previous_boundary(&self) -> usize {
+        self.buffer[..self.cursor]
+            .grapheme_indices(true)
+            .next_back()
+            .map_or(0, |(i, _)| i)
+    }
+
+    fn next_boundary(&self) -> usize {
+        self.buffer[self.cursor..]
+            .graphemes(true)
+            .next()
+            .map_or(self.cursor, |g| self.cursor + g.len())
+    }
+
+    /// The start of the word before the cursor, where words are runs of graphemes that `is_word`
+    fn word_start(&self, is_word: impl Fn(&str) -> bool) -> usize {
+        let mut start = self.cursor;
+        let mut in_word = false;
+        for (i, grapheme) in self.buffer[..self.cursor].grapheme_indices(true).rev() {
+            if is_word(grapheme) {
+                in_word = true;
+            } else if in_word {
+                break;
+            }
+            start = i;
+        }
+        start
+    }
+
+    fn word_end(&self) -> usize {
+        let mut end = self.cursor;
+        let mut in_word = false;
+        for (i, grapheme) in self.buffer[self.cursor..].grapheme_indices(true) {
+            if is_word(grapheme) {
+                in_word = true;
+            } else if in_word {
+                break;
+            }
+            end = self.cursor + i + grapheme.len();
+        }
+        end
+    }
+
+    // Remove the text between the cursor and another offset, returning it
+    fn kill(&mut self, other: usize) -> String {
+        let (start, end) = (self.cursor.min(other), self.cursor.max(other));
+        self.cursor = start;
+        self.buffer.drain(start..end).collect()
+    }
+
+    pub fn backspace(&mut self) {
+        let previous = self.previous_boundary();
+        self.kill(previous);
+    }
+
+    pub fn delete(&mut self) {
+        let next = self.next_boundary();
+        self.kill(next);
+    }
+
+    pub fn left(&mut self) {
+        self.cursor = self.previous_boundary();
+    }
+
+    pub fn right(&mut self) {
+        self.cursor = self.next_boundary();
+    }
+
+    pub fn home(&mut self) {
+        self.cursor = self.line_start(self.cursor);
+    }
+
+    pub fn end(&mut self) {
+        self.cursor = self.line_end(self.cursor);
+    }
+
+    /// Move to the line above, returning false if the cursor is already on the first line
+    pub fn line_up(&mut self) -> bool {
+        let start = self.line_start(self.cursor);
+        if start == 0 {
+            return false;
+        }
+        let column = self.column();
+        self.cursor = self.offset_in_line(self.line_start(start - 1), column);
+        true
+    }
+
+    /// Move to the line below, returning false if the cursor is already on the last line
+    pub fn line_down(&mut self) -> bool {
+        let end = self.line_end(self.cursor);
+        if end == self.buffer.len() {
+            return false;
+        }
+        let column = self.column();
+        self.cursor = self.offset_in_line(end + 1, column);
+        true
+    }
+
+    pub fn word_left(&mut self) {
+        self.cursor = self.word_start(is_word);
+    }
+
+    pub fn word_right(&mut self) {
+        self.cursor = self.word_end();
+    }
+
+    /// Delete back to the previous space, like Ctrl-W in a shell
+    pub fn kill_word_back(&mut self) -> String {
+        let start = self.word_start(|g| !is_space(g));
+        self.kill(start)
+    }
+
+    /// Delete back to the start of the word, stopping at punctuation as well as spaces
+    pub fn kill_alphanumeric_back(&mut self) -> String {
+        let start = self.word_start(is_word);
+        self.kill(start)
+    }
+
+    pub fn kill_to_start(&mut self) -> String {
+        let start = self.line_start(self.cursor);
+        self.kill(start)
+    }
+
+    pub fn kill_to_end(&mut self) -> String {
+        let end = self.line_end(self.cursor);
+        self.kill(end)
+    }
+
+    pub fn is_browsing_history(&self) -> bool {
+        self.browsing.is_some()
+    }
+
+    /// Show the message sent before the one shown, returning false if there isn't one
+    pub fn history_older(&mut self) -> bool {
+        let index = match &self.browsing {
+            Some((0, _)) => return false,
+            Some((i, _)) => i - 1,
+            None if self.history.is_empty() => return false,
+            None => {
+                let draft = std::mem::take(&mut self.buffer);
+                self.browsing = Some((self.history.len(), draft));
+                self.history.len() - 1
+            }
+        };
+        if let Some((i, _)) = &mut self.browsing {
+            *i = index;
+        }
+        self.set_text(self.history[index].clone());
+        true
+    }
+
+    /// Show the message sent after the one shown, or go back to the draft after the last one
+    pub fn history_newer(&mut self) -> bool {
+        match self.browsing.take() {
+            None => false,
+            Some((i, draft)) if i + 1 >= self.history.len() => {
+                self.set_text(draft);
+                true
+            }
+            Some((i, draft)) => {
+                self.browsing = Some((i + 1, draft));
+                self.set_text(self.history[i + 1].clone());
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn editor(text: &str) -> LineEditor {
+        let mut editor = LineEditor::default();
+        editor.set_text(text.to_string());
+        editor
+    }
+
+    #[test]
+    fn moves_over_whole_graphemes() {
+        // An e with a combining acute accent, then a family emoji joined from three
+        let mut editor = editor("e\u{301}\u{1f468}\u{200d}\u{1f469}\u{200d}\u{1f467}x");
+        editor.left();
+        assert_eq!(editor.cursor(), editor.text().len() - 1);
+        editor.left();
+        assert_eq!(editor.cursor(), "e\u{301}".len());
+        editor.left();
+        assert_eq!(editor.cursor(), 0);
+        editor.left();
+        assert_eq!(editor.cursor(), 0);
+        editor.right();
+        assert_eq!(editor.cursor(), "e\u{301}".len());
+    }
+
+    #[test]
+    fn deletes_whole_graphemes() {
+        let mut editor = editor("ae\u{301}");
+        editor.backspace();
+        assert_eq!(editor.text(), "a");
+        editor.home();
+        editor.insert_str("e\u{301}");
+        editor.home();
+        editor.delete();
+        assert_eq!(editor.text(), "a");
+    }
+
+    #[test]
+    fn inserting_a_combining_mark_keeps_the_cursor_after_it() {
+        let mut editor = editor("e");
+        editor.insert('\u{301}');
+        assert_eq!(editor.cursor(), editor.text().len());
+        editor.insert('x');
+        assert_eq!(editor.text(), "e\u{301}x");
+    }
+
+    #[test]
+    fn kill_word_back_stops_at_spaces() {
+        let mut editor = editor("hello foo-bar  baz");
+        assert_eq!(editor.kill_word_back(), "baz");
+        assert_eq!(editor.kill_word_back(), "foo-bar  ");
+        assert_eq!(editor.text(), "hello ");
+    }
+
+    #[test]
+    fn kill_alphanumeric_back_stops_at_punctuation() {
+        let mut editor = editor("hello foo-bar");
+        assert_eq!(editor.kill_alphanumeric_back(), "bar");
+        assert_eq!(editor.kill_alphanumeric_back(), "foo-");
+        assert_eq!(editor.text(), "hello ");
+    }
+
+    #[test]
+    fn killing_at_the_start_kills_nothing() {
+        let mut editor = editor("word");
+        editor.home();
+        assert_eq!(editor.kill_word_back(), "");
+        assert_eq!(editor.kill_alphanumeric_back(), "");
+        assert_eq!(editor.text(), "word");
+    }
+
+    #[test]
+    fn history_comes_back_to_the_draft() {
+        let mut editor = LineEditor::default();
+        for sent in &["one", "two"] {
+            editor.set_text(sent.to_string());
+            editor.take();
+        }
+        editor.set_text("draft".to_string());
+
+        assert!(editor.history_older());
+        assert_eq!(editor.text(), "two");
+        assert!(editor.history_older());
+        assert_eq!(editor.text(), "one");
+        assert!(!editor.history_older());
+        assert_eq!(editor.text(), "one");
+
+        assert!(editor.history_newer());
+        assert_eq!(editor.text(), "two");
+        assert!(editor.history_newer());
+        assert_eq!(editor.text(), "draft");
+        assert!(!editor.is_browsing_history());
+        assert!(!editor.history_newer());
+    }
+
+    #[test]
+    fn history_skips_repeats() {
+        let mut editor = LineEditor::default();
+        for sent in &["same", "same"] {
+            editor.set_text(sent.to_string());
+            editor.take();
+        }
+        assert!(editor.history_older());
+        assert!(!editor.history_older());
+    }
+}
@@ -1,29 +1,41 @@
 #![recursion_limit = "1024"]
 
 use serde::Deserialize;
+use std::collections::HashMap;
 
 mod bimap;
 mod chan_message;
 mod conn;
 mod curses;
 mod cursor_vec;
+mod discord_conn;
+mod keys;
+mod line_editor;
 mod logger;
+mod notify;
+mod search;
 mod slack_conn;
+mod store;
+mod theme;
 mod tui;
 
-#[derive(Deserialize)]
-struct SlackConfig {
-    token: String,
-}
-
 #[derive(Deserialize)]
 struct Config {
-    slack: Option<Vec<SlackConfig>>,
+    #[serde(default)]
+    keys: HashMap<String, String>,
+    #[serde(default)]
+    theme: HashMap<String, toml::Value>,
+    /// Words that count as mentioning us, along with our name, @here and @channel
+    #[serde(default)]
+    keywords: Vec<String>,
+    #[serde(default)]
+    notifications: HashMap<String, toml::Value>,
+    #[serde(flatten)]
+    backends: HashMap<String, toml::Value>,
 }
 
 #[tokio::main(core_threads = 4)]
 async fn main() {
-    use crate::slack_conn::SlackConn;
     use std::fs::File;
     use std::io::Read;
     use std::path::PathBuf;
@@ -55,23 +67,56 @@ async fn main() {
         std::process::exit(1)
     });
 
-    let tui = tui::Tui::new();
+    let keymap = keys::Keymap::from_config(&config.keys).unwrap_or_else(|errors| {
+        println!("Invalid [keys] in {:?}:", &config_path);
+        for e in errors {
+            println!("    {}", e);
+        }
+        std::process::exit(1)
+    });
+
+    let theme = theme::Theme::from_config(&config.theme).unwrap_or_else(|errors| {
+        println!("Invalid [theme] in {:?}:", &config_path);
+        for e in errors {
+            println!("    {}", e);
+        }
+        std::process::exit(1)
+    });
+
+    let notifier = notify::Notifier::from_config(&config.notifications).unwrap_or_else(|errors| {
+        println!("Invalid [notifications] in {:?}:", &config_path);
+        for e in errors {
+            println!("    {}", e);
+        }
+        std::process::exit(1)
+    });
+
+    conn::check_backends(&config.backends).unwrap_or_else(|errors| {
+        println!("Invalid config in {:?}:", &config_path);
+        for e in errors {
+            println!("    {}", e);
+        }
+        std::process::exit(1)
+    });
+
+    let tui = tui::Tui::new(keymap, theme, config.keywords, notifier);
 
     // Init the global logger
     log::set_boxed_logger(Box::new(logger::Logger::new(tui.sender())))
         .expect("Unable to create global logger");
     log::set_max_level(log::LevelFilter::Warn);
 
-    // Start all the slack connections
-    if let Some(slack) = config.slack {
-        for c in slack {
-            let sender = tui.sender();
-            let token = c.token.clone();
-            tokio::spawn(async move { SlackConn::create_on(&token, sender).await });
-        }
-    }
+    // Start all the configured connections
+    let connections = conn::launch_all(config.backends, &tui.sender());
+
+    tui.run().await;
 
-    tui.run().await
+    // Give the backends a moment to shut down cleanly
+    let _ = tokio::time::timeout(
+        std::time::Duration::from_secs(1),
+        futures::future::join_all(connections),
+    )
+    .await;
 }
 
 use regex_automata::{DenseDFA, DFA};
@@ -0,0 +1,501 @@
+use chrono::NaiveTime;
+use log::error;
+use std::collections::HashMap;
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+/// Which messages in a server or channel are worth a notification
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Level {
+    All,
+    /// Only messages that mention us, and direct messages
+    Mentions,
+    None,
+}
+
+impl Level {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "all" => Some(Level::All),
+            "mentions" => Some(Level::Mentions),
+            "none" => Some(Level::None),
+            _ => None,
+        }
+    }
+}
+
+/// Somewhere notifications can go
+#[derive(Clone, Debug, PartialEq)]
+enum Sink {
+    /// A freedesktop notification, through notify-send
+    Desktop,
+    Bell,
+    /// The OSC 9 escape sequence, which iTerm2, Windows Terminal and others show as a notification
+    Osc9,
+    /// The OSC 777 escape sequence, for urxvt, foot and VTE-based terminals
+    Osc777,
+    /// A shell command, which gets the message in its environment
+    Command(String),
+}
+
+/// A message that might be worth notifying about
+pub struct Notification<'a> {
+    pub server: &'a str,
+    pub channel: &'a str,
+    pub sender: &'a str,
+    pub text: &'a str,
+    pub direct: bool,
+    pub mentions_us: bool,
+}
+
+/// Tells us about new messages when we aren't looking at them
+///
+/// Set up with the `[notifications]` section of the config file, where `sinks` lists where
+/// notifications go, `level` is which messages are worth one, and `levels` overrides that for a
+/// server or a channel, written as `"server"` or `"server/channel"`. Nothing is sent during
+/// `quiet_hours`, written like `"22:00-08:00"`.
+///
+/// A burst of messages in one channel only makes one notification. The burst lasts until
+/// `BURST_WINDOW` has gone by since the last notification sent there, and then the last message
+/// held back is sent on its own, saying how many went by in between.
+pub struct Notifier {
+    sinks: Vec<Sink>,
+    level: Level,
+    levels: HashMap<String, Level>,
+    quiet_hours: Option<(NaiveTime, NaiveTime)>,
+    /// When each server and channel last had a notification, and what was held back since
+    recent: HashMap<(String, String), Recent>,
+}
+
+struct Recent {
+    sent: Instant,
+    held: usize,
+    /// The newest message held back, which is sent once the burst is over
+    last: Option<Held>,
+}
+
+struct Held {
+    sender: String,
+    text: String,
+    direct: bool,
+}
+
+const SINKS: &str = "\"desktop\", \"bell\", \"osc9\", \"osc777\" and \"command\"";
+
+// The longest a message gets in a notification, any more is cut off
+const MAX_BODY_CHARS: usize = 200;
+
+// How long after a notification more from the same channel are held back
+const BURST_WINDOW: Duration = Duration::from_secs(10);
+
+impl Notifier {
+    /// Apply the `[notifications]` section of the config file, or describe everything that's wrong
+    /// with it
+    pub fn from_config(config: &HashMap<String, toml::Value>) -> Result<Self, Vec<String>> {
+        let mut errors = Vec::new();
+        let mut notifier = Notifier {
+            sinks: vec![Sink::Desktop],
+            level: Level::Mentions,
+            levels: HashMap::new(),
+            quiet_hours: None,
+            recent: HashMap::new(),
+        };
+
+        let parse_level = |value: &toml::Value, errors: &mut Vec<String>| {
+            let level = value.as_str().and_then(Level::from_name);
+            if level.is_none() {
+                errors.push(format!(
+                    "levels are \"all\", \"mentions\" or \"none\", not {}",
+                    value
+                ));
+            }
+            level
+        };
+
+        let mut sink_names = Vec::new();
+        for (name, value) in config {
+            match name.as_str() {
+                "sinks" => match value.as_array() {
+                    Some(values) => sink_names = values.iter().collect(),
+                    None => errors.push(format!("sinks must be a list, not {}", value)),
+                },
+                // Checked along with the sinks
+                "command" => {}
+                "level" => {
+                    if let Some(level) = parse_level(value, &mut errors) {
+                        notifier.level = level;
+                    }
+                }
+                "levels" => match value.as_table() {
+                    Some(table) => {
+                        for (place, value) in table {
+                            if let Some(level) = parse_level(value, &mut errors) {
+                                notifier.levels.insert(place.clone(), level);
+                            }
+                        }
+                    }
+                    None => errors.push(format!(
+                        "levels must be a table of servers and channels, not {}",
+                        value
+                    )),
+                },
+                "quiet_hours" => match value.as_str().and_then(parse_hours) {
+                    Some(hours) => notifier.quiet_hours = Some(hours),
+                    None => errors.push(format!(
+                        "quiet_hours must look like \"22:00-08:00\", not {}",
+                        value
+                    )),
+                },
+                _ => errors.push(format!(
+                    "unknown setting \"{}\", the settings are sinks, command, level, levels and \
+                     quiet_hours",
+                    name
+                )),
+            }
+        }
+
+        if config.contains_key("sinks") {
+            notifier.sinks.clear();
+        }
+        for value in sink_names {
+            let sink = match value.as_str() {
+                Some("desktop") => Sink::Desktop,
+                Some("bell") => Sink::Bell,
+                Some("osc9") => Sink::Osc9,
+                Some("osc777") => Sink::Osc777,
+                Some("command") => match config.get("command").and_then(|c| c.as_str()) {
+                    Some(command) => Sink::Command(command.to_string()),
+                    None => {
+                        errors.push("the command sink needs a command to run".to_string());
+                        continue;
+                    }
+                },
+                _ => {
+                    errors.push(format!("the sinks are {}, not {}", SINKS, value));
+                    continue;
+                }
+            };
+            notifier.sinks.push(sink);
+        }
+
+        if errors.is_empty() {
+            Ok(notifier)
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn level_for(&self, server: &str, channel: &str) -> Level {
+        self.levels
+            .get(&format!("{}/{}", server, channel))
+            .or_else(|| self.levels.get(server))
+            .copied()
+            .unwrap_or(self.level)
+    }
+
+    fn is_quiet(&self) -> bool {
+        self.is_quiet_at(chrono::Local::now().time())
+    }
+
+    fn is_quiet_at(&self, now: NaiveTime) -> bool {
+        match self.quiet_hours {
+            Some((start, end)) => {
+                // A window like 22:00-08:00 goes past midnight
+                if start <= end {
+                    start <= now && now < end
+                } else {
+                    now >= start || now < end
+                }
+            }
+            None => false,
+        }
+    }
+
+    /// Send a notification to every sink, if the message is worth one
+    ///
+    /// A message held back as part of a burst returns when to call `flush` to send it, the first
+    /// time the burst holds one back.
+    pub fn notify(&mut self, notification: &Notification) -> Option<Instant> {
+        let wanted = match self.level_for(notification.server, notification.channel) {
+            Level::All => true,
+            Level::Mentions => notification.direct || notification.mentions_us,
+            Level::None => false,
+        };
+        if !wanted || self.is_quiet() {
+            return None;
+        }
+
+        let now = Instant::now();
+        let place = (
+            notification.server.to_string(),
+            notification.channel.to_string(),
+        );
+        let held = match self.recent.get_mut(&place) {
+            Some(recent) if now.duration_since(recent.sent) < BURST_WINDOW => {
+                recent.held += 1;
+                recent.last = Some(Held {
+                    sender: notification.sender.to_string(),
+                    text: notification.text.to_string(),
+                    direct: notification.direct,
+                });
+                return if recent.held == 1 {
+                    Some(recent.sent + BURST_WINDOW)
+                } else {
+                    None
+                };
+            }
+            Some(recent) => recent.held,
+            None => 0,
+        };
+        self.recent.insert(
+            place,
+            Recent {
+                sent: now,
+                held: 0,
+                last: None,
+            },
+        );
+        self.send(notification, held);
+        None
+    }
+
+    /// Send the last message held back in each burst that's over, unless it's now quiet hours
+    pub fn flush(&mut self) {
+        let now = Instant::now();
+        let quiet = self.is_quiet();
+        let mut due = Vec::new();
+        for ((server, channel), recent) in &mut self.recent {
+            if now.duration_since(recent.sent) < BURST_WINDOW {
+                continue;
+            }
+            if let Some(last) = recent.last.take() {
+                if !quiet {
+                    // The message itself is counted by being the one shown
+                    due.push((server.clone(), channel.clone(), last, recent.held - 1));
+                    recent.sent = now;
+                }
+                recent.held = 0;
+            }
+        }
+        for (server, channel, last, held) in due {
+            let notification = Notification {
+                server: &server,
+                channel: &channel,
+                sender: &last.sender,
+                text: &last.text,
+                direct: last.direct,
+                mentions_us: false,
+            };
+            self.send(&notification, held);
+        }
+    }
+
+    fn send(&mut self, notification: &Notification, held: usize) {
+        let mut title = if notification.direct {
+            format!("{} ({})", notification.sender, notification.server)
+        } else {
+            format!(
+                "{} in #{} ({})",
+                notification.sender, notification.channel, notification.server
+            )
+        };
+        if held > 0 {
+            title.push_str(&format!(", after {} more", held));
+        }
+        let mut body: String = notification.text.chars().take(MAX_BODY_CHARS).collect();
+        if body.len() < notification.text.len() {
+            body.push('…');
+        }
+
+        // A sink that can't be run is dropped, so that the error only shows up once
+        let mut broken = Vec::new();
+        for sink in &self.sinks {
+            let sent = match sink {
+                Sink::Desktop => spawn(
+                    tokio::process::Command::new("notify-send")
+                        .arg("--app-name=omnichat")
+                        .arg(&title)
+                        .arg(&body),
+                ),
+                Sink::Bell => write_to_terminal("\x07"),
+                Sink::Osc9 => write_to_terminal(&format!(
+                    "\x1b]9;{}: {}\x07",
+                    printable(&title),
+                    printable(&body)
+                )),
+                Sink::Osc777 => write_to_terminal(&format!(
+                    "\x1b]777;notify;{};{}\x07",
+                    // The title ends at the first semicolon
+                    printable(&title).replace(';', ","),
+                    printable(&body)
+                )),
+                Sink::Command(command) => spawn(
+                    tokio::process::Command::new("sh")
+                        .arg("-c")
+                        .arg(command)
+                        .env("OMNICHAT_SERVER", notification.server)
+                        .env("OMNICHAT_CHANNEL", notification.channel)
+                        .env("OMNICHAT_SENDER", notification.sender)
+                        .env("OMNICHAT_MESSAGE", notification.text)
+                        .env("OMNICHAT_TITLE", &title),
+                ),
+            };
+            if let Err(e) = sent {
+                error!(
+                    "Couldn't send a notification, turning off {:?}: {}",
+                    sink, e
+                );
+                broken.push(sink.clone());
+            }
+        }
+        self.sinks.retain(|s| !broken.contains(s));
+    }
+}
+
+// Run a notification command in the background, without waiting for it to finish
+fn spawn(command: &mut tokio::process::Command) -> std::io::Result<()> {
+    let child = command
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .spawn()?;
+    tokio::spawn(async move {
+        let _ = child.await;
+    });
+    Ok(())
+}
+
+fn write_to_terminal(text: &str) -> std::io::Result<()> {
+    let mut stdout = std::io::stdout();
+    stdout.write_all(text.as_bytes())?;
+    stdout.flush()
+}
+
+// Escape sequences end at the first control character, so a message can't contain any
+fn printable(text: &str) -> String {
+    text.chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .collect()
+}
+
+fn parse_hours(text: &str) -> Option<(NaiveTime, NaiveTime)> {
+    let mut times = text
+        .splitn(2, '-')
+        .map(|t| NaiveTime::parse_from_str(t.trim(), "%H:%M").ok());
+    Some((times.next()??, times.next()??))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn notifier(text: &str) -> Notifier {
+        Notifier::from_config(&toml::from_str(text).unwrap())
+            .ok()
+            .unwrap()
+    }
+
+    fn time(text: &str) -> NaiveTime {
+        NaiveTime::parse_from_str(text, "%H:%M").unwrap()
+    }
+
+    fn message(channel: &str) -> Notification<'_> {
+        Notification {
+            server: "work",
+            channel,
+            sender: "amy",
+            text: "hello",
+            direct: false,
+            mentions_us: true,
+        }
+    }
+
+    #[test]
+    fn hours_are_a_start_and_an_end() {
+        assert_eq!(
+            parse_hours("22:00-08:00"),
+            Some((time("22:00"), time("08:00")))
+        );
+        assert_eq!(
+            parse_hours(" 9:30 - 17:00 "),
+            Some((time("09:30"), time("17:00")))
+        );
+        assert_eq!(parse_hours("22:00"), None);
+        assert_eq!(parse_hours("22:00-"), None);
+        assert_eq!(parse_hours("10pm-8am"), None);
+        assert_eq!(parse_hours("25:00-08:00"), None);
+    }
+
+    #[test]
+    fn quiet_hours_can_go_past_midnight() {
+        let notifier = notifier("quiet_hours = \"22:00-08:00\"");
+        assert!(notifier.is_quiet_at(time("22:00")));
+        assert!(notifier.is_quiet_at(time("23:59")));
+        assert!(notifier.is_quiet_at(time("00:00")));
+        assert!(notifier.is_quiet_at(time("07:59")));
+        assert!(!notifier.is_quiet_at(time("08:00")));
+        assert!(!notifier.is_quiet_at(time("12:00")));
+        assert!(!notifier.is_quiet_at(time("21:59")));
+    }
+
+    #[test]
+    fn quiet_hours_within_a_day() {
+        let notifier = notifier("quiet_hours = \"12:00-13:00\"");
+        assert!(notifier.is_quiet_at(time("12:30")));
+        assert!(!notifier.is_quiet_at(time("13:00")));
+        assert!(!notifier.is_quiet_at(time("00:30")));
+        assert!(!self::notifier("").is_quiet_at(time("12:30")));
+    }
+
+    #[test]
+    fn channels_override_servers_override_the_default() {
+        let notifier = notifier(
+            "level = \"none\"\n\
+             [levels]\n\
+             work = \"mentions\"\n\
+             \"work/general\" = \"all\"",
+        );
+        assert_eq!(notifier.level_for("work", "general"), Level::All);
+        assert_eq!(notifier.level_for("work", "random"), Level::Mentions);
+        assert_eq!(notifier.level_for("home", "general"), Level::None);
+    }
+
+    #[test]
+    fn bad_settings_are_all_reported() {
+        let errors = Notifier::from_config(
+            &toml::from_str(
+                "sinks = [\"command\", \"pager\"]\nlevel = \"loud\"\nquiet_hours = \"late\"",
+            )
+            .unwrap(),
+        )
+        .err()
+        .unwrap();
+        assert_eq!(errors.len(), 4);
+    }
+
+    #[test]
+    fn a_burst_sends_what_it_held_once_over() {
+        let mut notifier = notifier("sinks = []");
+        assert_eq!(notifier.notify(&message("general")), None);
+        // Only the first message held back needs a flush scheduled
+        let due = notifier.notify(&message("general"));
+        assert!(due.is_some());
+        assert_eq!(notifier.notify(&message("general")), None);
+        // Other channels have their own bursts
+        assert_eq!(notifier.notify(&message("random")), None);
+
+        let place = ("work".to_string(), "general".to_string());
+        notifier.flush();
+        assert_eq!(notifier.recent[&place].held, 2);
+
+        let recent = notifier.recent.get_mut(&place).unwrap();
+        recent.sent -= BURST_WINDOW;
+        let before = recent.sent;
+        notifier.flush();
+        let recent = &notifier.recent[&place];
+        assert_eq!(recent.held, 0);
+        assert!(recent.last.is_none());
+        // Sending what was held starts a new window
+        assert!(recent.sent > before);
+    }
+}
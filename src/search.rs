@@ -0,0 +1,156 @@
+use crate::chan_message::ChanMessage;
+use crate::conn::DateTime;
+
+/// A parsed `/search` query
+///
+/// Words are matched case-insensitively against the message text, sender and channel name, and
+/// every one of them has to match. The filters `from:sender`, `in:channel`, `after:YYYY-MM-DD`
+/// and `before:YYYY-MM-DD` narrow things down further.
+pub struct Query {
+    words: Vec<String>,
+    from: Option<String>,
+    channel: Option<String>,
+    after: Option<DateTime>,
+    before: Option<DateTime>,
+}
+
+fn parse_date(date: &str) -> Result<DateTime, String> {
+    use chrono::TimeZone;
+    let date = chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .map_err(|_| format!("{} is not a date, dates look like 2020-01-31", date))?;
+    // Dates are in local time, that's what the timestamps on messages are displayed in
+    chrono::Local
+        .from_local_datetime(&date.and_hms(0, 0, 0))
+        .earliest()
+        .map(|d| d.with_timezone(&chrono::Utc).into())
+        .ok_or_else(|| format!("{} doesn't exist in the local timezone", date))
+}
+
+impl Query {
+    pub fn parse(query: &str) -> Result<Self, String> {
+        let mut parsed = Query {
+            words: Vec::new(),
+            from: None,
+            channel: None,
+            after: None,
+            before: None,
+        };
+        for word in query.split_whitespace() {
+            if let Some(from) = word.strip_prefix("from:") {
+                parsed.from = Some(from.trim_start_matches('@').to_lowercase());
+            } else if let Some(channel) = word.strip_prefix("in:") {
+                parsed.channel = Some(channel.trim_start_matches('#').to_lowercase());
+            } else if let Some(date) = word.strip_prefix("after:") {
+                parsed.after = Some(parse_date(date)?);
+            } else if let Some(date) = word.strip_prefix("before:") {
+                parsed.before = Some(parse_date(date)?);
+            } else {
+                parsed.words.push(word.to_lowercase());
+            }
+        }
+        Ok(parsed)
+    }
+
+    pub fn matches(&self, channel_name: &str, message: &ChanMessage) -> bool {
+        let sender = message.sender().to_lowercase();
+        let channel_name = channel_name.to_lowercase();
+        if self.from.as_ref().map_or(false, |from| *from != sender)
+            || self
+                .channel
+                .as_ref()
+                .map_or(false, |channel| *channel != channel_name)
+            || self
+                .after
+                .map_or(false, |after| *message.timestamp() < after)
+            || self
+                .before
+                .map_or(false, |before| *message.timestamp() >= before)
+        {
+            return false;
+        }
+        let text = message.raw.to_lowercase();
+        self.words.iter().all(|word| {
+            text.contains(word.as_str())
+                || sender.contains(word.as_str())
+                || channel_name.contains(word.as_str())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conn::{ChannelId, Message, MessageId, ServerId};
+
+    fn message(sender: &str, contents: &str, date: &str) -> ChanMessage {
+        ChanMessage::from(Message {
+            id: MessageId::from("1"),
+            server: ServerId::from("server"),
+            channel: ChannelId::from("channel"),
+            sender: sender.to_string(),
+            contents: contents.to_string(),
+            timestamp: parse_date(date).unwrap(),
+            reactions: Vec::new(),
+            thread: None,
+            broadcast: false,
+            reply_count: 0,
+            edited: false,
+        })
+    }
+
+    #[test]
+    fn every_word_has_to_match_somewhere() {
+        let message = message("Alice", "Lunch at noon?", "2020-01-02");
+        assert!(Query::parse("lunch").unwrap().matches("general", &message));
+        assert!(Query::parse("LUNCH alice")
+            .unwrap()
+            .matches("general", &message));
+        assert!(Query::parse("noon gen")
+            .unwrap()
+            .matches("general", &message));
+        assert!(!Query::parse("lunch dinner")
+            .unwrap()
+            .matches("general", &message));
+        assert!(Query::parse("").unwrap().matches("general", &message));
+    }
+
+    #[test]
+    fn filters_by_sender_and_channel() {
+        let message = message("Alice", "Lunch at noon?", "2020-01-02");
+        assert!(Query::parse("from:@alice")
+            .unwrap()
+            .matches("general", &message));
+        assert!(!Query::parse("from:ali")
+            .unwrap()
+            .matches("general", &message));
+        assert!(Query::parse("in:#General lunch")
+            .unwrap()
+            .matches("general", &message));
+        assert!(!Query::parse("in:random")
+            .unwrap()
+            .matches("general", &message));
+    }
+
+    #[test]
+    fn filters_by_date() {
+        let message = message("Alice", "Lunch at noon?", "2020-01-02");
+        assert!(Query::parse("after:2020-01-02")
+            .unwrap()
+            .matches("general", &message));
+        assert!(!Query::parse("after:2020-01-03")
+            .unwrap()
+            .matches("general", &message));
+        assert!(Query::parse("before:2020-01-03")
+            .unwrap()
+            .matches("general", &message));
+        assert!(!Query::parse("before:2020-01-02")
+            .unwrap()
+            .matches("general", &message));
+    }
+
+    #[test]
+    fn rejects_invalid_dates() {
+        assert!(Query::parse("after:yesterday").is_err());
+        assert!(Query::parse("before:2020-13-01").is_err());
+    }
+}
@@ -1,14 +1,20 @@
 use crate::bimap::BiMap;
 use crate::conn;
-use crate::conn::{ChannelType, Completer, ConnEvent, Message, TuiEvent};
+use crate::conn::{
+    Backend, Capabilities, ChannelId, ChannelType, Completer, Completions, ConnEvent, Message,
+    MessageId, ServerId, TuiEvent,
+};
+use crate::line_editor::LineEditor;
 use crate::DFAExtension;
 use log::error;
 use regex_automata::DenseDFA;
 use serde::Deserialize;
+use std::collections::HashMap;
 use std::sync::Arc;
 
 use futures::channel::mpsc::UnboundedSender;
-use futures::future::FutureExt;
+use futures::channel::oneshot;
+use futures::future::{BoxFuture, FutureExt};
 use futures::lock::Mutex;
 use futures::sink::SinkExt;
 use futures::stream::StreamExt;
@@ -84,9 +90,35 @@ struct MessageAck {
     ts: ::slack::Timestamp,
 }
 
+/// Call rtm.connect again until we have a new websocket, backing off exponentially
+async fn reconnect(token: &str) -> weebsocket::Client {
+    let mut delay = std::time::Duration::from_secs(1);
+    loop {
+        tokio::time::delay_for(delay).await;
+        let url = slack_url("rtm.connect", token, &());
+        if let Ok(response) = weeqwest::get(&url).await.map_err(|e| error!("{:#?}", e)) {
+            if let Ok(response) = deserialize_or_log!(response, ::slack::http::rtm::ConnectResponse)
+            {
+                match weebsocket::Client::connect(&response.url).await {
+                    Ok(websocket) => return websocket,
+                    Err(e) => error!("{:#?}", e),
+                }
+            }
+        }
+        delay = std::cmp::min(delay * 2, std::time::Duration::from_secs(64));
+    }
+}
+
+// Results from search.messages go in a channel of their own, which Slack knows nothing about
+const SEARCH_CHANNEL: &str = "search";
+const SEARCH_PAGE_SIZE: u32 = 100;
+const MAX_SEARCH_PAGES: u32 = 5;
+
 struct PendingMessage {
     id: u32,
-    channel: String,
+    channel: ::slack::ConversationId,
+    thread: Option<::slack::Timestamp>,
+    broadcast: bool,
 }
 
 impl SlackConn {
@@ -147,14 +179,20 @@ impl SlackConn {
                 .iter()
                 .position(|m| m.id == ack.reply_to)
             {
+                let pending = &self.pending_messages[index];
                 self.tui_sender
                     .send(ConnEvent::Message(Message {
-                        channel: self.pending_messages[index].channel.clone(),
+                        id: ack.ts.into(),
+                        channel: pending.channel.into(),
                         contents: self.convert_mentions(&ack.text),
                         reactions: Vec::new(),
                         sender: self.my_name.clone(),
-                        server: self.team_name.clone(),
+                        server: self.server_id.clone(),
                         timestamp: ack.ts.into(),
+                        thread: pending.thread.map(MessageId::from),
+                        broadcast: pending.broadcast,
+                        reply_count: 0,
+                        edited: false,
                     }))
                     .await
                     .unwrap();
@@ -172,13 +210,13 @@ impl SlackConn {
                 let (channel_id, timestamp) = match item {
                     Reactable::Message { channel, ts } => (channel, ts),
                 };
-                if let Some(channel) = self.channels.get_right(&channel_id) {
+                if self.channels.get_right(&channel_id).is_some() {
                     let _ = self
                         .tui_sender
                         .send(ConnEvent::ReactionAdded {
-                            server: self.team_name.clone(),
-                            channel: channel.clone(),
-                            timestamp: timestamp.into(),
+                            server: self.server_id.clone(),
+                            channel: channel_id.into(),
+                            id: timestamp.into(),
                             reaction,
                         })
                         .await
@@ -190,12 +228,12 @@ impl SlackConn {
                 let (channel_id, timestamp) = match item {
                     Reactable::Message { channel, ts } => (channel, ts),
                 };
-                if let Some(channel) = self.channels.get_right(&channel_id) {
+                if self.channels.get_right(&channel_id).is_some() {
                     self.tui_sender
                         .send(ConnEvent::ReactionRemoved {
-                            server: self.team_name.clone(),
-                            channel: channel.clone(),
-                            timestamp: timestamp.into(),
+                            server: self.server_id.clone(),
+                            channel: channel_id.into(),
+                            id: timestamp.into(),
                             reaction,
                         })
                         .await
@@ -212,21 +250,31 @@ impl SlackConn {
                 files,
                 bot_id,
                 message: edited_message,
+                deleted_ts,
+                thread_ts,
+                subtype,
             }) => {
-                if let Some(edited_message) = edited_message {
+                if let Some(deleted_ts) = deleted_ts {
+                    self.tui_sender
+                        .send(ConnEvent::MessageDeleted {
+                            server: self.server_id.clone(),
+                            channel: channel.into(),
+                            id: deleted_ts.into(),
+                        })
+                        .await
+                        .unwrap();
+                } else if subtype.as_deref() == Some("message_replied") {
+                    // Only tells us about a reply, which arrives as a message of its own
+                } else if let Some(edited_message) = edited_message {
                     // This check is how we verify that this is _actually_ an edit
                     if edited_message.edited.is_some() {
                         self.tui_sender
                             .send(ConnEvent::MessageEdited {
-                                server: self.team_name.clone(),
-                                channel: self
-                                    .channels
-                                    .get_right(&channel)
-                                    .cloned()
-                                    .unwrap_or_else(|| channel.to_string()),
+                                server: self.server_id.clone(),
+                                channel: channel.into(),
+                                id: edited_message.ts.into(),
                                 contents: self
                                     .convert_mentions(&edited_message.text.unwrap_or_default()),
-                                timestamp: edited_message.ts.into(),
                             })
                             .await
                             .unwrap();
@@ -270,16 +318,20 @@ impl SlackConn {
 
                     self.tui_sender
                         .send(ConnEvent::Message(Message {
-                            server: self.team_name.clone(),
-                            channel: self
-                                .channels
-                                .get_right(&channel)
-                                .cloned()
-                                .unwrap_or_else(|| channel.to_string()),
+                            id: ts.into(),
+                            server: self.server_id.clone(),
+                            channel: channel.into(),
                             sender,
                             timestamp: ts.into(),
                             reactions: Vec::new(),
                             contents,
+                            // The message that starts a thread has a thread_ts of its own
+                            thread: thread_ts
+                                .filter(|t| t.microseconds != ts.microseconds)
+                                .map(MessageId::from),
+                            broadcast: subtype.as_deref() == Some("thread_broadcast"),
+                            reply_count: 0,
+                            edited: false,
                         }))
                         .await
                         .unwrap();
@@ -288,12 +340,8 @@ impl SlackConn {
             Ok(rtm::Event::ChannelMarked { channel, ts, .. }) => {
                 self.tui_sender
                     .send(ConnEvent::MarkChannelRead {
-                        server: self.team_name.clone(),
-                        channel: self
-                            .channels
-                            .get_right(&channel.into())
-                            .unwrap_or(&channel.as_str().into())
-                            .clone(),
+                        server: self.server_id.clone(),
+                        channel: ::slack::ConversationId::from(channel).into(),
                         read_at: Some(ts.into()),
                         latest: None,
                     })
@@ -304,18 +352,40 @@ impl SlackConn {
             Ok(::slack::rtm::Event::GroupMarked { channel, ts, .. }) => {
                 self.tui_sender
                     .send(ConnEvent::MarkChannelRead {
-                        server: self.team_name.clone(),
-                        channel: self
-                            .channels
-                            .get_right(&channel.into())
-                            .unwrap_or(&String::from(channel.as_str()))
-                            .clone(),
+                        server: self.server_id.clone(),
+                        channel: ::slack::ConversationId::from(channel).into(),
                         read_at: Some(ts.into()),
                         latest: None,
                     })
                     .await
                     .unwrap();
             }
+            Ok(rtm::Event::UserChange { user }) | Ok(rtm::Event::TeamJoin { user }) => {
+                self.users.insert(user.id, user.name);
+                self.update_completions();
+            }
+            Ok(rtm::Event::ChannelCreated { channel })
+            | Ok(rtm::Event::ChannelRename { channel })
+            | Ok(rtm::Event::GroupRename { channel }) => {
+                self.channels.insert(channel.id, channel.name);
+                self.update_completions();
+            }
+            Ok(rtm::Event::EmojiChanged {
+                subtype,
+                name,
+                names,
+            }) => {
+                match (subtype.as_str(), name) {
+                    ("add", Some(name)) => {
+                        if let Err(i) = self.emoji.binary_search(&name) {
+                            self.emoji.insert(i, name);
+                        }
+                    }
+                    ("remove", _) => self.emoji.retain(|e| !names.contains(e)),
+                    _ => {}
+                }
+                self.update_completions();
+            }
             _ => {}
         }
     }
@@ -323,6 +393,7 @@ impl SlackConn {
 
 pub struct SlackConn {
     token: String,
+    server_id: ServerId,
     team_name: String,
     users: BiMap<::slack::UserId, String>,
     channels: BiMap<::slack::ConversationId, String>,
@@ -332,23 +403,119 @@ pub struct SlackConn {
     input_sender: UnboundedSender<weebsocket::Message>,
     tui_sender: UnboundedSender<ConnEvent>,
     pending_messages: Vec<PendingMessage>,
+    completer: Completer,
 }
 
-pub struct SlackCompleter {
+#[derive(Deserialize)]
+pub struct SlackConfig {
+    token: String,
+}
+
+pub struct SlackBackend {
     inner: Arc<Mutex<SlackConn>>,
+    shutdown: Option<oneshot::Sender<()>>,
 }
 
-impl Completer for SlackCompleter {
-    fn autocomplete(&self, _word: &str) -> Vec<String> {
-        error!("Autocompleter was stubbed out!");
-        Vec::new()
-        //self.inner.unwrap().autocomplete(word)
+impl Backend for SlackBackend {
+    type Config = SlackConfig;
+
+    fn connect(
+        config: SlackConfig,
+        sender: UnboundedSender<ConnEvent>,
+        events: UnboundedSender<TuiEvent>,
+    ) -> BoxFuture<'static, Result<Self, ()>> {
+        SlackConn::create_on(config.token, sender, events).boxed()
+    }
+
+    fn handle_event(&mut self, event: TuiEvent) -> BoxFuture<'_, ()> {
+        async move {
+            // The websocket needs the connection too, so anything that waits on Slack only locks
+            // it while it reads or changes what we know
+            let inner = &*self.inner;
+            match event {
+                // Anything typed in the Search channel is a new query
+                TuiEvent::SendMessage {
+                    channel, contents, ..
+                } if channel.as_str() == SEARCH_CHANNEL => {
+                    SlackConn::search(inner, &contents).await
+                }
+                TuiEvent::SendMessage {
+                    channel,
+                    contents,
+                    thread,
+                    broadcast,
+                    ..
+                } => {
+                    inner
+                        .lock()
+                        .await
+                        .send_channel_message(&channel, &contents, thread.as_ref(), broadcast)
+                        .await
+                }
+                TuiEvent::SendTyping { channel, .. } => {
+                    inner.lock().await.send_typing(&channel).await
+                }
+                TuiEvent::MarkRead { channel, .. } => inner.lock().await.mark_read(&channel),
+                TuiEvent::Command {
+                    channel, command, ..
+                } => SlackConn::handle_cmd(inner, &channel, &command).await,
+                TuiEvent::AddReaction {
+                    channel,
+                    reaction,
+                    id,
+                    ..
+                } => inner.lock().await.add_reaction(&channel, &reaction, &id),
+                TuiEvent::GetHistory { channel, after } => {
+                    SlackConn::get_history(inner, &channel, after.as_ref()).await
+                }
+                TuiEvent::GetThread { channel, thread } => {
+                    SlackConn::get_thread(inner, &channel, &thread).await
+                }
+                TuiEvent::EditMessage {
+                    channel,
+                    id,
+                    contents,
+                    ..
+                } => inner.lock().await.edit_message(&channel, &id, &contents),
+                TuiEvent::DeleteMessage { channel, id, .. } => {
+                    inner.lock().await.delete_message(&channel, &id)
+                }
+                TuiEvent::GetPermalink { channel, id, .. } => {
+                    SlackConn::get_permalink(inner, &channel, &id).await
+                }
+            }
+        }
+        .boxed()
+    }
+
+    fn capabilities() -> Capabilities {
+        Capabilities {
+            reactions: true,
+            typing: true,
+            history: true,
+            commands: true,
+            threads: true,
+            edits: true,
+            deletes: true,
+            search: true,
+            permalinks: true,
+        }
+    }
+
+    fn shutdown(&mut self) -> BoxFuture<'_, ()> {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        futures::future::ready(()).boxed()
     }
 }
 
 impl SlackConn {
-    pub async fn create_on(token: &str, mut sender: UnboundedSender<ConnEvent>) -> Result<(), ()> {
-        let token = token.to_string();
+    async fn create_on(
+        token: String,
+        mut sender: UnboundedSender<ConnEvent>,
+        tui_send: UnboundedSender<TuiEvent>,
+    ) -> Result<SlackBackend, ()> {
         // Launch all of the requests
         use slack::http::{conversations, emoji, rtm, users};
 
@@ -418,17 +585,38 @@ impl SlackConn {
             channels.insert(id, name.clone());
             let now = conn::DateTime::now();
             tui_channels.push(crate::tui::Channel {
+                id: id.into(),
                 messages: Vec::new(),
                 name,
                 read_at: now,
                 latest: now,
                 has_history: false,
+                synced_through: None,
                 message_scroll_offset: 0,
-                message_buffer: String::new(),
+                input: LineEditor::default(),
                 channel_type,
+                threads: HashMap::new(),
+                open_thread: None,
             });
         }
 
+        let now = conn::DateTime::now();
+        tui_channels.push(crate::tui::Channel {
+            id: ChannelId::from(SEARCH_CHANNEL),
+            messages: Vec::new(),
+            name: "Search".into(),
+            read_at: now,
+            latest: now,
+            // There's nothing to fetch until a search is run
+            has_history: true,
+            synced_through: None,
+            message_scroll_offset: 0,
+            input: LineEditor::default(),
+            channel_type: ChannelType::Search,
+            threads: HashMap::new(),
+            open_thread: None,
+        });
+
         let connect_response = connect_recv
             .await
             .unwrap()
@@ -441,6 +629,7 @@ impl SlackConn {
 
         let my_name = connect_response.slf.name;
         let team_name = connect_response.team.name;
+        let server_id = ServerId::from(connect_response.team.id);
 
         // Give the emoji handle as long as possible to complete
         let emoji_response = emoji_recv.await.unwrap().map_err(|e| error!("{:#?}", e))?;
@@ -457,10 +646,11 @@ impl SlackConn {
 
         let (input_sender, mut input_reciever) = futures::channel::mpsc::unbounded();
 
-        let connection = Arc::new(Mutex::new(SlackConn {
+        let connection = SlackConn {
             token: token.clone(),
             users,
             channels: channels.clone(),
+            server_id: server_id.clone(),
             team_name: team_name.clone(),
             emoji,
             last_typing_message: chrono::Utc::now(),
@@ -468,243 +658,340 @@ impl SlackConn {
             input_sender,
             tui_sender: sender.clone(),
             pending_messages: Vec::new(),
-        }));
-
-        let (tui_send, mut tui_recv) = futures::channel::mpsc::unbounded();
+            completer: Completer::default(),
+        };
+        connection.update_completions();
+        let completer = connection.completer.clone();
+        let connection = Arc::new(Mutex::new(connection));
 
         sender
-            .send(ConnEvent::ServerConnected(crate::tui::Server {
-                current_channel: 0,
-                channels: tui_channels,
-                completer: Some(Box::new(SlackCompleter {
-                    inner: connection.clone(),
-                })),
-                name: team_name.clone(),
-                channel_scroll_offset: 0,
-                sender: tui_send,
-            }))
+            .send(ConnEvent::ServerConnected(crate::tui::Server::new(
+                server_id.clone(),
+                team_name,
+                Some(my_name.clone()),
+                tui_channels,
+                Some(completer),
+                tui_send,
+                SlackBackend::capabilities(),
+            )))
             .await
             .unwrap();
 
-        let conn = connection.clone();
-        // Create a background thread that will handle events from the TUI
-        tokio::spawn(async move {
-            while let Some(event) = tui_recv.next().await {
-                match event {
-                    TuiEvent::SendMessage {
-                        channel, contents, ..
-                    } => {
-                        conn.lock()
-                            .await
-                            .send_channel_message(&channel, &contents)
-                            .await
-                    }
-                    TuiEvent::SendTyping { channel, .. } => {
-                        conn.lock().await.send_typing(&channel).await
-                    }
-                    TuiEvent::MarkRead { channel, .. } => conn.lock().await.mark_read(&channel),
-                    TuiEvent::Command {
-                        channel, command, ..
-                    } => conn.lock().await.handle_cmd(&channel, &command),
-                    TuiEvent::AddReaction {
-                        channel,
-                        reaction,
-                        timestamp,
-                        ..
-                    } => conn
-                        .lock()
-                        .await
-                        .add_reaction(&channel, &reaction, timestamp),
-                    TuiEvent::GetHistory { channel } => {
-                        conn.lock().await.get_history(&channel).await
-                    }
-                }
-            }
-        });
-
         let thread_conn = connection.clone();
+        let (shutdown, mut shutdown_recv) = oneshot::channel();
+        let mut thread_sender = sender.clone();
+        let thread_server_id = server_id.clone();
+        let thread_token = token.clone();
 
         tokio::spawn(async move {
             loop {
                 let mut input_fut = input_reciever.next();
+                let mut disconnected = false;
                 futures::select! {
                     ws_msg = websocket.recv().fuse() => {
-                        match ws_msg.unwrap() {
-                            weebsocket::Message::Close(_) => {
+                        match ws_msg {
+                            Ok(weebsocket::Message::Close(_)) => {
                                 error!("websocket closed");
-                                // TODO: Restart
+                                disconnected = true;
+                            }
+                            Ok(weebsocket::Message::Ping(data)) => {
+                                if let Err(e) = websocket.send(&weebsocket::Message::Pong(data)).await {
+                                    error!("{:#?}", e);
+                                    disconnected = true;
+                                }
                             }
-                            weebsocket::Message::Ping(data) => websocket.send(&weebsocket::Message::Pong(data)).await.unwrap(),
-                            weebsocket::Message::Text(text) => {
+                            Ok(weebsocket::Message::Text(text)) => {
                                 thread_conn.lock().await.process_slack_message(&text).await;
                             }
-                            weebsocket::Message::Pong(_) | weebsocket::Message::Binary(_) => error!("unrecognized message"),
+                            Ok(weebsocket::Message::Pong(_)) | Ok(weebsocket::Message::Binary(_)) => error!("unrecognized message"),
+                            Err(e) => {
+                                error!("{:#?}", e);
+                                disconnected = true;
+                            }
                         }
                     }
                     input = input_fut => {
                         if let Some(input) = input {
-                            websocket.send(&input).await.unwrap();
+                            if let Err(e) = websocket.send(&input).await {
+                                error!("{:#?}", e);
+                                disconnected = true;
+                            }
                         }
                     }
+                    _ = shutdown_recv => break,
+                }
+
+                if disconnected {
+                    // Anything we were waiting on an ack for is gone with the old websocket
+                    thread_conn.lock().await.pending_messages.clear();
+                    thread_sender
+                        .send(ConnEvent::ServerDisconnected {
+                            server: thread_server_id.clone(),
+                        })
+                        .await
+                        .unwrap();
+                    futures::select! {
+                        new_websocket = reconnect(&thread_token).fuse() => websocket = new_websocket,
+                        _ = shutdown_recv => break,
+                    }
+                    thread_sender
+                        .send(ConnEvent::ServerReconnected {
+                            server: thread_server_id.clone(),
+                        })
+                        .await
+                        .unwrap();
                 }
                 tokio::task::yield_now().await;
             }
         });
 
-        let mut pending_requests = Vec::new();
+        // Fetch the read state of every channel in the background so that we can start handling
+        // events from the TUI right away
+        tokio::spawn(async move {
+            let mut pending_requests = Vec::new();
 
-        // Launch all the history requests
-        for (conversation_id, conversation_name) in channels.clone() {
-            use slack::http::{channels, groups};
+            // Launch all the history requests
+            for (conversation_id, conversation_name) in channels.clone() {
+                use slack::http::{channels, groups};
 
-            let url = match conversation_id {
-                ::slack::ConversationId::Channel(channel_id) => {
-                    let req = channels::InfoRequest::new(channel_id);
-                    slack_url("channels.info", &token, req)
-                }
-                ::slack::ConversationId::Group(group_id) => {
-                    let req = groups::InfoRequest::new(group_id);
-                    slack_url("groups.info", &token, req)
-                }
-                ::slack::ConversationId::DirectMessage(_) => {
-                    let req = conversations::InfoRequest::new(conversation_id);
-                    slack_url("conversations.info", &token, req)
-                }
-            };
+                let url = match conversation_id {
+                    ::slack::ConversationId::Channel(channel_id) => {
+                        let req = channels::InfoRequest::new(channel_id);
+                        slack_url("channels.info", &token, req)
+                    }
+                    ::slack::ConversationId::Group(group_id) => {
+                        let req = groups::InfoRequest::new(group_id);
+                        slack_url("groups.info", &token, req)
+                    }
+                    ::slack::ConversationId::DirectMessage(_) => {
+                        let req = conversations::InfoRequest::new(conversation_id);
+                        slack_url("conversations.info", &token, req)
+                    }
+                };
 
-            let name = conversation_name.clone();
-            let info_response = tokio::spawn(async move {
-                let mut res = weeqwest::get(&url).await;
-                // TODO: This is not a serious retry loop, should eventually be exponential backoff
-                while res.is_err() {
-                    error!("retrying info for {:?}", name);
-                    tokio::time::delay_for(std::time::Duration::from_millis(500)).await;
-                    res = weeqwest::get(&url).await;
-                }
-                res
-            });
+                let name = conversation_name.clone();
+                let info_response = tokio::spawn(async move {
+                    let mut res = weeqwest::get(&url).await;
+                    // TODO: This is not a serious retry loop, should eventually be exponential backoff
+                    while res.is_err() {
+                        error!("retrying info for {:?}", name);
+                        tokio::time::delay_for(std::time::Duration::from_millis(500)).await;
+                        res = weeqwest::get(&url).await;
+                    }
+                    res
+                });
 
-            pending_requests.push((info_response, conversation_id, conversation_name));
-        }
+                pending_requests.push((info_response, conversation_id));
+            }
 
-        // Handle all the launched requests
-        for (info_response, conversation_id, conversation_name) in pending_requests {
-            use slack::http::{channels, groups};
-            use slack::ConversationId::*;
+            // Handle all the launched requests
+            for (info_response, conversation_id) in pending_requests {
+                use slack::http::{channels, groups};
+                use slack::ConversationId::*;
 
-            let info_response = info_response.await.unwrap().unwrap();
+                let info_response = info_response.await.unwrap().unwrap();
 
-            let (read_at, latest) = match conversation_id {
-                Channel(_) => {
-                    let info = deserialize_or_log!(info_response, channels::InfoResponse)?;
-                    (
-                        info.channel.last_read.unwrap().into(),
-                        info.channel.latest.ts.into(),
-                    )
-                }
-                Group(_) => {
-                    let info = deserialize_or_log!(info_response, groups::InfoResponse)?;
-                    (
-                        info.group.last_read.unwrap().into(),
-                        info.group.latest.ts.into(),
-                    )
-                }
-                DirectMessage(_) => {
-                    let info = deserialize_or_log!(info_response, conversations::InfoResponse)?;
-                    match info.channel {
-                        slack::http::conversations::ConversationInfo::DirectMessage {
-                            last_read,
-                            latest,
-                            ..
-                        } => {
-                            let last_read = last_read.into();
-                            let latest = latest.map(|l| l.ts.into()).unwrap_or(last_read);
-                            (last_read, latest)
-                        }
-                        _ => {
-                            error!(
-                                "Tried to get info about a DM but got info about something else"
-                            );
-                            continue;
+                let (read_at, latest) = match conversation_id {
+                    Channel(_) => {
+                        let info = deserialize_or_log!(info_response, channels::InfoResponse)?;
+                        (
+                            info.channel.last_read.unwrap().into(),
+                            info.channel.latest.ts.into(),
+                        )
+                    }
+                    Group(_) => {
+                        let info = deserialize_or_log!(info_response, groups::InfoResponse)?;
+                        (
+                            info.group.last_read.unwrap().into(),
+                            info.group.latest.ts.into(),
+                        )
+                    }
+                    DirectMessage(_) => {
+                        let info = deserialize_or_log!(info_response, conversations::InfoResponse)?;
+                        match info.channel {
+                            slack::http::conversations::ConversationInfo::DirectMessage {
+                                last_read,
+                                latest,
+                                ..
+                            } => {
+                                let last_read = last_read.into();
+                                let latest = latest.map(|l| l.ts.into()).unwrap_or(last_read);
+                                (last_read, latest)
+                            }
+                            _ => {
+                                error!(
+                                    "Tried to get info about a DM but got info about something else"
+                                );
+                                continue;
+                            }
                         }
                     }
-                }
-            };
+                };
 
-            sender
-                .send(ConnEvent::MarkChannelRead {
-                    server: team_name.clone(),
-                    channel: conversation_name.clone(),
-                    read_at: Some(read_at),
-                    latest: Some(latest),
-                })
-                .await
-                .unwrap();
-        }
+                sender
+                    .send(ConnEvent::MarkChannelRead {
+                        server: server_id.clone(),
+                        channel: conversation_id.into(),
+                        read_at: Some(read_at),
+                        latest: Some(latest),
+                    })
+                    .await
+                    .unwrap();
+            }
 
-        Ok(())
+            Ok::<(), ()>(())
+        });
+
+        Ok(SlackBackend {
+            inner: connection,
+            shutdown: Some(shutdown),
+        })
     }
 
-    fn autocomplete(&self, word: &str) -> Vec<String> {
-        match word.chars().next() {
-            Some('@') => self
-                .users
-                .iter()
-                .map(|(_, name)| name)
-                .chain(&[
-                    String::from("channel"),
-                    String::from("here"),
-                    String::from("everyone"),
-                ])
-                .filter(|name| name.starts_with(&word[1..]))
-                .map(|s| String::from("@") + s)
-                .collect(),
-            Some('#') => self
-                .channels
-                .iter()
-                .map(|(_id, name)| name)
-                .filter(|name| name.starts_with(&word[1..]))
-                .map(|s| String::from("#") + s)
-                .collect(),
-            Some(':') => self
-                .emoji
+    /// Publish everything we know of for the TUI to complete from
+    fn update_completions(&self) {
+        let mut users: Vec<String> = self
+            .users
+            .iter()
+            .map(|(_, name)| name.clone())
+            .chain(vec!["channel".into(), "here".into(), "everyone".into()])
+            .collect();
+        users.sort();
+        let mut channels: Vec<String> =
+            self.channels.iter().map(|(_, name)| name.clone()).collect();
+        channels.sort();
+        self.completer.update(Completions {
+            users,
+            channels,
+            emoji: self.emoji.clone(),
+        });
+    }
+
+    /// Find the Slack conversation behind one of our channel handles
+    fn conversation_id(&self, channel: &ChannelId) -> Option<::slack::ConversationId> {
+        let id = channel.as_str().parse().ok()?;
+        self.channels.get_right(&id).map(|_| id)
+    }
+
+    async fn get_history(inner: &Mutex<SlackConn>, channel: &ChannelId, after: Option<&MessageId>) {
+        if channel.as_str() == SEARCH_CHANNEL {
+            return;
+        }
+        let (token, conversation_id) = {
+            let conn = inner.lock().await;
+            match conn.conversation_id(channel) {
+                Some(id) => (conn.token.clone(), id),
+                None => {
+                    error!("Unknown channel: {}", channel);
+                    return;
+                }
+            }
+        };
+        // Slack identifies messages by their timestamp
+        let oldest = after.and_then(|id| id.as_str().parse().ok());
+        let mut history_messages: Vec<HistoryMessage> = Vec::new();
+        loop {
+            let mut request = slack::http::conversations::HistoryRequest::new(conversation_id);
+            request.limit = Some(1000);
+            request.oldest = oldest;
+            // Pages come newest first, so each one ends where the last one stopped
+            request.latest = history_messages
                 .iter()
-                .filter(|name| name.starts_with(&word[1..]))
-                .map(|s| format!(":{}:", s))
-                .collect(),
-            Some('+') => {
-                if word.chars().count() > 2 {
-                    self.emoji
-                        .iter()
-                        .filter(|name| name.starts_with(&word[2..]))
-                        .map(|s| format!("+:{}:", s))
-                        .collect()
-                } else {
-                    Vec::new()
+                .map(|m| m.ts)
+                .min_by_key(|ts| ts.microseconds);
+            let url = slack_url("conversations.history", &token, request);
+
+            let history_response = match weeqwest::get(&url).await {
+                Ok(r) => r,
+                Err(e) => {
+                    error!("{:#?}", e);
+                    return;
                 }
+            };
+            let page = match deserialize_or_log!(history_response, HistoryResponse) {
+                Ok(page) => page,
+                Err(()) => return,
+            };
+            let has_more = page.has_more && !page.messages.is_empty();
+            history_messages.extend(page.messages);
+
+            // Without somewhere to start from, the latest page is all we want
+            if oldest.is_none() || !has_more {
+                break;
             }
-            _ => Vec::new(),
         }
+
+        let conn = inner.lock().await;
+        let messages = conn.history_to_messages(channel, history_messages);
+
+        let mut tui_send = conn.tui_sender.clone();
+        tui_send
+            .send(ConnEvent::HistoryLoaded {
+                messages,
+                server: conn.server_id.clone(),
+                channel: channel.clone(),
+            })
+            .await
+            .unwrap();
     }
 
-    async fn get_history(&self, channel: &str) {
-        let mut request = slack::http::conversations::HistoryRequest::new(
-            *self.channels.get_left(channel).unwrap(),
-        );
+    async fn get_thread(inner: &Mutex<SlackConn>, channel: &ChannelId, thread: &MessageId) {
+        let (token, conversation_id) = {
+            let conn = inner.lock().await;
+            match conn.conversation_id(channel) {
+                Some(id) => (conn.token.clone(), id),
+                None => {
+                    error!("Unknown channel: {}", channel);
+                    return;
+                }
+            }
+        };
+        // Slack identifies messages by their timestamp
+        let thread_ts = match thread.as_str().parse() {
+            Ok(ts) => ts,
+            Err(e) => {
+                error!("Invalid Slack message id {}: {}", thread, e);
+                return;
+            }
+        };
+        let mut request =
+            slack::http::conversations::RepliesRequest::new(conversation_id, thread_ts);
         request.limit = Some(1000);
-        let url = format!(
-            "https://slack.com/api/conversations.history?token={}&{}",
-            self.token,
-            ::serde_urlencoded::to_string(request).unwrap_or_default()
-        );
+        let url = slack_url("conversations.replies", &token, request);
 
-        let history_response = weeqwest::get(&url).await.unwrap();
+        let replies_response = match weeqwest::get(&url).await {
+            Ok(r) => r,
+            Err(e) => {
+                error!("{:#?}", e);
+                return;
+            }
+        };
 
-        let history_messages = deserialize_or_log!(history_response, HistoryResponse)
+        let replies = deserialize_or_log!(replies_response, HistoryResponse)
             .map(|h| h.messages)
             .unwrap_or_default();
 
-        let messages = history_messages
+        let conn = inner.lock().await;
+        let messages = conn.history_to_messages(channel, replies);
+
+        let mut tui_send = conn.tui_sender.clone();
+        tui_send
+            .send(ConnEvent::ThreadLoaded {
+                messages,
+                server: conn.server_id.clone(),
+                channel: channel.clone(),
+                thread: thread.clone(),
+            })
+            .await
+            .unwrap();
+    }
+
+    fn history_to_messages(
+        &self,
+        channel: &ChannelId,
+        messages: Vec<HistoryMessage>,
+    ) -> Vec<Message> {
+        messages
             .into_iter()
             .map(|msg| {
                 let name = msg
@@ -714,8 +1001,9 @@ impl SlackConn {
                     .or_else(|| msg.bot_id.map(|b| b.to_string()))
                     .unwrap_or_else(|| "UNKNOWNUSER".into());
                 Message {
-                    server: self.team_name.clone(),
-                    channel: channel.to_string(),
+                    id: msg.ts.into(),
+                    server: self.server_id.clone(),
+                    channel: channel.clone(),
                     sender: name.clone(),
                     timestamp: msg.ts.into(),
                     reactions: msg
@@ -724,30 +1012,30 @@ impl SlackConn {
                         .map(|r| (r.name.clone(), r.count as usize))
                         .collect(),
                     contents: msg.to_omni(self),
+                    thread: msg
+                        .thread_ts
+                        .filter(|t| t.microseconds != msg.ts.microseconds)
+                        .map(MessageId::from),
+                    broadcast: msg.subtype.as_deref() == Some("thread_broadcast"),
+                    reply_count: msg.reply_count as usize,
+                    edited: msg.edited.is_some(),
                 }
             })
-            .collect();
-
-        let mut tui_send = self.tui_sender.clone();
-        tui_send
-            .send(ConnEvent::HistoryLoaded {
-                messages,
-                server: self.team_name.clone(),
-                channel: channel.to_string(),
-            })
-            .await
-            .unwrap();
+            .collect()
     }
 
-    async fn send_typing(&mut self, channel: &str) {
+    async fn send_typing(&mut self, channel: &ChannelId) {
+        if channel.as_str() == SEARCH_CHANNEL {
+            return;
+        }
         let now = chrono::Utc::now();
         if (now - self.last_typing_message) < chrono::Duration::seconds(3) {
             return;
         } else {
             self.last_typing_message = chrono::Utc::now();
         }
-        let channel_id = match self.channels.get_left(channel) {
-            Some(id) => *id,
+        let channel_id = match self.conversation_id(channel) {
+            Some(id) => id,
             None => {
                 error!("Unknown channel: {}", channel);
                 return;
@@ -759,8 +1047,10 @@ impl SlackConn {
             id += 1;
         }
         self.pending_messages.push(PendingMessage {
-            channel: String::from(channel),
+            channel: channel_id,
             id,
+            thread: None,
+            broadcast: false,
         });
 
         let message = ::serde_json::json!({
@@ -779,10 +1069,16 @@ impl SlackConn {
         }
     }
 
-    async fn send_channel_message(&mut self, channel: &str, contents: &str) {
+    async fn send_channel_message(
+        &mut self,
+        channel: &ChannelId,
+        contents: &str,
+        thread: Option<&MessageId>,
+        broadcast: bool,
+    ) {
         let contents = self.to_slack(contents.to_string());
-        let channel_id = match self.channels.get_left(channel) {
-            Some(id) => *id,
+        let channel_id = match self.conversation_id(channel) {
+            Some(id) => id,
             None => {
                 error!("Unknown channel: {}", channel);
                 return;
@@ -793,17 +1089,33 @@ impl SlackConn {
         while self.pending_messages.iter().any(|m| m.id == id) {
             id += 1;
         }
+        // Slack identifies messages by their timestamp
+        let thread_ts = match thread.map(|t| t.as_str().parse::<::slack::Timestamp>()) {
+            None => None,
+            Some(Ok(ts)) => Some(ts),
+            Some(Err(e)) => {
+                error!("Invalid Slack message id {:?}: {}", thread, e);
+                return;
+            }
+        };
+
         self.pending_messages.push(PendingMessage {
-            channel: String::from(channel),
+            channel: channel_id,
             id,
+            thread: thread_ts,
+            broadcast,
         });
 
-        let message = ::serde_json::json!({
+        let mut message = ::serde_json::json!({
             "id": id,
             "type": "message",
             "channel": channel_id,
             "text": contents,
         });
+        if let Some(thread_ts) = thread_ts {
+            message["thread_ts"] = ::serde_json::json!(thread_ts);
+            message["reply_broadcast"] = ::serde_json::json!(broadcast);
+        }
 
         match serde_json::to_string(&message) {
             Err(e) => error!("{:#?}", e),
@@ -815,11 +1127,15 @@ impl SlackConn {
         }
     }
 
-    fn mark_read(&self, channel: &str) {
+    fn mark_read(&self, channel: &ChannelId) {
         use slack::http::{channels, groups, im};
 
-        let channel_or_group_id = match self.channels.get_left(channel) {
-            Some(s) => *s,
+        if channel.as_str() == SEARCH_CHANNEL {
+            return;
+        }
+
+        let channel_or_group_id = match self.conversation_id(channel) {
+            Some(s) => s,
             None => {
                 error!(
                     "Tried to mark unread for channel {} in server {} but channel does not exist",
@@ -863,29 +1179,163 @@ impl SlackConn {
         });
     }
 
-    fn add_reaction(&self, channel: &str, reaction: &str, timestamp: conn::DateTime) {
+    fn edit_message(&self, channel: &ChannelId, id: &MessageId, contents: &str) {
+        let conversation_id = match self.conversation_id(channel) {
+            Some(id) => id,
+            None => {
+                error!("Unknown channel: {}", channel);
+                return;
+            }
+        };
+        // Slack identifies messages by their timestamp
+        let timestamp = match id.as_str().parse() {
+            Ok(ts) => ts,
+            Err(e) => {
+                error!("Invalid Slack message id {}: {}", id, e);
+                return;
+            }
+        };
+
+        let contents = self.to_slack(contents.to_string());
+        let mut req =
+            ::slack::http::chat::UpdateRequest::new(conversation_id, timestamp, &contents);
+        req.as_user = Some(true);
+        let url = slack_url("chat.update", &self.token, req);
+
+        // The edit will show up when Slack sends a message_changed event
+        tokio::spawn(async move {
+            if let Ok(r) = weeqwest::post(&url).await.map_err(|e| error!("{:#?}", e)) {
+                use slack::http::Error;
+                if let Ok(Error { ok: false, error }) = ::serde_json::from_slice::<Error>(r.body())
+                {
+                    error!("Couldn't edit message: {}", error.unwrap_or_default());
+                }
+            }
+        });
+    }
+
+    fn delete_message(&self, channel: &ChannelId, id: &MessageId) {
+        let conversation_id = match self.conversation_id(channel) {
+            Some(id) => id,
+            None => {
+                error!("Unknown channel: {}", channel);
+                return;
+            }
+        };
+        // Slack identifies messages by their timestamp
+        let timestamp = match id.as_str().parse() {
+            Ok(ts) => ts,
+            Err(e) => {
+                error!("Invalid Slack message id {}: {}", id, e);
+                return;
+            }
+        };
+
+        let mut req = ::slack::http::chat::DeleteRequest::new(conversation_id, timestamp);
+        req.as_user = Some(true);
+        let url = slack_url("chat.delete", &self.token, req);
+
+        // The message will be removed when Slack sends a message_deleted event
+        tokio::spawn(async move {
+            if let Ok(r) = weeqwest::post(&url).await.map_err(|e| error!("{:#?}", e)) {
+                use slack::http::Error;
+                if let Ok(Error { ok: false, error }) = ::serde_json::from_slice::<Error>(r.body())
+                {
+                    error!("Couldn't delete message: {}", error.unwrap_or_default());
+                }
+            }
+        });
+    }
+
+    async fn get_permalink(inner: &Mutex<SlackConn>, channel: &ChannelId, id: &MessageId) {
+        let (token, server_id, mut tui_send, conversation_id) = {
+            let conn = inner.lock().await;
+            (
+                conn.token.clone(),
+                conn.server_id.clone(),
+                conn.tui_sender.clone(),
+                conn.conversation_id(channel),
+            )
+        };
+        // Search results are named after the conversation they were found in, then the timestamp
+        let (conversation_id, timestamp) = if channel.as_str() == SEARCH_CHANNEL {
+            match id.as_str().find('-') {
+                Some(i) => (id.as_str()[..i].parse().ok(), &id.as_str()[i + 1..]),
+                None => (None, id.as_str()),
+            }
+        } else {
+            (conversation_id, id.as_str())
+        };
+        let conversation_id = match conversation_id {
+            Some(id) => id,
+            None => {
+                error!("Unknown channel: {}", channel);
+                return;
+            }
+        };
+        // Slack identifies messages by their timestamp
+        let timestamp = match timestamp.parse() {
+            Ok(ts) => ts,
+            Err(e) => {
+                error!("Invalid Slack message id {}: {}", id, e);
+                return;
+            }
+        };
+
+        let request = ::slack::http::chat::GetPermalinkRequest::new(conversation_id, timestamp);
+        let url = slack_url("chat.getPermalink", &token, request);
+        let response = match weeqwest::get(&url).await {
+            Ok(r) => r,
+            Err(e) => {
+                error!("{:#?}", e);
+                return;
+            }
+        };
+
+        if let Ok(::slack::http::chat::GetPermalinkResponse {
+            permalink: Some(url),
+            ..
+        }) = deserialize_or_log!(response, ::slack::http::chat::GetPermalinkResponse)
+        {
+            tui_send
+                .send(ConnEvent::Permalink {
+                    server: server_id,
+                    url,
+                })
+                .await
+                .unwrap();
+        }
+    }
+
+    fn add_reaction(&self, channel: &ChannelId, reaction: &str, id: &MessageId) {
         let token = self.token.clone();
         let name = String::from(reaction);
 
-        let channel = match self.channels.get_left(channel) {
-            Some(c) => *c,
+        let channel = match self.conversation_id(channel) {
+            Some(c) => c,
             None => {
                 error!(
-                    "Internal error, no known Slack ConversationId for channel name {}",
+                    "Internal error, no known Slack ConversationId for channel {}",
                     channel
                 );
                 return;
             }
         };
 
+        // Slack identifies messages by their timestamp
+        let timestamp = match id.as_str().parse() {
+            Ok(ts) => ts,
+            Err(e) => {
+                error!("Invalid Slack message id {}: {}", id, e);
+                return;
+            }
+        };
+
         // TODO: Pretty sure I can just call slack_url here
         use slack::http::reactions::Reactable;
         let req = ::slack::http::reactions::AddRequest::new(
             &name,
-            Reactable::Message {
-                channel,
-                timestamp: timestamp.into(),
-            },
+            Reactable::Message { channel, timestamp },
         );
 
         let url = format!(
@@ -900,14 +1350,113 @@ impl SlackConn {
         });
     }
 
-    fn handle_cmd(&self, channel: &str, cmd: &str) {
+    /// Run a search.messages query, replacing the contents of the Search channel with the results
+    async fn search(inner: &Mutex<SlackConn>, query: &str) {
+        use slack::http::search::{MessagesRequest, MessagesResponse, SortBy, SortDirection};
+
+        let (token, team_name, mut tui_send) = {
+            let conn = inner.lock().await;
+            (
+                conn.token.clone(),
+                conn.team_name.clone(),
+                conn.tui_sender.clone(),
+            )
+        };
+        let mut matches = Vec::new();
+        let mut page = 1;
+        loop {
+            let mut request = MessagesRequest::new(query);
+            request.sort = Some(SortBy::Timestamp);
+            request.sort_dir = Some(SortDirection::Descending);
+            request.count = Some(SEARCH_PAGE_SIZE);
+            request.page = Some(page);
+            let url = slack_url("search.messages", &token, request);
+
+            // Show whatever we found before anything went wrong
+            let response = match weeqwest::get(&url).await {
+                Ok(r) => r,
+                Err(e) => {
+                    error!("{:#?}", e);
+                    break;
+                }
+            };
+            let response = match deserialize_or_log!(response, MessagesResponse) {
+                Ok(r) => r,
+                Err(()) => break,
+            };
+
+            if response.messages.total == Some(0) {
+                tui_send
+                    .send(ConnEvent::Error(format!(
+                        "No results for {} in {}",
+                        query, team_name
+                    )))
+                    .await
+                    .unwrap();
+                break;
+            }
+
+            let pages = response.messages.paging.pages.unwrap_or(1);
+            matches.extend(response.messages.matches);
+            if page >= std::cmp::min(pages, MAX_SEARCH_PAGES) {
+                break;
+            }
+            page += 1;
+        }
+
+        let conn = inner.lock().await;
+        let results = matches
+            .iter()
+            .map(|m| {
+                // Our names for direct messages are nicer than what search gives us
+                let channel_name = conn
+                    .channels
+                    .get_right(&m.channel.id)
+                    .unwrap_or(&m.channel.name);
+                Message {
+                    // The same timestamp can turn up in different channels
+                    id: MessageId::from(format!("{}-{}", m.channel.id, m.ts)),
+                    server: conn.server_id.clone(),
+                    channel: ChannelId::from(SEARCH_CHANNEL),
+                    sender: m
+                        .user
+                        .and_then(|user| conn.users.get_right(&user).cloned())
+                        .or_else(|| m.username.clone())
+                        .unwrap_or_else(|| "UNKNOWNUSER".into()),
+                    contents: format!("#{}: {}", channel_name, conn.convert_mentions(&m.text)),
+                    timestamp: m.ts.into(),
+                    reactions: Vec::new(),
+                    thread: None,
+                    broadcast: false,
+                    reply_count: 0,
+                    edited: false,
+                }
+            })
+            .collect();
+
+        tui_send
+            .send(ConnEvent::SearchResults {
+                server: conn.server_id.clone(),
+                channel: ChannelId::from(SEARCH_CHANNEL),
+                messages: results,
+            })
+            .await
+            .unwrap();
+    }
+
+    async fn handle_cmd(inner: &Mutex<SlackConn>, channel: &ChannelId, cmd: &str) {
         let args: Vec<_> = cmd.split_whitespace().collect();
         match args.as_slice() {
+            ["search", _, ..] => {
+                let query = cmd.trim_start()["search".len()..].trim();
+                SlackConn::search(inner, query).await;
+            }
             ["upload", path] => {
-                let url = match self.channels.get_left(channel).map(|id| {
+                let conn = inner.lock().await;
+                let url = match conn.conversation_id(channel).map(|id| {
                     format!(
                         "https://slack.com/api/files.upload?token={}&channels={}",
-                        self.token, id,
+                        conn.token, id,
                     )
                 }) {
                     Some(v) => v,
@@ -916,6 +1465,7 @@ impl SlackConn {
                         return;
                     }
                 };
+                drop(conn);
 
                 let content = match std::fs::read(path) {
                     Ok(v) => v,
@@ -967,6 +1517,11 @@ struct HistoryMessage {
     username: Option<String>,
     bot_id: Option<slack::BotId>,
     ts: slack::Timestamp,
+    thread_ts: Option<slack::Timestamp>,
+    subtype: Option<String>,
+    #[serde(default)]
+    reply_count: u32,
+    edited: Option<slack::rtm::Edit>,
     #[serde(default)]
     reactions: Vec<Reaction>,
     #[serde(default)]
@@ -1021,4 +1576,6 @@ impl HistoryMessage {
 #[derive(Deserialize)]
 struct HistoryResponse {
     messages: Vec<HistoryMessage>,
+    #[serde(default)]
+    has_more: bool,
 }
@@ -0,0 +1,465 @@
+use crate::conn::{ChannelId, ChannelType, DateTime, Message, MessageId, ServerId};
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, DirBuilder, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::thread::JoinHandle;
+
+// Older messages are dropped whenever a channel's log is compacted
+const MAX_STORED_MESSAGES: usize = 5000;
+
+/// An on-disk cache of every server, channel and message we've seen
+///
+/// Each server gets a directory under `$HOME/.omnichat_store` with a description of its channels,
+/// and one log per channel. Changes are appended to the log as they arrive, and the log is
+/// compacted down to just the messages when it is loaded.
+///
+/// The store has every private channel and direct message in it, so only we may read it.
+///
+/// Writing happens on a thread of its own, so that a busy channel or a page of history doesn't
+/// hold up the interface.
+pub struct Store {
+    root: PathBuf,
+    jobs: Sender<Job>,
+    writer: Option<JoinHandle<()>>,
+}
+
+enum Job {
+    /// Add a line to the end of a file
+    Append(PathBuf, String),
+    /// Replace everything in a file
+    Replace(PathBuf, Vec<u8>),
+    /// Finish everything sent for a file so far and close it, then say so
+    Release(PathBuf, Sender<()>),
+    Stop,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct StoredServer {
+    pub name: String,
+    pub channels: Vec<StoredChannel>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct StoredChannel {
+    pub id: String,
+    pub name: String,
+    pub direct_message: bool,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct StoredMessage {
+    id: String,
+    sender: String,
+    contents: String,
+    timestamp: String,
+    reactions: Vec<(String, usize)>,
+    #[serde(default)]
+    thread: Option<String>,
+    #[serde(default)]
+    broadcast: bool,
+    #[serde(default)]
+    reply_count: usize,
+    #[serde(default)]
+    edited: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+enum Record {
+    Message(StoredMessage),
+    Edited {
+        id: String,
+        contents: String,
+    },
+    Deleted {
+        id: String,
+    },
+    ReactionAdded {
+        id: String,
+        reaction: String,
+    },
+    ReactionRemoved {
+        id: String,
+        reaction: String,
+    },
+    /// We have all the history up to this message
+    SyncedThrough {
+        id: String,
+    },
+}
+
+impl Store {
+    pub fn open() -> Option<Self> {
+        let root = dirs::home_dir()?.join(".omnichat_store");
+        // A store from an older version may have been created readable by everyone
+        create_private_dir(&root)
+            .and_then(|_| fs::set_permissions(&root, fs::Permissions::from_mode(0o700)))
+            .map_err(|e| error!("Couldn't create the message store: {:#?}", e))
+            .ok()?;
+        let (jobs, receiver) = mpsc::channel();
+        let writer = std::thread::spawn(move || write_jobs(receiver));
+        Some(Store {
+            root,
+            jobs,
+            writer: Some(writer),
+        })
+    }
+
+    fn send(&self, job: Job) {
+        if self.jobs.send(job).is_err() {
+            error!("The message store's writer has stopped");
+        }
+    }
+
+    fn server_dir(&self, server: &ServerId) -> PathBuf {
+        self.root.join(server.as_str())
+    }
+
+    fn channel_log(&self, server: &ServerId, channel: &ChannelId) -> PathBuf {
+        self.server_dir(server)
+            .join(format!("{}.jsonl", channel.as_str()))
+    }
+
+    /// Every server we've stored, so they can be browsed before their backend connects
+    pub fn servers(&self) -> Vec<(ServerId, StoredServer)> {
+        let entries = match fs::read_dir(&self.root) {
+            Ok(entries) => entries,
+            Err(e) => {
+                error!("Couldn't read the message store: {:#?}", e);
+                return Vec::new();
+            }
+        };
+        entries
+            .filter_map(|entry| {
+                let entry = entry.ok()?;
+                let id = ServerId::from(entry.file_name().to_str()?);
+                let contents = fs::read(entry.path().join("server.json")).ok()?;
+                let server = serde_json::from_slice(&contents)
+                    .map_err(|e| error!("Corrupt stored server {}: {:#?}", id, e))
+                    .ok()?;
+                Some((id, server))
+            })
+            .collect()
+    }
+
+    pub fn save_server(&self, server: &crate::tui::Server) {
+        let stored = StoredServer {
+            name: server.name.clone(),
+            channels: server
+                .channels
+                .iter()
+                // There's nothing to read in search results until the server is back
+                .filter(|c| c.channel_type != ChannelType::Search)
+                .map(|c| StoredChannel {
+                    id: c.id.to_string(),
+                    name: c.name.clone(),
+                    direct_message: c.channel_type == ChannelType::DirectMessage,
+                })
+                .collect(),
+        };
+        self.send(Job::Replace(
+            self.server_dir(&server.id).join("server.json"),
+            serde_json::to_vec(&stored).unwrap_or_default(),
+        ));
+    }
+
+    /// Load every stored message in a channel, oldest first, and the newest message that we have
+    /// all of the history up to
+    pub fn messages(
+        &self,
+        server: &ServerId,
+        channel: &ChannelId,
+    ) -> (Vec<Message>, Option<MessageId>) {
+        let path = self.channel_log(server, channel);
+
+        // The log can't be read or compacted while the writer still has some of it
+        let (released, wait) = mpsc::channel();
+        self.send(Job::Release(path.clone(), released));
+        let _ = wait.recv();
+
+        let file = match File::open(&path) {
+            Ok(f) => f,
+            Err(_) => return (Vec::new(), None),
+        };
+
+        let mut messages: Vec<StoredMessage> = Vec::new();
+        let mut synced_through = None;
+        let mut index: HashMap<String, usize> = HashMap::new();
+        for line in BufReader::new(file).lines().filter_map(Result::ok) {
+            let record = match serde_json::from_str(&line) {
+                Ok(r) => r,
+                // Most likely a write that was cut off when we exited
+                Err(_) => continue,
+            };
+            let find = |id: &str| index.get(id).copied();
+            match record {
+                Record::Message(message) => match find(&message.id) {
+                    Some(i) => messages[i] = message,
+                    None => {
+                        index.insert(message.id.clone(), messages.len());
+                        messages.push(message);
+                    }
+                },
+                Record::Edited { id, contents } => {
+                    if let Some(i) = find(&id) {
+                        messages[i].contents = contents;
+                        messages[i].edited = true;
+                    }
+                }
+                Record::Deleted { id } => {
+                    if let Some(i) = find(&id) {
+                        messages.remove(i);
+                        index = messages
+                            .iter()
+                            .enumerate()
+                            .map(|(i, m)| (m.id.clone(), i))
+                            .collect();
+                    }
+                }
+                Record::ReactionAdded { id, reaction } => {
+                    if let Some(i) = find(&id) {
+                        let reactions = &mut messages[i].reactions;
+                        match reactions.iter_mut().find(|r| r.0 == reaction) {
+                            Some(r) => r.1 += 1,
+                            None => reactions.push((reaction, 1)),
+                        }
+                    }
+                }
+                Record::ReactionRemoved { id, reaction } => {
+                    if let Some(i) = find(&id) {
+                        let reactions = &mut messages[i].reactions;
+                        if let Some(r) = reactions.iter_mut().find(|r| r.0 == reaction) {
+                            r.1 = r.1.saturating_sub(1);
+                        }
+                        reactions.retain(|r| r.1 > 0);
+                    }
+                }
+                Record::SyncedThrough { id } => synced_through = Some(id),
+            }
+        }
+
+        messages.sort_by(|m1, m2| m1.timestamp.cmp(&m2.timestamp));
+        let excess = messages.len().saturating_sub(MAX_STORED_MESSAGES);
+        messages.drain(..excess);
+
+        // Rewrite the log with only the messages and how far they go, so that it doesn't grow
+        // forever
+        let compacted = path.with_extension("jsonl.tmp");
+        let result = open_private(&compacted, OpenOptions::new().write(true).truncate(true))
+            .and_then(|mut file| {
+                let synced = synced_through
+                    .clone()
+                    .map(|id| Record::SyncedThrough { id });
+                for record in messages.iter().cloned().map(Record::Message).chain(synced) {
+                    writeln!(
+                        file,
+                        "{}",
+                        serde_json::to_string(&record).unwrap_or_default()
+                    )?;
+                }
+                Ok(())
+            })
+            .and_then(|_| fs::rename(&compacted, &path));
+        if let Err(e) = result {
+            error!("Couldn't compact {}: {:#?}", path.display(), e);
+        }
+
+        let messages = messages
+            .into_iter()
+            .map(|m| Message {
+                id: MessageId::from(m.id),
+                server: server.clone(),
+                channel: channel.clone(),
+                sender: m.sender,
+                contents: m.contents,
+                timestamp: ::chrono::DateTime::parse_from_rfc3339(&m.timestamp)
+                    .map(|d| d.with_timezone(&::chrono::Utc).into())
+                    .unwrap_or_else(|_| DateTime::now()),
+                reactions: m.reactions,
+                thread: m.thread.map(MessageId::from),
+                broadcast: m.broadcast,
+                reply_count: m.reply_count,
+                edited: m.edited,
+            })
+            .collect();
+        (messages, synced_through.map(MessageId::from))
+    }
+
+    fn append(&self, server: &ServerId, channel: &ChannelId, record: &Record) {
+        self.send(Job::Append(
+            self.channel_log(server, channel),
+            serde_json::to_string(record).unwrap_or_default(),
+        ));
+    }
+
+    pub fn add_message(&self, message: &Message) {
+        self.append(
+            &message.server,
+            &message.channel,
+            &Record::Message(StoredMessage {
+                id: message.id.to_string(),
+                sender: message.sender.clone(),
+                contents: message.contents.clone(),
+                // RFC 3339 in UTC sorts chronologically as a string
+                timestamp: message
+                    .timestamp
+                    .as_chrono()
+                    .to_rfc3339_opts(::chrono::SecondsFormat::Micros, true),
+                reactions: message.reactions.clone(),
+                thread: message.thread.as_ref().map(MessageId::to_string),
+                broadcast: message.broadcast,
+                reply_count: message.reply_count,
+                edited: message.edited,
+            }),
+        );
+    }
+
+    pub fn edit_message(
+        &self,
+        server: &ServerId,
+        channel: &ChannelId,
+        id: &MessageId,
+        contents: &str,
+    ) {
+        let record = Record::Edited {
+            id: id.to_string(),
+            contents: contents.to_string(),
+        };
+        self.append(server, channel, &record);
+    }
+
+    pub fn delete_message(&self, server: &ServerId, channel: &ChannelId, id: &MessageId) {
+        let record = Record::Deleted { id: id.to_string() };
+        self.append(server, channel, &record);
+    }
+
+    pub fn add_reaction(
+        &self,
+        server: &ServerId,
+        channel: &ChannelId,
+        id: &MessageId,
+        reaction: &str,
+    ) {
+        let record = Record::ReactionAdded {
+            id: id.to_string(),
+            reaction: reaction.to_string(),
+        };
+        self.append(server, channel, &record);
+    }
+
+    pub fn remove_reaction(
+        &self,
+        server: &ServerId,
+        channel: &ChannelId,
+        id: &MessageId,
+        reaction: &str,
+    ) {
+        let record = Record::ReactionRemoved {
+            id: id.to_string(),
+            reaction: reaction.to_string(),
+        };
+        self.append(server, channel, &record);
+    }
+
+    pub fn set_synced_through(&self, server: &ServerId, channel: &ChannelId, id: &MessageId) {
+        let record = Record::SyncedThrough { id: id.to_string() };
+        self.append(server, channel, &record);
+    }
+}
+
+impl Drop for Store {
+    // Everything that was sent is written before we exit
+    fn drop(&mut self) {
+        self.send(Job::Stop);
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+// Carry out writes in order, keeping files open and buffered until there's a lull, so that a page
+// of history is one write to each log rather than one per message
+fn write_jobs(jobs: Receiver<Job>) {
+    let mut files: HashMap<PathBuf, BufWriter<File>> = HashMap::new();
+    loop {
+        let job = match jobs.try_recv() {
+            Ok(job) => job,
+            Err(TryRecvError::Empty) => {
+                close_all(&mut files);
+                match jobs.recv() {
+                    Ok(job) => job,
+                    Err(_) => break,
+                }
+            }
+            Err(TryRecvError::Disconnected) => break,
+        };
+        match job {
+            Job::Append(path, line) => {
+                if !files.contains_key(&path) {
+                    let file = path
+                        .parent()
+                        .map_or(Ok(()), create_private_dir)
+                        .and_then(|_| open_private(&path, OpenOptions::new().append(true)));
+                    match file {
+                        Ok(file) => {
+                            files.insert(path.clone(), BufWriter::new(file));
+                        }
+                        Err(e) => {
+                            error!("Couldn't write to {}: {:#?}", path.display(), e);
+                            continue;
+                        }
+                    }
+                }
+                if let Some(file) = files.get_mut(&path) {
+                    if let Err(e) = writeln!(file, "{}", line) {
+                        error!("Couldn't write to {}: {:#?}", path.display(), e);
+                    }
+                }
+            }
+            Job::Replace(path, contents) => {
+                let result = path
+                    .parent()
+                    .map_or(Ok(()), create_private_dir)
+                    .and_then(|_| {
+                        open_private(&path, OpenOptions::new().write(true).truncate(true))
+                    })
+                    .and_then(|mut file| file.write_all(&contents));
+                if let Err(e) = result {
+                    error!("Couldn't write to {}: {:#?}", path.display(), e);
+                }
+            }
+            Job::Release(path, released) => {
+                if let Some(file) = files.remove(&path) {
+                    close(path, file);
+                }
+                let _ = released.send(());
+            }
+            Job::Stop => break,
+        }
+    }
+    close_all(&mut files);
+}
+
+fn create_private_dir(path: &Path) -> io::Result<()> {
+    DirBuilder::new().recursive(true).mode(0o700).create(path)
+}
+
+fn open_private(path: &Path, options: &mut OpenOptions) -> io::Result<File> {
+    options.create(true).mode(0o600).open(path)
+}
+
+fn close_all(files: &mut HashMap<PathBuf, BufWriter<File>>) {
+    for (path, file) in files.drain() {
+        close(path, file);
+    }
+}
+
+fn close(path: PathBuf, mut file: BufWriter<File>) {
+    if let Err(e) = file.flush() {
+        error!("Couldn't write to {}: {:#?}", path.display(), e);
+    }
+}
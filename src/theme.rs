@@ -0,0 +1,321 @@
+use crate::curses::{Color, ColorSupport, Style};
+use std::collections::HashMap;
+use termion::color::AnsiValue;
+
+/// The colors everything is drawn in
+///
+/// Starts from the `dark` or `light` preset, picked with `preset` in the `[theme]` section of the
+/// config file, and any of the colors can then be overridden there by name. Colors are written
+/// as a name like `"red"` or `"bright-blue"`, a 256-color palette index, or `"#rrggbb"`.
+pub struct Theme {
+    pub colors: ColorSupport,
+    pub background: Color,
+    pub text: Color,
+    /// Servers and channels with nothing new in them
+    pub dim: Color,
+    pub current: Color,
+    pub unread: Color,
+    /// Servers and channels with unread messages that mention us
+    pub mention: Color,
+    pub separator: Color,
+    /// Also used for hints and anything else that should stay out of the way
+    pub timestamp: Color,
+    pub unread_marker: Color,
+    /// The background of the selected message and of menus
+    pub highlight: Color,
+    pub editing: Color,
+    pub alert: Color,
+    /// Each sender's name is drawn in one of these, picked by a hash of the name
+    pub nicks: Vec<Color>,
+}
+
+const NAMED_COLORS: &[&str] = &[
+    "black",
+    "red",
+    "green",
+    "yellow",
+    "blue",
+    "magenta",
+    "cyan",
+    "white",
+    "bright-black",
+    "bright-red",
+    "bright-green",
+    "bright-yellow",
+    "bright-blue",
+    "bright-magenta",
+    "bright-cyan",
+    "bright-white",
+];
+
+impl Theme {
+    pub fn dark() -> Self {
+        let mut nicks = Vec::with_capacity(45);
+        for r in 1..6 {
+            for g in 1..6 {
+                for b in 1..6 {
+                    if r < 2 || g < 2 || b < 2 {
+                        nicks.push(AnsiValue::rgb(r, g, b).into());
+                    }
+                }
+            }
+        }
+        Theme {
+            colors: ColorSupport::detect(),
+            background: AnsiValue::rgb(0, 0, 0).into(),
+            text: AnsiValue::rgb(5, 5, 5).into(),
+            dim: AnsiValue::rgb(3, 3, 3).into(),
+            current: AnsiValue::rgb(5, 5, 5).into(),
+            unread: AnsiValue::rgb(5, 0, 0).into(),
+            mention: AnsiValue::rgb(5, 1, 5).into(),
+            separator: AnsiValue::rgb(5, 5, 5).into(),
+            timestamp: AnsiValue::grayscale(8).into(),
+            unread_marker: AnsiValue::rgb(5, 0, 0).into(),
+            highlight: AnsiValue::grayscale(4).into(),
+            editing: AnsiValue::rgb(5, 5, 0).into(),
+            alert: AnsiValue::rgb(5, 0, 0).into(),
+            nicks,
+        }
+    }
+
+    pub fn light() -> Self {
+        // Names need to be dark enough to read on white
+        let mut nicks = Vec::new();
+        for r in 0..4 {
+            for g in 0..4 {
+                for b in 0..4 {
+                    if max3(r, g, b) >= 2 && !(r == g && g == b) {
+                        nicks.push(AnsiValue::rgb(r, g, b).into());
+                    }
+                }
+            }
+        }
+        Theme {
+            colors: ColorSupport::detect(),
+            background: AnsiValue::rgb(5, 5, 5).into(),
+            text: AnsiValue::rgb(0, 0, 0).into(),
+            dim: AnsiValue::grayscale(10).into(),
+            current: AnsiValue::rgb(0, 0, 0).into(),
+            unread: AnsiValue::rgb(4, 0, 0).into(),
+            mention: AnsiValue::rgb(3, 0, 3).into(),
+            separator: AnsiValue::grayscale(12).into(),
+            timestamp: AnsiValue::grayscale(12).into(),
+            unread_marker: AnsiValue::rgb(4, 0, 0).into(),
+            highlight: AnsiValue::grayscale(21).into(),
+            editing: AnsiValue::rgb(3, 2, 0).into(),
+            alert: AnsiValue::rgb(4, 0, 0).into(),
+            nicks,
+        }
+    }
+
+    /// Apply the `[theme]` section of the config file, or describe everything that's wrong with it
+    pub fn from_config(config: &HashMap<String, toml::Value>) -> Result<Self, Vec<String>> {
+        let mut errors = Vec::new();
+        let mut theme = match config.get("preset").map(|p| p.as_str()) {
+            None | Some(Some("dark")) => Theme::dark(),
+            Some(Some("light")) => Theme::light(),
+            Some(_) => {
+                errors.push(format!(
+                    "preset must be \"dark\" or \"light\", not {}",
+                    config["preset"]
+                ));
+                Theme::dark()
+            }
+        };
+
+        for (name, value) in config {
+            let color = match name.as_str() {
+                "preset" => continue,
+                "colors" => {
+                    match value.as_str().and_then(ColorSupport::from_name) {
+                        Some(colors) => theme.colors = colors,
+                        None => errors.push(format!(
+                            "colors must be \"auto\", \"none\", \"16\", \"256\" or \"truecolor\", not {}",
+                            value
+                        )),
+                    }
+                    continue;
+                }
+                "nicks" => {
+                    match value.as_array() {
+                        Some(values) => {
+                            let nicks: Vec<_> = values
+                                .iter()
+                                .filter_map(|v| parse_color(v).map_err(|e| errors.push(e)).ok())
+                                .collect();
+                            if nicks.is_empty() {
+                                errors.push("nicks needs at least one color".to_string());
+                            } else {
+                                theme.nicks = nicks;
+                            }
+                        }
+                        None => {
+                            errors.push(format!("nicks must be a list of colors, not {}", value))
+                        }
+                    }
+                    continue;
+                }
+                "background" => &mut theme.background,
+                "text" => &mut theme.text,
+                "dim" => &mut theme.dim,
+                "current" => &mut theme.current,
+                "unread" => &mut theme.unread,
+                "mention" => &mut theme.mention,
+                "separator" => &mut theme.separator,
+                "timestamp" => &mut theme.timestamp,
+                "unread_marker" => &mut theme.unread_marker,
+                "highlight" => &mut theme.highlight,
+                "editing" => &mut theme.editing,
+                "alert" => &mut theme.alert,
+                _ => {
+                    errors.push(format!(
+                        "unknown setting \"{}\", the settings are preset, colors, background, \
+                         text, dim, current, unread, mention, separator, timestamp, \
+                         unread_marker, highlight, editing, alert and nicks",
+                        name
+                    ));
+                    continue;
+                }
+            };
+            match parse_color(value) {
+                Ok(c) => *color = c,
+                Err(e) => errors.push(format!("{}: {}", name, e)),
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(theme)
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Picks something out from what's around it, like the selected message or menu entry
+    ///
+    /// Without colors the background can't do that, so the text is drawn reversed instead.
+    pub fn highlighted(&self, foreground: Color, background: Color) -> Style {
+        Style {
+            reverse: self.colors == ColorSupport::None,
+            ..Style::new(foreground, background)
+        }
+    }
+
+    /// The color a sender's name is drawn in, which stays the same between runs
+    pub fn nick_color(&self, sender: &str) -> Color {
+        self.nicks[djb2(sender) as usize % self.nicks.len()]
+    }
+}
+
+fn djb2(input: &str) -> u64 {
+    let mut hash: u64 = 5381;
+
+    for c in input.bytes() {
+        hash = (hash << 5).wrapping_add(hash).wrapping_add(u64::from(c));
+    }
+    hash
+}
+
+fn max3(a: u8, b: u8, c: u8) -> u8 {
+    a.max(b).max(c)
+}
+
+fn parse_color(value: &toml::Value) -> Result<Color, String> {
+    let invalid = || {
+        format!(
+            "{} is not a color, colors are a name like \"red\" or \"bright-red\", a number from 0 \
+             to 255, or \"#rrggbb\"",
+            value
+        )
+    };
+    match value {
+        toml::Value::Integer(i) if (0..256).contains(i) => Ok(Color::Ansi(*i as u8)),
+        toml::Value::String(s) => {
+            if let Some(i) = NAMED_COLORS.iter().position(|name| name == s) {
+                Ok(Color::Ansi(i as u8))
+            } else if let Some(hex) = s.strip_prefix('#').filter(|h| h.len() == 6) {
+                let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+                match (channel(0), channel(2), channel(4)) {
+                    (Some(r), Some(g), Some(b)) => Ok(Color::Rgb(r, g, b)),
+                    _ => Err(invalid()),
+                }
+            } else {
+                Err(invalid())
+            }
+        }
+        _ => Err(invalid()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(text: &str) -> HashMap<String, toml::Value> {
+        toml::from_str(text).unwrap()
+    }
+
+    #[test]
+    fn parses_names_indices_and_hex() {
+        let color = |text: &str| parse_color(&config(&format!("c = {}", text))["c"]);
+        assert_eq!(color("\"red\""), Ok(Color::Ansi(1)));
+        assert_eq!(color("\"bright-white\""), Ok(Color::Ansi(15)));
+        assert_eq!(color("208"), Ok(Color::Ansi(208)));
+        assert_eq!(color("\"#ff8000\""), Ok(Color::Rgb(255, 128, 0)));
+        assert_eq!(color("\"#FF8000\""), Ok(Color::Rgb(255, 128, 0)));
+    }
+
+    #[test]
+    fn rejects_anything_else() {
+        let color = |text: &str| parse_color(&config(&format!("c = {}", text))["c"]);
+        assert!(color("256").is_err());
+        assert!(color("-1").is_err());
+        assert!(color("\"Red\"").is_err());
+        assert!(color("\"#ff80\"").is_err());
+        assert!(color("\"#ff80zz\"").is_err());
+        // Six bytes, but not six hex digits
+        assert!(color("\"#ff80é\"").is_err());
+        assert!(color("true").is_err());
+    }
+
+    #[test]
+    fn overrides_the_preset() {
+        let theme = Theme::from_config(&config(
+            "preset = \"light\"\nalert = \"red\"\nnicks = [1, \"#000080\"]",
+        ))
+        .ok()
+        .unwrap();
+        assert_eq!(theme.background, Theme::light().background);
+        assert_eq!(theme.alert, Color::Ansi(1));
+        assert_eq!(theme.nicks, vec![Color::Ansi(1), Color::Rgb(0, 0, 128)]);
+    }
+
+    #[test]
+    fn reports_every_problem() {
+        let errors = Theme::from_config(&config(
+            "preset = \"solarized\"\ntext = \"plaid\"\nborder = 1\nnicks = []",
+        ))
+        .err()
+        .unwrap();
+        assert_eq!(errors.len(), 4);
+        assert!(errors.iter().any(|e| e.starts_with("preset must be")));
+        assert!(errors
+            .iter()
+            .any(|e| e.starts_with("text: \"plaid\" is not a color")));
+        assert!(errors
+            .iter()
+            .any(|e| e.starts_with("unknown setting \"border\"")));
+        assert!(errors.iter().any(|e| e == "nicks needs at least one color"));
+    }
+
+    #[test]
+    fn highlights_are_reversed_without_colors() {
+        let theme = Theme::from_config(&config("colors = \"none\""))
+            .ok()
+            .unwrap();
+        assert!(theme.highlighted(theme.text, theme.highlight).reverse);
+        let theme = Theme::from_config(&config("colors = \"256\""))
+            .ok()
+            .unwrap();
+        assert!(!theme.highlighted(theme.text, theme.highlight).reverse);
+    }
+}
@@ -1,9 +1,20 @@
 use crate::chan_message::ChanMessage;
-use crate::conn::{ChannelType, Completer, ConnEvent, DateTime, Message, TuiEvent};
+use crate::conn::{
+    Capabilities, ChannelId, ChannelType, Completer, ConnEvent, DateTime, Message, MessageId,
+    ServerId, TuiEvent,
+};
 use crate::cursor_vec::CursorVec;
+use crate::keys::{Action, Keymap};
+use crate::line_editor::LineEditor;
+use crate::notify::{Notification, Notifier};
+use crate::store::Store;
+use crate::theme::Theme;
 use crate::DFAExtension;
 
 use std::cmp::{max, min};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
 use futures::channel::mpsc::{UnboundedReceiver, UnboundedSender};
 use futures::prelude::*;
@@ -23,9 +34,23 @@ lazy_static::lazy_static! {
 }
 
 const CHAN_WIDTH: u16 = 20;
+const COMPLETION_MENU_HEIGHT: usize = 10;
+// The input area grows to this many rows before it starts scrolling
+const MAX_INPUT_ROWS: usize = 8;
+// What terminals send around pasted text once bracketed paste is on
+const PASTE_START: &[u8] = b"\x1b[200~";
+const PASTE_END: &[u8] = b"\x1b[201~";
+// And when the terminal gains or loses focus
+const FOCUS_IN: &[u8] = b"\x1b[I";
+const FOCUS_OUT: &[u8] = b"\x1b[O";
+
+// Handles for the Client tab, which is always present and displays the error log
+const CLIENT_ID: &str = "client";
+const ERRORS_ID: &str = "errors";
 
 pub struct Tui {
     servers: CursorVec<Server>,
+    server_index: HashMap<ServerId, usize>,
     longest_channel_name: u16,
     shutdown: bool,
     events: UnboundedReceiver<ConnEvent>,
@@ -33,37 +58,120 @@ pub struct Tui {
     server_scroll_offset: usize,
     autocompletions: Vec<String>,
     autocomplete_index: usize,
-    cursor_pos: usize,
-    _guards: (
+    /// The last text cut out of an input line, which Ctrl-Y pastes back
+    killed: String,
+    store: Option<Store>,
+    search: Option<SearchResults>,
+    /// The message being edited, the next message sent replaces its contents
+    editing: Option<(ServerId, ChannelId, MessageId)>,
+    /// The message a `+:emoji:` reacts to, instead of the most recent one
+    reacting: Option<(ServerId, ChannelId, MessageId)>,
+    /// The highlighted message in selection mode, which keys act on instead of typing
+    selection: Option<MessageId>,
+    /// A message in the current channel we're asking whether to delete
+    pending_delete: Option<MessageId>,
+    keymap: Keymap,
+    theme: Theme,
+    /// Words that count as a mention, as well as our name
+    keywords: Vec<String>,
+    notifier: Notifier,
+    /// Whether the terminal has focus, so that we know if new messages are being seen
+    focused: bool,
+    /// Stops the input thread from reading while another program has the terminal
+    input_paused: Arc<AtomicBool>,
+    /// Set when another program had the terminal, so everything needs drawing again
+    redraw_all: bool,
+    /// Put the terminal back the way it was when dropped
+    guards: (
         termion::screen::AlternateScreen<::std::io::Stdout>,
         termion::raw::RawTerminal<::std::io::Stdout>,
+        TerminalModes,
     ),
 }
 
 pub struct Server {
+    pub id: ServerId,
     pub channels: Vec<Channel>,
-    pub completer: Option<Box<dyn Completer>>,
+    channel_index: HashMap<ChannelId, usize>,
+    pub completer: Option<Completer>,
     pub name: String,
+    /// Who we are on this server, so that we can find our own messages
+    pub my_name: Option<String>,
     pub current_channel: usize,
     pub channel_scroll_offset: usize,
     pub sender: UnboundedSender<TuiEvent>,
+    pub capabilities: Capabilities,
+    connected: bool,
 }
 
 impl Server {
+    pub fn new(
+        id: ServerId,
+        name: String,
+        my_name: Option<String>,
+        channels: Vec<Channel>,
+        completer: Option<Completer>,
+        sender: UnboundedSender<TuiEvent>,
+        capabilities: Capabilities,
+    ) -> Self {
+        let mut server = Server {
+            id,
+            channels,
+            channel_index: HashMap::new(),
+            completer,
+            name,
+            my_name,
+            current_channel: 0,
+            channel_scroll_offset: 0,
+            sender,
+            capabilities,
+            connected: true,
+        };
+        server.reindex_channels();
+        server
+    }
+
     fn has_unreads(&self) -> bool {
         self.channels.iter().any(Channel::is_unread)
     }
+
+    fn num_mentions(&self) -> usize {
+        self.channels.iter().map(Channel::num_mentions).sum()
+    }
+
+    // Must be called whenever channels are added or reordered
+    fn reindex_channels(&mut self) {
+        self.channel_index = self
+            .channels
+            .iter()
+            .enumerate()
+            .map(|(i, c)| (c.id.clone(), i))
+            .collect();
+    }
+
+    fn channel_mut(&mut self, id: &ChannelId) -> Option<&mut Channel> {
+        let index = *self.channel_index.get(id)?;
+        self.channels.get_mut(index)
+    }
 }
 
 pub struct Channel {
+    pub id: ChannelId,
     pub messages: Vec<ChanMessage>,
     pub name: String,
     pub read_at: DateTime,
     pub latest: DateTime,
     pub has_history: bool,
+    /// The newest message that we have all of the history up to, which only loading history moves
+    /// forward, because live messages can arrive in a channel we haven't loaded yet
+    pub synced_through: Option<MessageId>,
     pub message_scroll_offset: usize,
-    pub message_buffer: String,
+    pub input: LineEditor,
     pub channel_type: ChannelType,
+    /// Replies, by the message that started their thread
+    pub threads: HashMap<MessageId, Vec<ChanMessage>>,
+    /// Which thread is shown instead of the channel
+    pub open_thread: Option<MessageId>,
 }
 
 impl Channel {
@@ -71,43 +179,380 @@ impl Channel {
         self.latest > self.read_at
     }
 
-    fn num_unreads(&self) -> usize {
+    // A broadcast reply is both in the channel and in its thread, so there may be more than one
+    fn messages_with_id_mut<'a>(
+        &'a mut self,
+        id: &'a MessageId,
+    ) -> impl Iterator<Item = &'a mut ChanMessage> + 'a {
+        self.messages
+            .iter_mut()
+            .chain(self.threads.values_mut().flatten())
+            .filter(move |m| m.id() == id)
+    }
+
+    fn remove_message(&mut self, id: &MessageId) -> bool {
+        let thread = self
+            .messages_with_id_mut(id)
+            .next()
+            .and_then(|m| m.thread().cloned());
+        let before = self.messages.len() + self.threads.values().map(Vec::len).sum::<usize>();
+        let was_latest = self
+            .messages
+            .iter()
+            .any(|m| m.id() == id && *m.timestamp() >= self.latest);
+        self.messages.retain(|m| m.id() != id);
+        // The channel shouldn't stay unread because of a message that's gone
+        if was_latest {
+            self.latest = self
+                .messages
+                .last()
+                .map(|m| *m.timestamp())
+                .unwrap_or(self.read_at);
+        }
+        for replies in self.threads.values_mut() {
+            replies.retain(|m| m.id() != id);
+        }
+        let after = self.messages.len() + self.threads.values().map(Vec::len).sum::<usize>();
+        if let Some(thread) = thread {
+            self.messages_with_id_mut(&thread)
+                .for_each(ChanMessage::remove_reply);
+        }
+        after < before
+    }
+
+    /// Add messages to the channel, or to their thread if they are replies
+    fn insert_messages(&mut self, messages: impl IntoIterator<Item = ChanMessage>) {
+        for message in messages {
+            if let Some(thread) = message.thread().cloned() {
+                let replies = self.threads.entry(thread).or_default();
+                if !message.broadcast() {
+                    replies.push(message);
+                    continue;
+                }
+                replies.push(message.clone());
+            }
+            self.messages.push(message);
+        }
+        self.messages
+            .sort_unstable_by(|m1, m2| m1.timestamp().cmp(&m2.timestamp()));
+        for replies in self.threads.values_mut() {
+            replies.sort_unstable_by(|m1, m2| m1.timestamp().cmp(&m2.timestamp()));
+        }
+    }
+
+    fn shown_messages(&self) -> &[ChanMessage] {
+        match self.open_thread.as_ref().and_then(|t| self.threads.get(t)) {
+            Some(replies) => replies,
+            None => &self.messages,
+        }
+    }
+
+    fn shown_messages_mut(&mut self) -> &mut Vec<ChanMessage> {
+        match &self.open_thread {
+            Some(thread) => self.threads.entry(thread.clone()).or_default(),
+            None => &mut self.messages,
+        }
+    }
+
+    fn unread_messages(&self) -> impl Iterator<Item = &ChanMessage> {
         self.messages
             .iter()
             .rev()
-            .take_while(|m| *m.timestamp() > self.read_at)
-            .count()
+            .take_while(move |m| *m.timestamp() > self.read_at)
+    }
+
+    fn num_unreads(&self) -> usize {
+        self.unread_messages().count()
+    }
+
+    /// How many unread messages from other people mention us
+    fn num_mentions(&self) -> usize {
+        self.unread_messages().filter(|m| m.mentions_us()).count()
+    }
+}
+
+// Where a message found by /search lives, and how to describe it in the results pane
+struct SearchResult {
+    server: ServerId,
+    channel: ChannelId,
+    id: MessageId,
+    timestamp: DateTime,
+    summary: String,
+}
+
+struct SearchResults {
+    query: String,
+    results: Vec<SearchResult>,
+    selected: usize,
+}
+
+/// Has the terminal mark pasted text and report when it gains or loses focus, while it's alive
+///
+/// Otherwise a paste looks just like typing, and every newline in it sends a message.
+struct TerminalModes;
+
+impl TerminalModes {
+    fn enable() -> Self {
+        TerminalModes::set(true);
+        TerminalModes
+    }
+
+    fn set(enabled: bool) {
+        use std::io::Write;
+        let mode = if enabled { 'h' } else { 'l' };
+        print!("\x1b[?2004{}\x1b[?1004{}", mode, mode);
+        let _ = std::io::stdout().flush();
+    }
+}
+
+impl Drop for TerminalModes {
+    fn drop(&mut self) {
+        TerminalModes::set(false);
+    }
+}
+
+/// Reads stdin without buffering, and not at all while `paused`
+///
+/// Anything blocked on stdin would otherwise steal keypresses from a program we hand the
+/// terminal over to, so this only waits for input a little at a time to check in between.
+struct TerminalInput {
+    paused: Arc<AtomicBool>,
+}
+
+impl std::io::Read for TerminalInput {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            if self.paused.load(Ordering::SeqCst) {
+                std::thread::sleep(std::time::Duration::from_millis(50));
+                continue;
+            }
+            let mut stdin = libc::pollfd {
+                fd: libc::STDIN_FILENO,
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            let ready = unsafe { libc::poll(&mut stdin, 1, 100) };
+            if ready < 0 {
+                let error = std::io::Error::last_os_error();
+                if error.kind() != std::io::ErrorKind::Interrupted {
+                    return Err(error);
+                }
+            } else if ready > 0 && !self.paused.load(Ordering::SeqCst) {
+                let n = unsafe {
+                    libc::read(
+                        libc::STDIN_FILENO,
+                        buf.as_mut_ptr() as *mut libc::c_void,
+                        buf.len(),
+                    )
+                };
+                return if n < 0 {
+                    Err(std::io::Error::last_os_error())
+                } else {
+                    Ok(n as usize)
+                };
+            }
+        }
+    }
+}
+
+/// Break the input into the rows it's drawn in, each with the offset in the text it starts at
+///
+/// A row that exactly fills the width is followed by an empty one, so that a cursor at its end
+/// has somewhere to go.
+fn input_rows(text: &str, width: usize) -> Vec<(usize, &str)> {
+    use crate::curses::{display_width, truncate_to_width};
+
+    let mut rows = Vec::new();
+    let mut start = 0;
+    for line in text.split('\n') {
+        let mut rest = line;
+        loop {
+            let row = truncate_to_width(rest, width);
+            rows.push((start, row));
+            start += row.len();
+            rest = &rest[row.len()..];
+            if row.is_empty() || (rest.is_empty() && display_width(row) < width) {
+                break;
+            }
+        }
+        // Skip the newline
+        start += 1;
+    }
+    rows
+}
+
+/// Whether someone else's message is meant for us
+fn mentions_us(sender: &str, text: &str, my_name: Option<&str>, keywords: &[String]) -> bool {
+    Some(sender) != my_name && is_mention(text, my_name, keywords)
+}
+
+/// Get a message ready for a channel, noting whether it mentions us
+fn chan_message(message: Message, my_name: Option<&str>, keywords: &[String]) -> ChanMessage {
+    let mentions = mentions_us(&message.sender, &message.contents, my_name, keywords);
+    let mut message = ChanMessage::from(message);
+    message.set_mentions_us(mentions);
+    message
+}
+
+/// Whether a message is meant for us, because it has our name, a keyword, or @here and the like
+///
+/// Everything is matched as a whole word and without regard to case.
+fn is_mention(text: &str, my_name: Option<&str>, keywords: &[String]) -> bool {
+    let text = text.to_lowercase();
+    let is_word_char = |c: Option<char>| matches!(c, Some(c) if c.is_alphanumeric() || c == '_');
+    let has_word = |word: &str| {
+        let word = word.to_lowercase();
+        !word.is_empty()
+            && text.match_indices(&word).any(|(i, _)| {
+                !is_word_char(text[..i].chars().next_back())
+                    && !is_word_char(text[i + word.len()..].chars().next())
+            })
+    };
+    ["@here", "@channel", "@everyone"]
+        .iter()
+        .any(|w| has_word(w))
+        || matches!(my_name, Some(name) if has_word(&format!("@{}", name)))
+        || keywords.iter().any(|k| has_word(k))
+}
+
+/// Put text on the clipboard, with xclip
+fn copy_to_clipboard(text: &[u8]) -> bool {
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+    Command::new("xclip")
+        .arg("-selection")
+        .arg("clipboard")
+        .stdin(Stdio::piped())
+        .spawn()
+        .and_then(|mut child| child.stdin.as_mut().unwrap().write_all(text))
+        .map_err(|e| error!("{:#?}", e))
+        .is_ok()
+}
+
+/// Put a draft in a new file that only we can read, for an editor to open
+///
+/// Anyone can write to the temporary directory, so the file must be one we just created rather
+/// than one somebody left there for us.
+fn write_draft(draft: &str) -> std::io::Result<std::path::PathBuf> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let mut attempt = 0;
+    loop {
+        let path =
+            std::env::temp_dir().join(format!("omnichat-{}-{}.txt", std::process::id(), attempt));
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path);
+        match file {
+            Ok(mut file) => {
+                if let Err(e) = file.write_all(draft.as_bytes()) {
+                    let _ = std::fs::remove_file(&path);
+                    return Err(e);
+                }
+                return Ok(path);
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists && attempt < 100 => {
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
     }
 }
 
+/// Every URL in some text, in order
+fn find_urls(text: &str) -> Vec<&str> {
+    let mut urls = Vec::new();
+    let mut rest = text.as_bytes();
+    while let Some(url) = URL_REGEX.get_first(rest) {
+        let end = url.as_ptr() as usize - rest.as_ptr() as usize + url.len();
+        let url = url.strip_suffix(b">").unwrap_or(url);
+        if let Ok(url) = std::str::from_utf8(url) {
+            urls.push(url);
+        }
+        rest = &rest[end..];
+    }
+    urls
+}
+
+/// What comes after a command such as `/search`, if the message is that command
+///
+/// Checking for a space after it keeps `/s` from also matching `/search`, and so on.
+fn command_argument<'a>(contents: &'a str, command: &str) -> Option<&'a str> {
+    contents
+        .strip_prefix(command)
+        .filter(|rest| rest.is_empty() || rest.starts_with(' '))
+        .map(str::trim_start)
+}
+
+/// Split `s/old/new/` into old and new
+///
+/// Only that exact form counts, so that a message which happens to start with `s/` is still sent.
+fn parse_substitution(text: &str) -> Option<(&str, &str)> {
+    let mut parts = text.strip_prefix("s/")?.strip_suffix('/')?.splitn(2, '/');
+    let old = parts.next()?;
+    let new = parts.next()?;
+    if old.is_empty() || new.contains('/') {
+        return None;
+    }
+    Some((old, new))
+}
+
 impl Tui {
-    pub fn new() -> Self {
+    pub fn new(keymap: Keymap, theme: Theme, keywords: Vec<String>, notifier: Notifier) -> Self {
         use termion::raw::IntoRawMode;
 
         let screenguard = termion::screen::AlternateScreen::from(::std::io::stdout());
         let rawguard = std::io::stdout()
             .into_raw_mode()
             .expect("Couldn't put the terminal in raw mode");
+        let modeguard = TerminalModes::enable();
 
         let (sender, reciever) = futures::channel::mpsc::unbounded();
 
         // Launch a background thread to feed input from stdin
         // Note this isn't raw keyboard events, it's termion's opinion of an event
-        let mut stdin_sender = sender.clone();
-        tokio::spawn(async move {
-            use tokio::io::AsyncReadExt;
-            let mut stdin = tokio::io::stdin();
-            loop {
-                let mut bytes = [0u8; 4];
-                let n = stdin.read(&mut bytes).await.unwrap();
-                if n == 0 {
-                    continue;
-                }
-                if let Ok(event) = termion::event::parse_event(
-                    bytes[0],
-                    &mut bytes[1..].iter().take(n - 1).map(|b| Ok(*b)),
-                ) {
-                    stdin_sender.send(ConnEvent::Input(event)).await.unwrap();
+        let stdin_sender = sender.clone();
+        let input_paused = Arc::new(AtomicBool::new(false));
+        let stdin = TerminalInput {
+            paused: input_paused.clone(),
+        };
+        std::thread::spawn(move || {
+            use termion::event::Event;
+            use termion::input::TermReadEventsAndRaw;
+            // Everything between the terminal's paste markers, as it was sent
+            let mut pasted: Option<Vec<u8>> = None;
+            for input in stdin.events_and_raw() {
+                let (event, raw) = match input {
+                    Ok(input) => input,
+                    Err(_) => break,
+                };
+                let to_send = match (event, &mut pasted) {
+                    // Focus changes can arrive in the middle of a paste, but aren't part of it
+                    (Event::Unsupported(bytes), _) if bytes == FOCUS_IN || bytes == FOCUS_OUT => {
+                        ConnEvent::Input(Event::Unsupported(bytes))
+                    }
+                    (Event::Unsupported(ref bytes), None) if bytes == PASTE_START => {
+                        pasted = Some(Vec::new());
+                        continue;
+                    }
+                    (Event::Unsupported(ref bytes), Some(text)) if bytes == PASTE_END => {
+                        let text = String::from_utf8_lossy(text)
+                            .replace("\r\n", "\n")
+                            .replace('\r', "\n");
+                        pasted = None;
+                        ConnEvent::Paste(text)
+                    }
+                    (_, Some(text)) => {
+                        text.extend(raw);
+                        continue;
+                    }
+                    (event, None) => ConnEvent::Input(event),
+                };
+                if stdin_sender.unbounded_send(to_send).is_err() {
+                    break;
                 }
             }
         });
@@ -123,14 +568,20 @@ impl Tui {
                     contents, channel, ..
                 } = ev
                 {
+                    let timestamp = DateTime::now();
                     to_tui
                         .send(ConnEvent::Message(Message {
-                            server: "Client".into(),
+                            id: MessageId::from(timestamp.to_string()),
+                            server: ServerId::from(CLIENT_ID),
                             channel,
                             sender: "You".into(),
                             contents,
-                            timestamp: DateTime::now(),
+                            timestamp,
                             reactions: Vec::new(),
+                            thread: None,
+                            broadcast: false,
+                            reply_count: 0,
+                            edited: false,
                         }))
                         .await
                         .unwrap();
@@ -140,26 +591,45 @@ impl Tui {
 
         // Initialize with the Client's server which displays an error log
         let now = DateTime::now();
-        let client = Server {
-            channels: vec![Channel {
+        let client = Server::new(
+            ServerId::from(CLIENT_ID),
+            "Client".into(),
+            None,
+            vec![Channel {
+                id: ChannelId::from(ERRORS_ID),
                 messages: Vec::new(),
                 name: "Errors".into(),
                 read_at: now,
                 latest: now,
                 has_history: false,
+                synced_through: None,
                 message_scroll_offset: 0,
-                message_buffer: String::new(),
+                input: LineEditor::default(),
                 channel_type: ChannelType::Normal,
+                threads: HashMap::new(),
+                open_thread: None,
             }],
-            completer: None,
-            channel_scroll_offset: 0,
-            current_channel: 0,
-            name: "Client".into(),
-            sender: to_client,
-        };
+            None,
+            to_client,
+            Capabilities::default(),
+        );
+
+        let mut server_index = HashMap::new();
+        server_index.insert(client.id.clone(), 0);
+
+        // Servers from the store that haven't connected yet have nowhere to send events
+        let (offline_sender, mut from_offline) = futures::channel::mpsc::unbounded();
+        tokio::spawn(async move {
+            while let Some(ev) = from_offline.next().await {
+                if let TuiEvent::SendMessage { server, .. } = ev {
+                    error!("Can't send messages to {} while it's offline", server);
+                }
+            }
+        });
 
-        Self {
+        let mut tui = Self {
             servers: CursorVec::new(client),
+            server_index,
             longest_channel_name: 6, // "Client"
             shutdown: false,
             events: reciever,
@@ -167,8 +637,70 @@ impl Tui {
             server_scroll_offset: 0,
             autocompletions: Vec::new(),
             autocomplete_index: 0,
-            cursor_pos: 0,
-            _guards: (screenguard, rawguard),
+            killed: String::new(),
+            store: Store::open(),
+            search: None,
+            editing: None,
+            reacting: None,
+            selection: None,
+            pending_delete: None,
+            keymap,
+            theme,
+            keywords,
+            notifier,
+            focused: true,
+            input_paused,
+            redraw_all: false,
+            guards: (screenguard, rawguard, modeguard),
+        };
+
+        // Show everything we have stored right away, backends replace these as they connect
+        let stored_servers = tui.store.as_ref().map(Store::servers).unwrap_or_default();
+        for (id, stored) in stored_servers {
+            let channels = stored
+                .channels
+                .into_iter()
+                .map(|c| Channel {
+                    id: ChannelId::from(c.id),
+                    messages: Vec::new(),
+                    name: c.name,
+                    read_at: now,
+                    latest: now,
+                    has_history: false,
+                    synced_through: None,
+                    message_scroll_offset: 0,
+                    input: LineEditor::default(),
+                    channel_type: if c.direct_message {
+                        ChannelType::DirectMessage
+                    } else {
+                        ChannelType::Normal
+                    },
+                    threads: HashMap::new(),
+                    open_thread: None,
+                })
+                .collect();
+            let mut server = Server::new(
+                id,
+                stored.name,
+                None,
+                channels,
+                None,
+                offline_sender.clone(),
+                Capabilities::default(),
+            );
+            server.connected = false;
+            tui.add_server(server);
+        }
+
+        tui
+    }
+
+    /// Where to persist events for a server, the Client tab isn't worth keeping
+    fn store_for(&self, server: &ServerId) -> Option<&Store> {
+        if server.as_str() == CLIENT_ID {
+            None
+        } else {
+            self.store.as_ref()
         }
     }
 
@@ -177,12 +709,13 @@ impl Tui {
     }
 
     async fn update_history(&mut self) {
-        if !self.current_channel().has_history {
-            let channel_to_update = self.current_channel().name.clone();
+        if self.servers.get().capabilities.history && !self.current_channel().has_history {
+            let channel_to_update = self.current_channel().id.clone();
             let mut sender = self.servers.get().sender.clone();
             sender
                 .send(TuiEvent::GetHistory {
                     channel: channel_to_update,
+                    after: self.current_channel().synced_through.clone(),
                 })
                 .await
                 .unwrap();
@@ -199,6 +732,21 @@ impl Tui {
         &mut server.channels[server.current_channel]
     }
 
+    // Who we are on a server, if it tells us
+    fn my_name(&self, id: &ServerId) -> Option<String> {
+        let index = *self.server_index.get(id)?;
+        self.servers.iter().nth(index)?.my_name.clone()
+    }
+
+    fn server_mut(&mut self, id: &ServerId) -> Option<&mut Server> {
+        let index = *self.server_index.get(id)?;
+        self.servers.get_index_mut(index)
+    }
+
+    fn channel_mut(&mut self, server: &ServerId, channel: &ChannelId) -> Option<&mut Channel> {
+        self.server_mut(server)?.channel_mut(channel)
+    }
+
     async fn reset_current_unreads(&mut self) {
         let server = self.servers.get_mut();
         server.channels[server.current_channel].read_at = chrono::Utc::now().into();
@@ -206,8 +754,8 @@ impl Tui {
 
         let mut sender = server.sender.clone();
         let event = TuiEvent::MarkRead {
-            server: server.name.clone(),
-            channel: current_channel.name.clone(),
+            server: server.id.clone(),
+            channel: current_channel.id.clone(),
         };
         sender.send(event).await.unwrap();
     }
@@ -215,14 +763,12 @@ impl Tui {
     async fn next_server(&mut self) {
         self.reset_current_unreads().await;
         self.servers.next();
-        self.cursor_pos = min(self.cursor_pos, self.current_channel().message_buffer.len());
         self.update_history().await;
     }
 
     async fn previous_server(&mut self) {
         self.reset_current_unreads().await;
         self.servers.prev();
-        self.cursor_pos = min(self.cursor_pos, self.current_channel().message_buffer.len());
         self.update_history().await;
     }
 
@@ -235,7 +781,6 @@ impl Tui {
             self.reset_current_unreads().await;
             self.servers.get_mut().current_channel = index;
         }
-        self.cursor_pos = min(self.cursor_pos, self.current_channel().message_buffer.len());
         self.update_history().await;
     }
 
@@ -256,7 +801,6 @@ impl Tui {
                 self.servers.get_mut().current_channel = index;
             }
         }
-        self.cursor_pos = min(self.cursor_pos, self.current_channel().message_buffer.len());
         self.update_history().await;
     }
 
@@ -267,7 +811,6 @@ impl Tui {
         if server.current_channel >= server.channels.len() {
             server.current_channel = 0;
         }
-        self.cursor_pos = min(self.cursor_pos, self.current_channel().message_buffer.len());
         self.update_history().await;
     }
 
@@ -279,37 +822,85 @@ impl Tui {
         } else {
             server.current_channel = server.channels.len() - 1;
         }
-        self.cursor_pos = min(self.cursor_pos, self.current_channel().message_buffer.len());
         self.update_history().await;
     }
 
     // Take by value because we need to own the allocation
     fn add_client_message(&mut self, message: String) {
+        let timestamp = DateTime::now();
         self.servers.get_first_mut().channels[0]
             .messages
             .push(ChanMessage::from(Message {
-                server: "Client".into(),
-                channel: "Errors".into(),
+                id: MessageId::from(timestamp.to_string()),
+                server: ServerId::from(CLIENT_ID),
+                channel: ChannelId::from(ERRORS_ID),
                 contents: message,
-                timestamp: chrono::Utc::now().into(),
+                timestamp,
                 sender: "Client".into(),
                 reactions: Vec::new(),
+                thread: None,
+                broadcast: false,
+                reply_count: 0,
+                edited: false,
             }));
     }
 
     pub fn add_server(&mut self, mut server: Server) {
+        // Keep the messages we already have, either from before the server connected or from disk
+        let existing = self.server_index.get(&server.id).copied();
+        // And stay in whichever channel was open, wherever it ends up in the new list
+        let open_channel = existing
+            .and_then(|i| self.servers.get_index_mut(i))
+            .and_then(|s| s.channels.get(s.current_channel))
+            .map(|c| c.name.clone());
+        for channel in &mut server.channels {
+            let previous = existing
+                .and_then(|i| self.servers.get_index_mut(i))
+                .and_then(|s| s.channel_mut(&channel.id));
+            if let Some(previous) = previous {
+                channel.messages = std::mem::replace(&mut previous.messages, Vec::new());
+                channel.threads = std::mem::replace(&mut previous.threads, HashMap::new());
+                channel.input = std::mem::take(&mut previous.input);
+                channel.synced_through = previous.synced_through.take();
+            } else if let Some(store) = self.store_for(&server.id) {
+                let (messages, synced_through) = store.messages(&server.id, &channel.id);
+                channel.insert_messages(messages.into_iter().map(ChanMessage::from));
+                channel.synced_through = synced_through;
+            }
+            // Who we are may only have become known now
+            for message in channel
+                .messages
+                .iter_mut()
+                .chain(channel.threads.values_mut().flatten())
+            {
+                let mentions = mentions_us(
+                    message.sender(),
+                    &message.raw,
+                    server.my_name.as_deref(),
+                    &self.keywords,
+                );
+                message.set_mentions_us(mentions);
+            }
+        }
+
         server.channels.sort_by(|c1, c2| c1.name.cmp(&c2.name));
         server
             .channels
             .sort_by_key(|c| c.channel_type == ChannelType::DirectMessage);
 
+        server.reindex_channels();
+
         server.current_channel = server
             .channels
             .iter()
-            .position(|c| c.name == "general")
+            .position(|c| Some(&c.name) == open_channel.as_ref())
+            .or_else(|| server.channels.iter().position(|c| c.name == "general"))
             .unwrap_or(0);
 
-        self.servers.push(server);
+        match existing.and_then(|i| self.servers.get_index_mut(i)) {
+            Some(previous) => *previous = server,
+            None => self.servers.push(server),
+        }
 
         self.longest_channel_name = self
             .servers
@@ -319,50 +910,96 @@ impl Tui {
             .unwrap_or(0) as u16
             + 1;
 
-        let previous_server_name = self.servers.get().name.clone();
+        let previous_server = self.servers.get().id.clone();
         self.servers.sort_by_key(|s| s.name.clone());
         // TODO properly pin the client tab to the far-left position
         // This is a temporary hack, and may suggest that instead of a CursorVec I should have
         // functions like current_server() as I have current_channel()
-        self.servers.sort_by_key(|s| &s.name != "Client");
-        while self.servers.get().name != previous_server_name {
+        self.servers.sort_by_key(|s| s.id.as_str() != CLIENT_ID);
+        while self.servers.get().id != previous_server {
             self.servers.next();
         }
-    }
 
-    fn add_message(&mut self, message: Message) {
-        let channel = match self
+        self.server_index = self
             .servers
-            .iter_mut()
-            .find(|s| s.name == message.server)
-            .or_else(|| {
-                error!("Unable to add message, no server named {}", message.server);
-                None
-            })
-            .and_then(|server| {
-                server
-                    .channels
-                    .iter_mut()
-                    .find(|c| c.name == message.channel)
-            }) {
+            .iter()
+            .enumerate()
+            .map(|(i, s)| (s.id.clone(), i))
+            .collect();
+    }
+
+    /// Pass a new message on to the notifier, unless it's already in front of us
+    fn notify_about(&mut self, message: &Message, mentions_us: bool) {
+        let in_view = self.focused
+            && self.servers.get().id == message.server
+            && self.current_channel().id == message.channel;
+        if in_view || message.server.as_str() == CLIENT_ID {
+            return;
+        }
+        let server = match self.server_mut(&message.server) {
+            Some(s) => s,
+            None => return,
+        };
+        if server.my_name.as_ref() == Some(&message.sender) {
+            return;
+        }
+        let server_name = server.name.clone();
+        let (channel_name, direct) = match server.channel_mut(&message.channel) {
+            Some(c) => (c.name.clone(), c.channel_type == ChannelType::DirectMessage),
+            None => return,
+        };
+        let due = self.notifier.notify(&Notification {
+            server: &server_name,
+            channel: &channel_name,
+            sender: &message.sender,
+            text: &message.contents,
+            direct,
+            mentions_us,
+        });
+        if let Some(due) = due {
+            let mut sender = self.sender.clone();
+            tokio::spawn(async move {
+                tokio::time::delay_until(tokio::time::Instant::from_std(due)).await;
+                let _ = sender.send(ConnEvent::NotificationsDue).await;
+            });
+        }
+    }
+
+    fn add_message(&mut self, message: Message, mentions_us: bool) {
+        let channel = match self.channel_mut(&message.server, &message.channel) {
             Some(c) => c,
             None => {
                 error!(
-                    "Unable to add message, no channel named {} in server {}",
+                    "Unable to add message, no channel {} in server {}",
                     message.channel, message.server
                 );
                 return;
             }
         };
 
+        let mut message = ChanMessage::from(message);
+        message.set_mentions_us(mentions_us);
+        // Replies go in their thread, and only show up in the channel if they were broadcast
+        if let Some(thread) = message.thread().cloned() {
+            channel
+                .messages_with_id_mut(&thread)
+                .for_each(ChanMessage::add_reply);
+            let replies = channel.threads.entry(thread).or_default();
+            replies.push(message.clone());
+            replies.sort_unstable_by(|m1, m2| m1.timestamp().cmp(&m2.timestamp()));
+            if !message.broadcast() {
+                return;
+            }
+        }
+
         let needs_sort = channel
             .messages
             .last()
             .map(|m| *m.timestamp())
-            .unwrap_or(message.timestamp)
-            > message.timestamp;
+            .unwrap_or(*message.timestamp())
+            > *message.timestamp();
 
-        channel.messages.push(message.into());
+        channel.messages.push(message);
 
         if needs_sort {
             channel
@@ -376,31 +1013,237 @@ impl Tui {
             .unwrap_or(channel.latest);
     }
 
+    /// The most recent message we sent in the current channel, or thread if one is open
+    fn my_last_message(&self) -> Option<&ChanMessage> {
+        let my_name = self.servers.get().my_name.as_ref()?;
+        self.current_channel()
+            .shown_messages()
+            .iter()
+            .rev()
+            .find(|m| m.sender() == my_name)
+    }
+
+    /// Which message the input line is editing, if it's one in the current channel
+    fn editing_current(&self) -> Option<&MessageId> {
+        self.target_in_current(&self.editing)
+    }
+
+    fn target_in_current<'a>(
+        &self,
+        target: &'a Option<(ServerId, ChannelId, MessageId)>,
+    ) -> Option<&'a MessageId> {
+        match target {
+            Some((server, channel, id))
+                if *server == self.servers.get().id && *channel == self.current_channel().id =>
+            {
+                Some(id)
+            }
+            _ => None,
+        }
+    }
+
+    async fn edit_message(&mut self, id: MessageId, contents: String) {
+        if !self.servers.get().capabilities.edits {
+            self.add_client_message(format!(
+                "{} doesn't support editing messages",
+                self.servers.get().name
+            ));
+            return;
+        }
+        let server = self.servers.get().id.clone();
+        let channel = self.current_channel().id.clone();
+        self.servers
+            .get_mut()
+            .sender
+            .send(TuiEvent::EditMessage {
+                server,
+                channel,
+                id,
+                contents,
+            })
+            .await
+            .unwrap();
+    }
+
+    /// Ask whether to delete a message, if it's one of ours
+    fn confirm_delete(&mut self, id: MessageId) {
+        let my_name = self.servers.get().my_name.as_ref();
+        let mine = self
+            .current_channel()
+            .shown_messages()
+            .iter()
+            .any(|m| m.id() == &id && Some(m.sender()) == my_name.map(String::as_str));
+        if !self.servers.get().capabilities.deletes {
+            self.add_client_message(format!(
+                "{} doesn't support deleting messages",
+                self.servers.get().name
+            ));
+        } else if mine {
+            self.pending_delete = Some(id);
+        } else {
+            self.add_client_message("You can only delete your own messages".to_string());
+        }
+    }
+
+    /// Do whatever a key means for the selected message, returning false if it means nothing
+    async fn act_on_selection(&mut self, key: char) -> bool {
+        let search_result = self.current_channel().channel_type == ChannelType::Search;
+        let (id, thread, raw, mine) = match self.selection.as_ref().and_then(|id| {
+            self.current_channel()
+                .shown_messages()
+                .iter()
+                .find(|m| m.id() == id)
+        }) {
+            Some(m) => (
+                m.id().clone(),
+                m.thread().cloned(),
+                m.raw.clone(),
+                Some(m.sender()) == self.servers.get().my_name.as_deref(),
+            ),
+            None => return false,
+        };
+        let server = self.servers.get().id.clone();
+        let channel = self.current_channel().id.clone();
+        let capabilities = self.servers.get().capabilities;
+        let name = self.servers.get().name.clone();
+        match key {
+            // A search result is a copy, the message itself is in another channel
+            'r' | 't' | 'e' | 'd' | 'y' if search_result => {
+                self.add_client_message(
+                    "That's a search result, find the message in its channel to do that"
+                        .to_string(),
+                );
+            }
+            'r' if !capabilities.reactions => {
+                self.add_client_message(format!("{} doesn't support reactions", name));
+            }
+            'r' => {
+                self.reacting = Some((server, channel, id));
+                self.current_channel_mut().input.set_text("+:".to_string());
+                self.selection = None;
+            }
+            't' if !capabilities.threads => {
+                self.add_client_message(format!("{} doesn't support threads", name));
+            }
+            't' => {
+                self.selection = None;
+                self.open_thread(thread.unwrap_or(id)).await;
+            }
+            'e' if !mine => {
+                self.add_client_message("You can only edit your own messages".to_string());
+            }
+            'e' if !capabilities.edits => {
+                self.add_client_message(format!("{} doesn't support editing messages", name));
+            }
+            'e' => {
+                self.editing = Some((server, channel, id));
+                self.current_channel_mut().input.set_text(raw);
+                self.selection = None;
+            }
+            'd' => self.confirm_delete(id),
+            'y' => {
+                if copy_to_clipboard(raw.as_bytes()) {
+                    self.add_client_message("Copied the message".to_string());
+                }
+            }
+            'Y' if !capabilities.permalinks => {
+                self.add_client_message(format!("{} doesn't support links to messages", name));
+            }
+            'Y' => {
+                self.servers
+                    .get_mut()
+                    .sender
+                    .send(TuiEvent::GetPermalink {
+                        server,
+                        channel,
+                        id,
+                    })
+                    .await
+                    .unwrap();
+            }
+            'o' => {
+                let urls = find_urls(&raw);
+                if urls.is_empty() {
+                    self.add_client_message("That message doesn't have any links".to_string());
+                }
+                for url in urls {
+                    let _ = std::process::Command::new("xdg-open")
+                        .arg(url)
+                        .stdout(std::process::Stdio::null())
+                        .stderr(std::process::Stdio::null())
+                        .spawn()
+                        .map_err(|e| error!("{:#?}", e));
+                }
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    /// Move the selection towards older messages, or newer ones, starting from the newest
+    fn move_selection(&mut self, older: bool) {
+        let messages = self.current_channel().shown_messages();
+        let position = self
+            .selection
+            .as_ref()
+            .and_then(|id| messages.iter().rposition(|m| m.id() == id));
+        let index = match position {
+            None => messages.len().checked_sub(1),
+            Some(p) if older => Some(p.saturating_sub(1)),
+            Some(p) => Some(min(p + 1, messages.len() - 1)),
+        };
+        self.selection = index.map(|i| messages[i].id().clone());
+    }
+
     async fn send_message(&mut self) {
-        let contents = self.current_channel().message_buffer.clone();
-        self.current_channel_mut().message_buffer.clear();
+        let contents = self.current_channel_mut().input.take();
         if self.servers.tell() == 0 && !contents.starts_with('/') {
             self.add_client_message(contents);
             return;
         }
-        let current_server_name = self.servers.get().name.clone();
-        let current_channel_name = self.current_channel().name.clone();
+        if let Some(id) = self.editing_current().cloned() {
+            self.editing = None;
+            self.edit_message(id, contents).await;
+            return;
+        }
+        let current_server_id = self.servers.get().id.clone();
+        let current_channel_id = self.current_channel().id.clone();
+        let reacting = self.target_in_current(&self.reacting).cloned();
+        self.reacting = None;
         if contents.starts_with("+:") {
-            if let Some(ts) = self
-                .current_channel()
-                .messages
-                .last()
-                .map(|m| *m.timestamp())
+            let reaction = match contents
+                .strip_prefix("+:")
+                .and_then(|r| r.strip_suffix(':'))
+                .filter(|r| !r.is_empty())
             {
-                let reaction = &contents[2..contents.len() - 1];
+                Some(reaction) => reaction,
+                None => {
+                    self.add_client_message(format!(
+                        "Reactions look like +:emoji:, not {}",
+                        contents
+                    ));
+                    return;
+                }
+            };
+            if !self.servers.get().capabilities.reactions {
+                self.add_client_message(format!(
+                    "{} doesn't support reactions",
+                    self.servers.get().name
+                ));
+            } else if let Some(id) = reacting.or_else(|| {
+                self.current_channel()
+                    .shown_messages()
+                    .last()
+                    .map(|m| m.id().clone())
+            }) {
                 self.servers
                     .get_mut()
                     .sender
                     .send(TuiEvent::AddReaction {
                         reaction: reaction.into(),
-                        server: current_server_name,
-                        channel: current_channel_name,
-                        timestamp: ts,
+                        server: current_server_id,
+                        channel: current_channel_id,
+                        id,
                     })
                     .await
                     .unwrap()
@@ -410,39 +1253,126 @@ impl Tui {
                         .to_string(),
                 );
             }
+        } else if let Some(query) = command_argument(&contents, "/search") {
+            if query.trim().is_empty() {
+                self.add_client_message("Usage: /search <words to look for>".to_string());
+                return;
+            }
+            self.search(query);
+            // Some servers can also find what we haven't loaded
+            if self.servers.get().capabilities.search {
+                self.servers
+                    .get_mut()
+                    .sender
+                    .send(TuiEvent::Command {
+                        server: current_server_id,
+                        channel: current_channel_id,
+                        command: format!("search {}", query),
+                    })
+                    .await
+                    .unwrap();
+            }
         } else if contents == "/mark" || contents == "/m" {
             // Mark current channel as read
             self.reset_current_unreads().await;
-        } else if contents.starts_with("/c") {
-            // Find and switch to the specified channel
-            if let Some(requested_channel) = contents.splitn(2, ' ').nth(1) {
-                if let Some(index) = self
-                    .servers
-                    .get()
-                    .channels
-                    .iter()
-                    .position(|c| c.name == requested_channel)
-                {
-                    self.reset_current_unreads().await;
+        } else if contents == "/thread" {
+            // Open the thread of the most recent message, or start one
+            if !self.servers.get().capabilities.threads {
+                self.add_client_message(format!(
+                    "{} doesn't support threads",
+                    self.servers.get().name
+                ));
+            } else if let Some(thread) = self
+                .current_channel()
+                .messages
+                .last()
+                .map(|m| m.thread().unwrap_or_else(|| m.id()).clone())
+            {
+                self.open_thread(thread).await;
+            } else {
+                self.add_client_message(
+                    "Can't open a thread if there are no messages in this channel!".to_string(),
+                );
+            }
+        } else if let Some(new_contents) = command_argument(&contents, "/edit") {
+            // Replace the contents of our most recent message
+            if new_contents.trim().is_empty() {
+                self.add_client_message("Usage: /edit <new message>".to_string());
+                return;
+            }
+            match self.my_last_message().map(|m| m.id().clone()) {
+                Some(id) => self.edit_message(id, new_contents.to_string()).await,
+                None => self
+                    .add_client_message("You haven't sent any messages here to edit".to_string()),
+            }
+        } else if contents == "/delete" {
+            // Delete our most recent message, after asking first
+            match self.my_last_message().map(|m| m.id().clone()) {
+                Some(id) => self.confirm_delete(id),
+                None => self
+                    .add_client_message("You haven't sent any messages here to delete".to_string()),
+            }
+        } else if let Some(draft) = command_argument(&contents, "/compose") {
+            // Write the message in an editor, starting from whatever came after the command
+            self.compose(draft.to_string()).await;
+        } else if let Some((old, new)) = parse_substitution(&contents) {
+            // s/old/new/ replaces the first occurrence of old in our most recent message
+            match self
+                .my_last_message()
+                .map(|m| (m.id().clone(), m.raw.clone()))
+            {
+                Some((id, raw)) if raw.contains(old) => {
+                    self.edit_message(id, raw.replacen(old, new, 1)).await
+                }
+                Some(_) => {
+                    self.add_client_message(format!("Couldn't find {} in your last message", old))
+                }
+                None => self
+                    .add_client_message("You haven't sent any messages here to edit".to_string()),
+            }
+        } else if let (Some(text), Some(thread)) = (
+            contents.strip_prefix("/broadcast "),
+            self.current_channel().open_thread.clone(),
+        ) {
+            // Reply in the open thread and send the reply to the channel too
+            self.servers
+                .get_mut()
+                .sender
+                .send(TuiEvent::SendMessage {
+                    server: current_server_id,
+                    channel: current_channel_id,
+                    contents: text.to_string(),
+                    thread: Some(thread),
+                    broadcast: true,
+                })
+                .await
+                .unwrap();
+        } else if let Some(requested_channel) = command_argument(&contents, "/c") {
+            // Find and switch to the specified channel
+            if !requested_channel.is_empty() {
+                if let Some(index) = self
+                    .servers
+                    .get()
+                    .channels
+                    .iter()
+                    .position(|c| c.name == requested_channel)
+                {
+                    self.reset_current_unreads().await;
                     self.servers.get_mut().current_channel = index;
-                    self.cursor_pos =
-                        min(self.cursor_pos, self.current_channel().message_buffer.len());
                     self.update_history().await;
                 } else {
                     error!("unknown channel {}", requested_channel);
                 }
             }
-        } else if contents.starts_with("/s") {
+        } else if let Some(requested_server) = command_argument(&contents, "/s") {
             // Find and switch to a server
-            if let Some(requested_server) = contents.splitn(2, ' ').nth(1) {
+            if !requested_server.is_empty() {
                 let index = self.servers.iter().position(|s| s.name == requested_server);
                 if let Some(index) = index {
                     self.reset_current_unreads().await;
                     while self.servers.tell() != index {
                         self.servers.next();
                     }
-                    self.cursor_pos =
-                        min(self.cursor_pos, self.current_channel().message_buffer.len());
                     self.update_history().await;
                 } else {
                     error!("unknown server {}", requested_server);
@@ -451,8 +1381,6 @@ impl Tui {
         } else if contents == "/url" {
             // The /url command searches for a URL mentioned in the current channel and
             // copies it to the clipboard if one is found
-            use std::io::Write;
-            use std::process::{Command, Stdio};
             if let Some(mut url) = self
                 .current_channel()
                 .messages
@@ -464,81 +1392,291 @@ impl Tui {
                 if url.ends_with(&[b'>']) {
                     url = &url[..url.len() - 1];
                 }
-                let _ = Command::new("xclip")
-                    .arg("-selection")
-                    .arg("clipboard")
-                    .stdin(Stdio::piped())
-                    .spawn()
-                    .and_then(|mut child| child.stdin.as_mut().unwrap().write_all(url))
-                    .map_err(|e| error!("{:#?}", e));
+                copy_to_clipboard(url);
             }
         } else if contents.starts_with('/') {
+            if !self.servers.get().capabilities.commands {
+                self.add_client_message(format!(
+                    "{} doesn't support commands",
+                    self.servers.get().name
+                ));
+                return;
+            }
             self.servers
                 .get_mut()
                 .sender
                 .send(TuiEvent::Command {
-                    server: current_server_name,
-                    channel: current_channel_name,
+                    server: current_server_id,
+                    channel: current_channel_id,
                     command: String::from(&contents[1..]),
                 })
                 .await
                 .unwrap();
         } else {
+            let thread = self.current_channel().open_thread.clone();
             self.servers
                 .get_mut()
                 .sender
                 .send(TuiEvent::SendMessage {
-                    server: current_server_name,
-                    channel: current_channel_name,
+                    server: current_server_id,
+                    channel: current_channel_id,
                     contents,
+                    thread,
+                    broadcast: false,
                 })
                 .await
                 .unwrap();
         }
     }
 
+    /// Show a thread in place of the current channel, and fetch all of its replies
+    async fn open_thread(&mut self, thread: MessageId) {
+        let channel = self.current_channel_mut();
+        // The message that started the thread goes at the top, until the replies arrive with it
+        let parent = channel
+            .messages
+            .iter()
+            .rev()
+            .find(|m| m.id() == &thread)
+            .cloned();
+        let replies = channel.threads.entry(thread.clone()).or_default();
+        if let Some(parent) = parent {
+            if !replies.iter().any(|m| m.id() == &thread) {
+                replies.insert(0, parent);
+            }
+        }
+        channel.open_thread = Some(thread.clone());
+        channel.message_scroll_offset = 0;
+
+        let channel = channel.id.clone();
+        self.servers
+            .get_mut()
+            .sender
+            .send(TuiEvent::GetThread { channel, thread })
+            .await
+            .unwrap();
+    }
+
+    /// Look through every message we have, loaded or from the store, and open the results pane
+    fn search(&mut self, query: &str) {
+        let parsed = match crate::search::Query::parse(query) {
+            Ok(q) => q,
+            Err(e) => {
+                self.add_client_message(format!("Invalid search: {}", e));
+                return;
+            }
+        };
+
+        let mut results = Vec::new();
+        for server in self.servers.iter().filter(|s| s.id.as_str() != CLIENT_ID) {
+            // Server search results are copies of messages we'd otherwise find twice
+            let channels = server
+                .channels
+                .iter()
+                .filter(|c| c.channel_type != ChannelType::Search);
+            for channel in channels {
+                for message in channel
+                    .messages
+                    .iter()
+                    .filter(|m| parsed.matches(&channel.name, m))
+                {
+                    let timestamp = *message.timestamp();
+                    let summary = format!(
+                        "{} #{} {} {}: {}",
+                        server.name,
+                        channel.name,
+                        timestamp
+                            .as_chrono()
+                            .with_timezone(&chrono::Local)
+                            .format("%Y-%m-%d %H:%M"),
+                        message.sender(),
+                        message.raw.lines().next().unwrap_or_default(),
+                    );
+                    results.push(SearchResult {
+                        server: server.id.clone(),
+                        channel: channel.id.clone(),
+                        id: message.id().clone(),
+                        timestamp,
+                        summary,
+                    });
+                }
+            }
+        }
+        // Newest first
+        results.sort_by(|r1, r2| r2.timestamp.cmp(&r1.timestamp));
+
+        self.search = Some(SearchResults {
+            query: query.to_string(),
+            results,
+            selected: 0,
+        });
+    }
+
+    /// Switch to the channel a search result is in, scrolled so that the message is visible
+    async fn jump_to_search_result(&mut self) {
+        let search = match self.search.take() {
+            Some(s) => s,
+            None => return,
+        };
+        let result = match search.results.get(search.selected) {
+            Some(r) => r,
+            None => return,
+        };
+        let server_index = match self.server_index.get(&result.server) {
+            Some(i) => *i,
+            None => {
+                error!("Search result is in an unknown server {}", result.server);
+                return;
+            }
+        };
+        let channel_index = match self
+            .servers
+            .get_index_mut(server_index)
+            .and_then(|s| s.channel_index.get(&result.channel).copied())
+        {
+            Some(i) => i,
+            None => {
+                error!(
+                    "Search result is in an unknown channel {} in server {}",
+                    result.channel, result.server
+                );
+                return;
+            }
+        };
+
+        self.reset_current_unreads().await;
+        while self.servers.tell() != server_index {
+            self.servers.next();
+        }
+        self.servers.get_mut().current_channel = channel_index;
+        self.current_channel_mut().open_thread = None;
+
+        // Scroll past everything that was sent after the message, as it would be drawn
+        let width = termion::terminal_size()
+            .map(|(columns, _)| columns.saturating_sub(CHAN_WIDTH) as usize)
+            .unwrap_or(80);
+        let channel = self.current_channel_mut();
+        if let Some(index) = channel.messages.iter().rposition(|m| m.id() == &result.id) {
+            channel.message_scroll_offset = channel.messages[index + 1..]
+                .iter_mut()
+                .map(|m| m.formatted_to(width).lines().count())
+                .sum();
+        }
+        self.update_history().await;
+    }
+
     fn draw(&mut self, master: &mut crate::curses::Screen) {
-        use crate::curses::Screen;
-        use termion::color::AnsiValue;
+        use crate::curses::{display_width, pad_to_width, truncate_to_width, Screen, Style};
 
+        let theme = &self.theme;
         let mut new = Screen::new();
 
         for r in 1..new.rows() + 1 {
-            new.set_str(
-                r,
-                CHAN_WIDTH,
-                AnsiValue::rgb(5, 5, 5),
-                AnsiValue::rgb(0, 0, 0),
-                false,
-                "|",
-            );
+            new.set_str(r, CHAN_WIDTH, theme.separator, theme.background, false, "|");
         }
 
         // Draw the message input area
         // We need this message area height to render the channel messages
-        // TODO: This shouldn't be .chars().count(), we want to count grapheme clusters
         let remaining_width = (new.columns() - CHAN_WIDTH) as usize;
-        new.set_str(
-            new.rows(),
-            CHAN_WIDTH + 1,
-            AnsiValue::rgb(5, 5, 5),
-            AnsiValue::rgb(0, 0, 0),
-            false,
-            self.current_channel().message_buffer.as_str(),
+        // Prompts and hints take over the input line
+        let prompt = if self.pending_delete.is_some() {
+            Some(("Delete this message? (y/n)", theme.alert))
+        } else if self.selection.is_some() {
+            Some((
+                "j/k move, r react, t thread, e edit, d delete, y copy, Y copy link, o open links, Esc or Enter stop",
+                theme.timestamp,
+            ))
+        } else {
+            None
+        };
+        // The input grows upwards as it gets longer, scrolling to keep the cursor in view
+        let input = &self.current_channel().input;
+        let rows = input_rows(input.text(), remaining_width);
+        let cursor_row = rows
+            .iter()
+            .rposition(|(start, _)| *start <= input.cursor())
+            .unwrap_or_default();
+        let shown = if prompt.is_some() {
+            1
+        } else {
+            min(rows.len(), min(MAX_INPUT_ROWS, new.rows() as usize / 2))
+        };
+        let first = (cursor_row + 1).saturating_sub(shown);
+        let input_top = new.rows() + 1 - shown as u16;
+        let (cursor_start, _) = rows[cursor_row];
+        let cursor = termion::cursor::Goto(
+            CHAN_WIDTH + 1 + display_width(&input.text()[cursor_start..input.cursor()]) as u16,
+            input_top + (cursor_row - first) as u16,
         );
-        let message_area_height = new.rows();
+        if let Some((prompt, color)) = prompt {
+            new.set_str(
+                new.rows(),
+                CHAN_WIDTH + 1,
+                color,
+                theme.background,
+                true,
+                &pad_to_width(prompt, remaining_width),
+            );
+        } else {
+            // Make it obvious that sending will replace a message instead
+            let color = if self.editing_current().is_some() {
+                theme.editing
+            } else {
+                theme.text
+            };
+            for (i, (_, row)) in rows.iter().skip(first).take(shown).enumerate() {
+                new.set_str(
+                    input_top + i as u16,
+                    CHAN_WIDTH + 1,
+                    color,
+                    theme.background,
+                    false,
+                    row,
+                );
+            }
+        }
+        let message_area_height = input_top;
 
         // Draw all the messages by looping over them in reverse
+        // A thread replaces the channel, leaving a row at the top to say so
+        let in_thread = self.current_channel().open_thread.is_some();
+        let top_row = if in_thread { 2 } else { 1 };
+
+        // Scroll so that the whole selected message is visible
+        let selection = self.selection.clone();
+        if let Some(selected) = &selection {
+            let messages = self.current_channel_mut().shown_messages_mut();
+            if let Some(index) = messages.iter().rposition(|m| m.id() == selected) {
+                let below: usize = messages[index + 1..]
+                    .iter_mut()
+                    .map(|m| m.formatted_to(remaining_width).lines().count())
+                    .sum();
+                let height = messages[index]
+                    .formatted_to(remaining_width)
+                    .lines()
+                    .count();
+                let visible = message_area_height.saturating_sub(1 + top_row) as usize;
+                let channel = self.current_channel_mut();
+                channel.message_scroll_offset = min(
+                    max(
+                        channel.message_scroll_offset,
+                        (below + height).saturating_sub(visible),
+                    ),
+                    below,
+                );
+            }
+        }
         let num_unreads = self.current_channel().num_unreads();
-        let mut draw_unread_marker = self.current_channel().is_unread();
+        let mut draw_unread_marker = !in_thread && self.current_channel().is_unread();
 
         let offset = self.current_channel().message_scroll_offset;
 
         let mut row = message_area_height - 1;
         let mut skipped = 0;
-        'outer: for (m, message) in self
-            .current_channel_mut()
-            .messages
+        let server = self.servers.get_mut();
+        let theme = &self.theme;
+        'outer: for (m, message) in server.channels[server.current_channel]
+            .shown_messages_mut()
             .iter_mut()
             .rev()
             .enumerate()
@@ -548,8 +1686,8 @@ impl Tui {
                 new.set_str(
                     row,
                     CHAN_WIDTH + 1,
-                    AnsiValue::rgb(5, 0, 0),
-                    AnsiValue::grayscale(0),
+                    theme.unread_marker,
+                    theme.background,
                     false,
                     std::iter::repeat('-')
                         .take(remaining_width)
@@ -559,43 +1697,45 @@ impl Tui {
 
                 row -= 1;
                 draw_unread_marker = false;
-                if row == 1 {
+                if row == top_row {
                     break 'outer;
                 }
             }
 
+            let selected = selection.as_ref() == Some(message.id());
+            let style = |foreground| {
+                if selected {
+                    theme.highlighted(foreground, theme.highlight)
+                } else {
+                    Style::new(foreground, theme.background)
+                }
+            };
             for line in message.formatted_to(remaining_width).lines().rev() {
                 if skipped < offset {
                     skipped += 1;
                     continue;
                 }
-                new.set_str(
+                new.set_styled(
                     row,
                     CHAN_WIDTH + 1,
-                    AnsiValue::rgb(5, 5, 5),
-                    AnsiValue::rgb(0, 0, 0),
-                    false,
-                    line,
+                    &style(theme.text),
+                    &pad_to_width(line, remaining_width),
                 );
                 row -= 1;
-                if row == 1 {
+                if row == top_row {
                     break 'outer;
                 }
             }
-            new.set_str(
+            new.set_styled(
                 row + 1,
                 CHAN_WIDTH + 1,
-                AnsiValue::grayscale(8),
-                AnsiValue::rgb(0, 0, 0),
-                false,
+                &style(theme.timestamp),
                 message.formatted_to(remaining_width).split_at(7).0,
             );
-            new.set_str(
+            new.set_styled(
                 row + 1,
                 CHAN_WIDTH + 1 + 7 + 1,
-                message.color(),
-                AnsiValue::rgb(0, 0, 0),
-                false,
+                &style(theme.nick_color(message.sender())),
                 message.sender(),
             );
         }
@@ -605,8 +1745,8 @@ impl Tui {
             new.set_str(
                 max(2, row),
                 CHAN_WIDTH + 1,
-                AnsiValue::rgb(5, 0, 0),
-                AnsiValue::grayscale(0),
+                self.theme.unread_marker,
+                self.theme.background,
                 false,
                 std::iter::repeat('-')
                     .take(remaining_width)
@@ -615,6 +1755,21 @@ impl Tui {
             );
         }
 
+        if in_thread {
+            let header = format!(
+                " Thread in #{} (Esc to go back, /broadcast to also send to the channel)",
+                self.current_channel().name
+            );
+            new.set_str(
+                2,
+                CHAN_WIDTH + 1,
+                self.theme.background,
+                self.theme.dim,
+                true,
+                &pad_to_width(&header, remaining_width),
+            );
+        }
+
         let num_servers = self.servers.len();
         let mut current_col = CHAN_WIDTH + 1;
         for (s, server) in self
@@ -623,41 +1778,67 @@ impl Tui {
             .enumerate()
             .skip(self.server_scroll_offset)
         {
-            if s == self.servers.tell() {
+            let mut label = if server.connected {
+                server.name.clone()
+            } else {
+                format!("{} (reconnecting)", server.name)
+            };
+            let mentions = server.num_mentions();
+            if mentions > 0 {
+                label.push_str(&format!(" @{}", mentions));
+            }
+            current_col += if s == self.servers.tell() {
                 new.set_str(
                     1,
                     current_col,
-                    AnsiValue::rgb(5, 5, 5),
-                    AnsiValue::rgb(0, 0, 0),
+                    self.theme.current,
+                    self.theme.background,
                     true,
-                    &server.name,
-                );
+                    &label,
+                )
+            } else if !server.connected {
+                new.set_str(
+                    1,
+                    current_col,
+                    self.theme.timestamp,
+                    self.theme.background,
+                    false,
+                    &label,
+                )
+            } else if mentions > 0 {
+                new.set_str(
+                    1,
+                    current_col,
+                    self.theme.mention,
+                    self.theme.background,
+                    true,
+                    &label,
+                )
             } else if server.has_unreads() {
                 new.set_str(
                     1,
                     current_col,
-                    AnsiValue::rgb(5, 0, 0),
-                    AnsiValue::rgb(0, 0, 0),
+                    self.theme.unread,
+                    self.theme.background,
                     false,
-                    &server.name,
-                );
+                    &label,
+                )
             } else {
                 new.set_str(
                     1,
                     current_col,
-                    AnsiValue::rgb(3, 3, 3),
-                    AnsiValue::rgb(0, 0, 0),
+                    self.theme.dim,
+                    self.theme.background,
                     false,
-                    &server.name,
-                );
-            }
-            current_col += server.name.chars().count() as u16;
+                    &label,
+                )
+            };
             if s != num_servers - 1 {
                 new.set_str(
                     1,
                     current_col,
-                    AnsiValue::rgb(5, 5, 5),
-                    AnsiValue::rgb(0, 0, 0),
+                    self.theme.separator,
+                    self.theme.background,
                     true,
                     " • ",
                 );
@@ -676,10 +1857,11 @@ impl Tui {
 
         fn write_shortened_name(f: &mut String, name: &str, max_len: usize) {
             use std::fmt::Write;
-            if name.chars().count() < max_len {
+            if display_width(name) < max_len {
                 let _ = write!(f, "{}", name);
             } else {
-                f.extend(name.chars().take(max_len - 4).chain("...".chars()));
+                f.push_str(truncate_to_width(name, max_len - 4));
+                f.push_str("...");
             }
         }
 
@@ -691,59 +1873,188 @@ impl Tui {
             .skip(server.channel_scroll_offset)
             .take(new.rows() as usize)
         {
+            // Unread counts go on the right, with how many of them mention us in front
+            let unreads = channel.num_unreads();
+            let mentions = channel.num_mentions();
+            let count = if c == server.current_channel || unreads == 0 {
+                String::new()
+            } else if mentions > 0 {
+                format!(" @{} {}", mentions, unreads)
+            } else {
+                format!(" {}", unreads)
+            };
             short_name.clear();
-            write_shortened_name(&mut short_name, &channel.name, CHAN_WIDTH as usize);
+            write_shortened_name(
+                &mut short_name,
+                &channel.name,
+                CHAN_WIDTH as usize - display_width(&count),
+            );
             let draw_at = (c - server.channel_scroll_offset) as u16
                 + 1
                 + ((channel.channel_type == ChannelType::DirectMessage) as u16);
+            let count_col = CHAN_WIDTH - display_width(&count) as u16;
             // Skip a row if we're transitioning from the normal to DM channels
             if c == server.current_channel {
                 new.set_str(
                     draw_at,
                     1,
-                    AnsiValue::rgb(5, 5, 5),
-                    AnsiValue::rgb(0, 0, 0),
+                    self.theme.current,
+                    self.theme.background,
+                    true,
+                    &short_name,
+                );
+            } else if mentions > 0 {
+                new.set_str(
+                    draw_at,
+                    1,
+                    self.theme.mention,
+                    self.theme.background,
                     true,
                     &short_name,
                 );
+                new.set_str(
+                    draw_at,
+                    count_col,
+                    self.theme.mention,
+                    self.theme.background,
+                    true,
+                    &count,
+                );
             } else if channel.is_unread() {
                 new.set_str(
                     draw_at,
                     1,
-                    AnsiValue::rgb(5, 0, 0),
-                    AnsiValue::rgb(0, 0, 0),
+                    self.theme.unread,
+                    self.theme.background,
                     true,
                     &short_name,
                 );
+                new.set_str(
+                    draw_at,
+                    count_col,
+                    self.theme.unread,
+                    self.theme.background,
+                    false,
+                    &count,
+                );
             } else {
                 new.set_str(
                     draw_at,
                     1,
-                    AnsiValue::rgb(3, 3, 3),
-                    AnsiValue::rgb(0, 0, 0),
+                    self.theme.dim,
+                    self.theme.background,
                     false,
                     &short_name,
                 );
             }
         }
 
+        // Draw the search results over the messages
+        if let Some(search) = &self.search {
+            let pad = |text: &str| pad_to_width(text, remaining_width);
+            new.set_str(
+                2,
+                CHAN_WIDTH + 1,
+                self.theme.background,
+                self.theme.dim,
+                true,
+                &pad(&format!(
+                    " {} results for \"{}\" (Enter to jump, Esc to close)",
+                    search.results.len(),
+                    search.query
+                )),
+            );
+            let shown = input_top.saturating_sub(3) as usize;
+            // Scroll the results so that the selected one is always visible
+            let first = (search.selected + 1).saturating_sub(shown);
+            for i in 0..shown {
+                let row = 3 + i as u16;
+                match search.results.get(first + i) {
+                    Some(result) if first + i == search.selected => new.set_styled(
+                        row,
+                        CHAN_WIDTH + 1,
+                        &Style {
+                            bold: true,
+                            ..self
+                                .theme
+                                .highlighted(self.theme.background, self.theme.text)
+                        },
+                        &pad(&result.summary),
+                    ),
+                    Some(result) => new.set_str(
+                        row,
+                        CHAN_WIDTH + 1,
+                        self.theme.text,
+                        self.theme.background,
+                        false,
+                        &pad(&result.summary),
+                    ),
+                    None => new.set_str(
+                        row,
+                        CHAN_WIDTH + 1,
+                        self.theme.text,
+                        self.theme.background,
+                        false,
+                        &pad(""),
+                    ),
+                };
+            }
+        }
+
+        // Draw the completion menu just above the input line
+        if !self.autocompletions.is_empty() {
+            let shown = min(self.autocompletions.len(), COMPLETION_MENU_HEIGHT);
+            // Scroll the menu so that the selected entry is always visible
+            let first = (self.autocomplete_index + 1).saturating_sub(shown);
+            let width = min(
+                self.autocompletions
+                    .iter()
+                    .map(|c| display_width(c))
+                    .max()
+                    .unwrap_or_default()
+                    + 2,
+                remaining_width,
+            );
+            for (i, completion) in self
+                .autocompletions
+                .iter()
+                .enumerate()
+                .skip(first)
+                .take(shown)
+            {
+                let entry = if display_width(completion) + 2 > width {
+                    let shortened = truncate_to_width(completion, width.saturating_sub(3));
+                    pad_to_width(&format!(" {}… ", shortened), width)
+                } else {
+                    pad_to_width(&format!(" {}", completion), width)
+                };
+                let style = if i == self.autocomplete_index {
+                    Style {
+                        bold: true,
+                        ..self
+                            .theme
+                            .highlighted(self.theme.background, self.theme.text)
+                    }
+                } else {
+                    Style::new(self.theme.text, self.theme.highlight)
+                };
+                new.set_styled(
+                    input_top - (shown - (i - first)) as u16,
+                    CHAN_WIDTH + 1,
+                    &style,
+                    &entry,
+                );
+            }
+        }
+
         let out = std::io::stdout();
         let mut lock = out.lock();
-        let mut diff = master.update_from(&new);
+        let default_style = Style::new(self.theme.text, self.theme.background);
+        let mut diff = master.update_from(&new, &default_style, self.theme.colors);
 
         {
             use std::fmt::Write;
-
-            let total_chars = self.current_channel().message_buffer.chars().count();
-            let _ = write!(
-                diff,
-                "{}",
-                termion::cursor::Goto(
-                    CHAN_WIDTH + 1 + (self.cursor_pos % remaining_width) as u16,
-                    new.rows() - (total_chars / remaining_width) as u16
-                        + (self.cursor_pos / remaining_width) as u16
-                )
-            );
+            let _ = write!(diff, "{}", cursor);
         }
         {
             use std::io::Write;
@@ -753,196 +2064,339 @@ impl Tui {
         }
     }
 
+    /// Everything the word under the cursor could be completed to
+    fn find_completions(&self) -> Vec<String> {
+        let word = self.current_channel().input.word_before_cursor();
+
+        if self.current_channel().input.text().starts_with("/c ") {
+            // Autocomplete from current server's channel names
+            self.servers
+                .get()
+                .channels
+                .iter()
+                .map(|c| c.name.to_string())
+                .filter(|name| name.starts_with(word))
+                .collect()
+        } else if self.current_channel().input.text().starts_with("/s ") {
+            // Autocomplete from available server names
+            self.servers
+                .iter()
+                .map(|s| s.name.to_string())
+                .filter(|name| name.starts_with(word))
+                .collect()
+        } else if self.current_channel().input.text().starts_with("/upload ") {
+            fn complete_from(argument: &str) -> Option<Vec<String>> {
+                use std::path::Path;
+
+                let current_dir = std::env::current_dir().unwrap();
+                let full_path = current_dir.join(Path::new(&argument));
+                let start_of_entry = full_path.file_name()?.to_str()?;
+                let dir_part = full_path.parent()?;
+
+                let mut output = Vec::new();
+                // Autocomplete from the path provided
+                for entry in std::fs::read_dir(dir_part).ok()? {
+                    let entry = entry.ok()?;
+                    let path = entry.path();
+                    if path.file_name()?.to_str()?.starts_with(start_of_entry) {
+                        let mut suggestion = path
+                            .strip_prefix(&current_dir)
+                            .unwrap()
+                            .to_str()?
+                            .to_string();
+                        if path.is_dir() {
+                            suggestion.push(std::path::MAIN_SEPARATOR);
+                        }
+                        output.push(suggestion);
+                    }
+                }
+                Some(output)
+            }
+
+            let mut completions = complete_from(word).unwrap_or_default();
+            completions.sort();
+            completions
+        } else {
+            self.servers
+                .get()
+                .completer
+                .as_ref()
+                .map(|c| c.snapshot().autocomplete(word))
+                .unwrap_or_default()
+        }
+    }
+
+    /// Narrow down an open completion menu to what still matches the word under the cursor, or
+    /// close it once that word is finished
+    fn refilter_completions(&mut self) {
+        if !self.autocompletions.is_empty() {
+            self.autocompletions = if self.current_channel().input.word_before_cursor().is_empty() {
+                Vec::new()
+            } else {
+                self.find_completions()
+            };
+            self.autocomplete_index = 0;
+        }
+    }
+
+    /// Replace the word under the cursor with the selected completion
+    fn accept_completion(&mut self) {
+        if let Some(chosen_completion) = self.autocompletions.get(self.autocomplete_index).cloned()
+        {
+            self.current_channel_mut()
+                .input
+                .replace_word(&chosen_completion);
+        }
+        self.autocompletions.clear();
+        self.autocomplete_index = 0;
+    }
+
+    async fn run_action(&mut self, action: Action) {
+        match action {
+            Action::Quit => {
+                error!("got shutdown request");
+                self.shutdown = true;
+            }
+            Action::NextServer => self.next_server().await,
+            Action::PreviousServer => self.previous_server().await,
+            Action::NextChannel => self.next_channel().await,
+            Action::PreviousChannel => self.previous_channel().await,
+            Action::NextUnread => self.next_channel_unread().await,
+            Action::PreviousUnread => self.previous_channel_unread().await,
+            Action::ScrollUp => self.current_channel_mut().message_scroll_offset += 1,
+            Action::ScrollDown => {
+                let chan = self.current_channel_mut();
+                chan.message_scroll_offset = chan.message_scroll_offset.saturating_sub(1);
+            }
+            Action::SelectMessages => self.move_selection(true),
+            Action::Compose => {
+                let draft = self.current_channel().input.text().to_string();
+                self.compose(draft).await;
+            }
+            Action::EditLast => {
+                if !self.current_channel().input.is_empty() {
+                    return;
+                }
+                if let Some((id, raw)) = self
+                    .my_last_message()
+                    .map(|m| (m.id().clone(), m.raw.clone()))
+                {
+                    self.editing = Some((
+                        self.servers.get().id.clone(),
+                        self.current_channel().id.clone(),
+                        id,
+                    ));
+                    self.current_channel_mut().input.set_text(raw);
+                }
+            }
+        }
+    }
+
     async fn handle_input(&mut self, event: &::termion::event::Event) {
         use termion::event::Event::*;
         use termion::event::Key::*;
+
+        // A confirmation takes the next key, anything but y is a no
+        if let Some(id) = self.pending_delete.take() {
+            if let Key(Char('y')) = *event {
+                let server = self.servers.get().id.clone();
+                let channel = self.current_channel().id.clone();
+                self.servers
+                    .get_mut()
+                    .sender
+                    .send(TuiEvent::DeleteMessage {
+                        server,
+                        channel,
+                        id,
+                    })
+                    .await
+                    .unwrap();
+            }
+            return;
+        }
+
+        // Selection mode keeps letters for itself, other keys leave it and work as usual
+        if self.selection.is_some() {
+            match *event {
+                Key(Char('k')) | Key(Up) => self.move_selection(true),
+                Key(Char('j')) | Key(Down) => self.move_selection(false),
+                // Enter sends whatever is typed, like it does outside of selection mode
+                Key(Char(c)) if c != '\n' => {
+                    self.act_on_selection(c).await;
+                }
+                Key(Esc) => self.selection = None,
+                _ => {
+                    self.selection = None;
+                    self.handle_unselected_input(event).await;
+                }
+            }
+            return;
+        }
+
+        self.handle_unselected_input(event).await;
+    }
+
+    async fn handle_unselected_input(&mut self, event: &::termion::event::Event) {
+        use termion::event::Event::*;
+        use termion::event::Key::*;
         use termion::event::{MouseButton, MouseEvent};
 
+        // Anything other than typing or moving through the completion menu closes it
         match *event {
-            Key(Char('\n')) => {
-                if !self.current_channel().message_buffer.is_empty() {
-                    self.send_message().await;
-                    self.cursor_pos = 0;
-                }
+            Key(Char(_)) | Key(Backspace) | Key(Up) | Key(Down) | Key(BackTab) => {}
+            _ => self.autocompletions.clear(),
+        }
+
+        match *event {
+            Key(Char('\n')) if !self.autocompletions.is_empty() => {
+                self.accept_completion();
             }
-            Key(Backspace) => {
-                if self.cursor_pos > 0 {
-                    let remove_pos = self.cursor_pos as usize - 1;
-                    self.current_channel_mut().message_buffer.remove(remove_pos);
-                    self.cursor_pos -= 1;
+            Key(Up) | Key(BackTab) if !self.autocompletions.is_empty() => {
+                let len = self.autocompletions.len();
+                self.autocomplete_index = (self.autocomplete_index + len - 1) % len;
+            }
+            Key(Down) if !self.autocompletions.is_empty() => {
+                self.autocomplete_index =
+                    (self.autocomplete_index + 1) % self.autocompletions.len();
+            }
+            // While search results are shown the arrow keys move through them, and Enter on an empty
+            // buffer jumps to the selected one
+            Key(Char('\n')) if self.search.is_some() && self.current_channel().input.is_empty() => {
+                self.jump_to_search_result().await;
+            }
+            Key(Up) if self.search.is_some() => {
+                if let Some(search) = &mut self.search {
+                    search.selected = search.selected.saturating_sub(1);
                 }
             }
-            Key(Delete) => {
-                let buffer_chars = self.current_channel().message_buffer.chars().count();
-                if buffer_chars > 0 && self.cursor_pos < buffer_chars {
-                    let remove_pos = self.cursor_pos;
-                    self.current_channel_mut().message_buffer.remove(remove_pos);
+            Key(Down) if self.search.is_some() => {
+                if let Some(search) = &mut self.search {
+                    search.selected =
+                        min(search.selected + 1, search.results.len().saturating_sub(1));
                 }
             }
-            Key(Ctrl('c')) => {
-                error!("got shutdown request");
-                self.shutdown = true;
+            Key(Esc) if self.search.is_some() => {
+                self.search = None;
             }
-            Key(Up) => {
-                self.previous_channel().await;
+            Key(Esc) if self.editing_current().is_some() => {
+                self.editing = None;
+                self.current_channel_mut().input.clear();
             }
-            Key(Down) => {
-                self.next_channel().await;
+            // Once there's a draft to come back to, or we're already looking at an old message, Up
+            // and Down move between the lines of the input, and past the first or last line go
+            // through what was sent here before. With nothing typed they're up to the keymap, which
+            // switches channels with them by default; Ctrl-p and Ctrl-n reach the history from there.
+            Key(Up)
+                if !self.current_channel().input.is_empty()
+                    || self.current_channel().input.is_browsing_history() =>
+            {
+                let input = &mut self.current_channel_mut().input;
+                if !input.line_up() {
+                    input.history_older();
+                }
             }
-            Key(Ctrl('d')) => {
-                self.next_server().await;
+            Key(Down)
+                if !self.current_channel().input.is_empty()
+                    || self.current_channel().input.is_browsing_history() =>
+            {
+                let input = &mut self.current_channel_mut().input;
+                if !input.line_down() {
+                    input.history_newer();
+                }
             }
-            Key(Ctrl('a')) => {
-                self.previous_server().await;
+            Key(Esc) if self.current_channel().open_thread.is_some() => {
+                let channel = self.current_channel_mut();
+                channel.open_thread = None;
+                channel.message_scroll_offset = 0;
             }
-            Key(PageDown) | Key(Ctrl('s')) => {
-                self.next_channel_unread().await;
+            Key(key) if self.keymap.action(key).is_some() => {
+                if let Some(action) = self.keymap.action(key) {
+                    self.run_action(action).await;
+                }
             }
-            Key(PageUp) | Key(Ctrl('w')) => {
-                self.previous_channel_unread().await;
+            Key(Char('\n')) => {
+                if !self.current_channel().input.is_empty() {
+                    self.send_message().await;
+                }
             }
-            Key(Ctrl('q')) | Mouse(MouseEvent::Press(MouseButton::WheelUp, ..)) => {
-                self.current_channel_mut().message_scroll_offset += 1;
+            // Alt-Enter starts a new line instead of sending
+            Key(Alt('\r')) | Key(Alt('\n')) => {
+                self.current_channel_mut().input.insert('\n');
             }
-            Key(Ctrl('e')) | Mouse(MouseEvent::Press(MouseButton::WheelDown, ..)) => {
-                let chan = self.current_channel_mut();
-                let previous_offset = chan.message_scroll_offset;
-                chan.message_scroll_offset = previous_offset.saturating_sub(1);
+            Key(Backspace) => {
+                self.current_channel_mut().input.backspace();
+                self.refilter_completions();
             }
-            Key(Left) => {
-                if self.cursor_pos > 0 {
-                    self.cursor_pos -= 1;
-                }
+            Key(Delete) => {
+                self.current_channel_mut().input.delete();
+                self.refilter_completions();
             }
-            Key(Right) => {
-                if self.cursor_pos < self.current_channel().message_buffer.len() {
-                    self.cursor_pos += 1;
+            Key(Ctrl('w')) | Key(Alt('\x7f')) | Key(Ctrl('u')) | Key(Ctrl('k')) => {
+                let input = &mut self.current_channel_mut().input;
+                let killed = match *event {
+                    Key(Ctrl('w')) => input.kill_word_back(),
+                    Key(Alt('\x7f')) => input.kill_alphanumeric_back(),
+                    Key(Ctrl('u')) => input.kill_to_start(),
+                    _ => input.kill_to_end(),
+                };
+                // Cutting nothing keeps what was cut before, ready to paste back
+                if !killed.is_empty() {
+                    self.killed = killed;
                 }
+                self.refilter_completions();
+            }
+            Key(Ctrl('y')) => {
+                let killed = self.killed.clone();
+                self.current_channel_mut().input.insert_str(&killed);
+                self.refilter_completions();
+            }
+            Mouse(MouseEvent::Press(MouseButton::WheelUp, ..)) => {
+                self.run_action(Action::ScrollUp).await;
+            }
+            Mouse(MouseEvent::Press(MouseButton::WheelDown, ..)) => {
+                self.run_action(Action::ScrollDown).await;
+            }
+            Key(Left) => self.current_channel_mut().input.left(),
+            Key(Right) => self.current_channel_mut().input.right(),
+            Key(Home) => self.current_channel_mut().input.home(),
+            Key(End) => self.current_channel_mut().input.end(),
+            Key(Alt('b')) => self.current_channel_mut().input.word_left(),
+            Key(Alt('f')) => self.current_channel_mut().input.word_right(),
+            Key(Ctrl('p')) => {
+                self.current_channel_mut().input.history_older();
+            }
+            Key(Ctrl('n')) => {
+                self.current_channel_mut().input.history_newer();
             }
             Key(Char('\t')) => {
                 if self.autocompletions.is_empty() {
-                    // Pick a source to autocomplete from
-                    let search_name_fragment = self
-                        .current_channel()
-                        .message_buffer
-                        .splitn(2, ' ')
-                        .nth(1)
-                        .unwrap_or_default();
-
-                    if self.current_channel().message_buffer.starts_with("/c ") {
-                        // Autocomplete from current server's channel names
-                        self.autocompletions = self
-                            .servers
-                            .get()
-                            .channels
-                            .iter()
-                            .map(|c| c.name.to_string())
-                            .filter(|name| name.starts_with(search_name_fragment))
-                            .collect();
-                    } else if self.current_channel().message_buffer.starts_with("/s ") {
-                        // Autocomplete from available server names
-                        self.autocompletions = self
-                            .servers
-                            .iter()
-                            .map(|s| s.name.to_string())
-                            .filter(|name| name.starts_with(search_name_fragment))
-                            .collect();
-                    } else if self
-                        .current_channel()
-                        .message_buffer
-                        .starts_with("/upload ")
-                    {
-                        fn complete_from(argument: &str) -> Option<Vec<String>> {
-                            use std::path::Path;
-
-                            let current_dir = std::env::current_dir().unwrap();
-                            let full_path = current_dir.join(Path::new(&argument));
-                            let start_of_entry = full_path.file_name()?.to_str()?;
-                            let dir_part = full_path.parent()?;
-
-                            let mut output = Vec::new();
-                            // Autocomplete from the path provided
-                            for entry in std::fs::read_dir(dir_part).unwrap() {
-                                let entry = entry.unwrap();
-                                let path = entry.path();
-                                if path.file_name()?.to_str()?.starts_with(start_of_entry) {
-                                    let mut suggestion = path
-                                        .strip_prefix(&current_dir)
-                                        .unwrap()
-                                        .to_str()?
-                                        .to_string();
-                                    if path.is_dir() {
-                                        suggestion.push(std::path::MAIN_SEPARATOR);
-                                    }
-                                    output.push(suggestion);
-                                }
-                            }
-                            Some(output)
-                        }
-
-                        let argument = self
-                            .current_channel()
-                            .message_buffer
-                            .splitn(2, ' ')
-                            .nth(1)
-                            .unwrap_or(".");
-
-                        self.autocompletions = complete_from(argument).unwrap_or_default();
-                        self.autocompletions.sort();
-                    } else {
-                        self.autocompletions = if let Some(last_word) = self
-                            .current_channel()
-                            .message_buffer
-                            .split_whitespace()
-                            .last()
-                        {
-                            self.servers
-                                .get()
-                                .completer
-                                .as_ref()
-                                .map(|c| c.autocomplete(last_word))
-                                .unwrap_or_default()
-                        } else {
-                            Vec::new()
-                        }
-                    }
-                }
-                if !self.autocompletions.is_empty() {
-                    while let Some(c) = self.current_channel().message_buffer.chars().last() {
-                        if c.is_whitespace() {
-                            break;
-                        } else {
-                            self.current_channel_mut().message_buffer.pop();
-                        }
+                    self.autocompletions = self.find_completions();
+                    self.autocomplete_index = 0;
+                    // No need for a menu if there's only one option
+                    if self.autocompletions.len() == 1 {
+                        self.accept_completion();
                     }
-                    self.autocomplete_index %= self.autocompletions.len();
-                    let chosen_completion = self.autocompletions[self.autocomplete_index].clone();
-                    self.current_channel_mut()
-                        .message_buffer
-                        .push_str(&chosen_completion);
-                    self.cursor_pos = self.current_channel().message_buffer.len();
-                    self.autocomplete_index += 1;
+                } else {
+                    self.autocomplete_index =
+                        (self.autocomplete_index + 1) % self.autocompletions.len();
                 }
             }
             Key(Char(c)) => {
-                let current_server_name = self.servers.get().name.clone();
-                let current_channel_name = self.current_channel().name.clone();
-                self.servers
-                    .get_mut()
-                    .sender
-                    .send(TuiEvent::SendTyping {
-                        server: current_server_name,
-                        channel: current_channel_name,
-                    })
-                    .await
-                    .unwrap();
-                self.autocompletions.clear();
-                self.autocomplete_index = 0;
-                let current_pos = self.cursor_pos as usize;
-                self.current_channel_mut()
-                    .message_buffer
-                    .insert(current_pos, c);
-                self.cursor_pos += 1;
+                if self.servers.get().capabilities.typing {
+                    let current_server_id = self.servers.get().id.clone();
+                    let current_channel_id = self.current_channel().id.clone();
+                    self.servers
+                        .get_mut()
+                        .sender
+                        .send(TuiEvent::SendTyping {
+                            server: current_server_id,
+                            channel: current_channel_id,
+                        })
+                        .await
+                        .unwrap();
+                }
+                self.current_channel_mut().input.insert(c);
+                self.refilter_completions();
             }
             Unsupported(ref bytes) => match bytes.as_slice() {
                 [27, 79, 65] => {
@@ -965,7 +2419,11 @@ impl Tui {
                         .await
                         .unwrap();
                 }
-
+                // Shift-Enter, from terminals that report it as something other than Enter
+                b"\x1b[13;2u" | b"\x1b[27;2;13~" => self.current_channel_mut().input.insert('\n'),
+                // Ctrl-Left and Ctrl-Right
+                [27, 91, 49, 59, 53, 68] => self.current_channel_mut().input.word_left(),
+                [27, 91, 49, 59, 53, 67] => self.current_channel_mut().input.word_right(),
                 _ => {}
             },
             _ => {}
@@ -973,126 +2431,176 @@ impl Tui {
     }
 
     async fn handle_event(&mut self, event: ConnEvent) {
+        use termion::event::Event;
+
         match event {
-            ConnEvent::Resize => {
-            } // Will be redrawn because we got an event
+            ConnEvent::Resize => {} // Will be redrawn because we got an event
+            ConnEvent::NotificationsDue => self.notifier.flush(),
+            ConnEvent::Input(Event::Unsupported(ref bytes)) if bytes == FOCUS_IN => {
+                self.focused = true;
+            }
+            ConnEvent::Input(Event::Unsupported(ref bytes)) if bytes == FOCUS_OUT => {
+                self.focused = false;
+            }
             ConnEvent::Input(event) => {
                 self.handle_input(&event).await;
             }
+            ConnEvent::Paste(text) => {
+                self.selection = None;
+                self.pending_delete = None;
+                self.current_channel_mut().input.insert_str(&text);
+                self.refilter_completions();
+            }
             ConnEvent::Message(message) => {
-                self.add_message(message);
+                if let Some(store) = self.store_for(&message.server) {
+                    store.add_message(&message);
+                }
+                let my_name = self.my_name(&message.server);
+                let mentions = mentions_us(
+                    &message.sender,
+                    &message.contents,
+                    my_name.as_deref(),
+                    &self.keywords,
+                );
+                self.notify_about(&message, mentions);
+                self.add_message(message, mentions);
             }
             ConnEvent::MessageEdited {
                 server,
                 channel,
+                id,
                 contents,
-                timestamp,
             } => {
-                if let Some(msg) = self
-                    .servers
-                    .iter_mut()
-                    .find(|s| s.name == server)
-                    .or_else(|| {
-                        error!("Couldn't process edit request: No server named {}", server);
-                        None
-                    }).and_then(|server| server.channels.iter_mut().find(|c| c.name == channel))
-                    .or_else(|| {
-                        error!(
-                            "Couldn't process edit request: No channel named {} in server {}",
-                            channel, server
-                        );
-                        None
-                    }).and_then(|c| {
-                        c.messages
-                            .iter_mut()
-                            .rev()
-                            .find(|m| m.timestamp() == &timestamp)
-                    }).or_else(|| {
+                if let Some(store) = self.store_for(&server) {
+                    store.edit_message(&server, &channel, &id, &contents);
+                }
+                let my_name = self.my_name(&server);
+                let mentioned = is_mention(&contents, my_name.as_deref(), &self.keywords);
+                let mut edited = false;
+                if let Some(c) = self.channel_mut(&server, &channel) {
+                    for msg in c.messages_with_id_mut(&id) {
+                        msg.set_mentions_us(mentioned && Some(msg.sender()) != my_name.as_deref());
+                        msg.edit_to(contents.clone());
+                        edited = true;
+                    }
+                }
+                if !edited {
+                    error!(
+                        "Couldn't process edit request: No message with id {} in server: {}, channel: {}",
+                        id, server, channel,
+                    );
+                }
+            }
+            ConnEvent::MessageDeleted {
+                server,
+                channel,
+                id,
+            } => {
+                if let Some(store) = self.store_for(&server) {
+                    store.delete_message(&server, &channel, &id);
+                }
+                if let Some(c) = self.channel_mut(&server, &channel) {
+                    if !c.remove_message(&id) {
                         error!(
-                            "Couldn't process edit request: No message with timestamp {} in server: {}, channel: {}",
-                            timestamp, server, channel,
+                            "Couldn't delete message: No message with id {} in server: {}, channel: {}",
+                            id, server, channel,
                         );
-                        None
-                    }) {
-                    msg.edit_to(contents);
                     }
+                }
             }
             ConnEvent::ReactionAdded {
                 server,
                 channel,
-                timestamp,
+                id,
                 reaction,
             } => {
-                if let Some(msg) = self
-                    .servers
-                    .iter_mut()
-                    .find(|s| s.name == server)
-                    .and_then(|server| server.channels.iter_mut().find(|c| c.name == channel))
-                    .and_then(|c| {
-                        c.messages
-                            .iter_mut()
-                            .rev()
-                            .find(|m| m.timestamp() == &timestamp)
-                    })
-                {
-                    msg.add_reaction(&reaction);
-                } else {
+                if let Some(store) = self.store_for(&server) {
+                    store.add_reaction(&server, &channel, &id, &reaction);
+                }
+                let mut reacted = false;
+                if let Some(c) = self.channel_mut(&server, &channel) {
+                    for msg in c.messages_with_id_mut(&id) {
+                        msg.add_reaction(&reaction);
+                        reacted = true;
+                    }
+                }
+                if !reacted {
                     error!(
-                        "Couldn't add reaction {} to message: server: {}, channel: {}, timestamp: {}",
-                        reaction, server, channel, timestamp
+                        "Couldn't add reaction {} to message: server: {}, channel: {}, id: {}",
+                        reaction, server, channel, id
                     );
                 }
             }
             ConnEvent::ReactionRemoved {
                 server,
                 channel,
-                timestamp,
+                id,
                 reaction,
             } => {
-                if let Some(msg) = self
-                    .servers
-                    .iter_mut()
-                    .find(|s| s.name == server)
-                    .and_then(|server| server.channels.iter_mut().find(|c| c.name == channel))
-                    .and_then(|c| {
-                        c.messages
-                            .iter_mut()
-                            .rev()
-                            .find(|m| m.timestamp() == &timestamp)
-                    })
-                {
-                    msg.remove_reaction(&reaction);
-                } else {
+                if let Some(store) = self.store_for(&server) {
+                    store.remove_reaction(&server, &channel, &id, &reaction);
+                }
+                let mut reacted = false;
+                if let Some(c) = self.channel_mut(&server, &channel) {
+                    for msg in c.messages_with_id_mut(&id) {
+                        msg.remove_reaction(&reaction);
+                        reacted = true;
+                    }
+                }
+                if !reacted {
                     error!(
-                        "Couldn't remove reaction {} from message server: {}, channel: {}, timestamp: {}",
-                        reaction, server, channel, timestamp
+                        "Couldn't remove reaction {} from message server: {}, channel: {}, id: {}",
+                        reaction, server, channel, id
                     );
                 }
             }
             ConnEvent::Error(message) => {
                 self.add_client_message(message);
             }
+            ConnEvent::Permalink { url, .. } => {
+                if copy_to_clipboard(url.as_bytes()) {
+                    self.add_client_message(format!("Copied a link to the message, {}", url));
+                }
+            }
             ConnEvent::HistoryLoaded {
                 messages,
                 server,
                 channel,
             } => {
-                if let Some(c) = self
-                    .servers
-                    .iter_mut()
-                    .find(|s| s.name == server)
-                    .and_then(|server| server.channels.iter_mut().find(|c| c.name == channel))
-                {
-                    // TODO: This duplicate check is quadratic and maybe not what we even want;
-                    // duplicate timestamps might be okay. Should we compare everything instead?
-                    for new_message in messages {
-                        if !c.messages.iter().any(|m| *m.timestamp() == new_message.timestamp) {
-                            c.messages.push(new_message.into());
+                let known: Option<HashSet<MessageId>> = self
+                    .channel_mut(&server, &channel)
+                    .map(|c| c.messages.iter().map(|m| m.id().clone()).collect());
+                if let Some(mut known) = known {
+                    // Backends send everything since the last sync, so we're caught up to here
+                    let newest = messages
+                        .iter()
+                        .max_by_key(|m| m.timestamp)
+                        .map(|m| m.id.clone());
+                    // A page of history can repeat a message, so it's known once it's taken
+                    let new_messages: Vec<Message> = messages
+                        .into_iter()
+                        .filter(|m| known.insert(m.id.clone()))
+                        .collect();
+                    if let Some(store) = self.store_for(&server) {
+                        for message in &new_messages {
+                            store.add_message(message);
+                        }
+                        if let Some(newest) = &newest {
+                            store.set_synced_through(&server, &channel, newest);
+                        }
+                    }
+                    let my_name = self.my_name(&server);
+                    let new_messages: Vec<ChanMessage> = new_messages
+                        .into_iter()
+                        .map(|m| chan_message(m, my_name.as_deref(), &self.keywords))
+                        .collect();
+                    if let Some(c) = self.channel_mut(&server, &channel) {
+                        c.insert_messages(new_messages);
+                        c.has_history = true;
+                        if newest.is_some() {
+                            c.synced_through = newest;
                         }
                     }
-                    c.messages
-                        .sort_unstable_by(|m1, m2| m1.timestamp().cmp(&m2.timestamp()));
-                    c.has_history = true;
                 } else {
                     error!(
                         "Got history for an unknown channel {} in server {}",
@@ -1100,23 +2608,102 @@ impl Tui {
                     );
                 }
             }
+            ConnEvent::SearchResults {
+                server,
+                channel,
+                messages,
+            } => {
+                if let Some(c) = self.channel_mut(&server, &channel) {
+                    c.messages.clear();
+                    c.threads.clear();
+                    c.message_scroll_offset = 0;
+                    c.insert_messages(messages.into_iter().map(ChanMessage::from));
+                } else {
+                    error!(
+                        "Got search results for an unknown channel {} in server {}",
+                        channel, server
+                    );
+                }
+            }
+            ConnEvent::ThreadLoaded {
+                server,
+                channel,
+                thread,
+                messages,
+            } => {
+                let known: Option<HashSet<MessageId>> =
+                    self.channel_mut(&server, &channel).map(|c| {
+                        c.threads
+                            .get(&thread)
+                            .map(|replies| replies.iter().map(|m| m.id().clone()).collect())
+                            .unwrap_or_default()
+                    });
+                if let Some(mut known) = known {
+                    let new_messages: Vec<Message> = messages
+                        .into_iter()
+                        .filter(|m| known.insert(m.id.clone()))
+                        .collect();
+                    if let Some(store) = self.store_for(&server) {
+                        for message in &new_messages {
+                            store.add_message(message);
+                        }
+                    }
+                    let my_name = self.my_name(&server);
+                    let new_messages: Vec<ChanMessage> = new_messages
+                        .into_iter()
+                        .map(|m| chan_message(m, my_name.as_deref(), &self.keywords))
+                        .collect();
+                    // Replies that arrived while the thread was loading are already in it
+                    if let Some(c) = self.channel_mut(&server, &channel) {
+                        let replies = c.threads.entry(thread).or_default();
+                        replies.extend(new_messages);
+                        replies.sort_unstable_by(|m1, m2| m1.timestamp().cmp(&m2.timestamp()));
+                    }
+                } else {
+                    error!(
+                        "Got a thread for an unknown channel {} in server {}",
+                        channel, server
+                    );
+                }
+            }
             ConnEvent::ServerConnected(server) => {
+                if let Some(store) = self.store_for(&server.id) {
+                    store.save_server(&server);
+                }
                 self.add_server(server);
             }
+            ConnEvent::ServerDisconnected { server } => {
+                if let Some(s) = self.server_mut(&server) {
+                    s.connected = false;
+                }
+            }
+            ConnEvent::ServerReconnected { server } => {
+                if let Some(s) = self.server_mut(&server) {
+                    s.connected = true;
+                    // Fill in whatever we missed in the channels we've already loaded
+                    if s.capabilities.history {
+                        for channel in s.channels.iter().filter(|c| c.has_history) {
+                            s.sender
+                                .send(TuiEvent::GetHistory {
+                                    channel: channel.id.clone(),
+                                    after: channel.synced_through.clone(),
+                                })
+                                .await
+                                .unwrap();
+                        }
+                    }
+                }
+            }
             ConnEvent::MarkChannelRead {
                 server,
                 channel,
                 read_at,
                 latest,
             } => {
-                let current_channel_name = self.current_channel().name.clone();
-                if let Some(c) = self
-                    .servers
-                    .iter_mut()
-                    .find(|s| s.name == server)
-                    .and_then(|server| server.channels.iter_mut().find(|c| c.name == channel))
-                {
-                    if current_channel_name != c.name {
+                let is_current =
+                    self.servers.get().id == server && self.current_channel().id == channel;
+                if let Some(c) = self.channel_mut(&server, &channel) {
+                    if !is_current {
                         read_at.map(|t| c.read_at = t);
                         latest.map(|t| c.latest = t);
                     }
@@ -1125,13 +2712,90 @@ impl Tui {
         }
     }
 
+    /// Give the terminal back the way we found it, so that another program can use it
+    fn suspend_terminal(&mut self) {
+        use std::io::Write;
+        self.input_paused.store(true, Ordering::SeqCst);
+        TerminalModes::set(false);
+        print!("{}", termion::screen::ToMainScreen);
+        let _ = std::io::stdout().flush();
+        if let Err(e) = self.guards.1.suspend_raw_mode() {
+            error!("Couldn't take the terminal out of raw mode: {}", e);
+        }
+    }
+
+    /// Take the terminal back after `suspend_terminal`
+    fn resume_terminal(&mut self) {
+        if let Err(e) = self.guards.1.activate_raw_mode() {
+            error!("Couldn't put the terminal back in raw mode: {}", e);
+        }
+        print!("{}", termion::screen::ToAlternateScreen);
+        TerminalModes::set(true);
+        self.input_paused.store(false, Ordering::SeqCst);
+        self.redraw_all = true;
+    }
+
+    /// Write a message in $VISUAL or $EDITOR, then put it in the input to look over before sending
+    async fn compose(&mut self, draft: String) {
+        let path = match write_draft(&draft) {
+            Ok(path) => path,
+            Err(e) => {
+                error!("Couldn't write a file to compose in: {}", e);
+                return;
+            }
+        };
+        let editor = std::env::var("VISUAL")
+            .or_else(|_| std::env::var("EDITOR"))
+            .unwrap_or_else(|_| "vi".to_string());
+        // Editors are often set with arguments, like "code --wait"
+        let mut words = editor.split_whitespace();
+        let program = words.next().unwrap_or("vi");
+
+        self.suspend_terminal();
+        let status = tokio::process::Command::new(program)
+            .args(words)
+            .arg(&path)
+            .status()
+            .await;
+        self.resume_terminal();
+
+        match status {
+            Ok(status) if status.success() => match std::fs::read_to_string(&path) {
+                // Editors like to end files with a newline, which nobody means to send
+                Ok(text) => self
+                    .current_channel_mut()
+                    .input
+                    .set_text(text.trim_end_matches('\n').to_string()),
+                Err(e) => error!("Couldn't read {}: {}", path.display(), e),
+            },
+            Ok(status) => error!("{} exited with {}, nothing was changed", program, status),
+            Err(e) => error!("Couldn't run {}: {}", program, e),
+        }
+        let _ = std::fs::remove_file(&path);
+    }
+
+    // Clearing fills the screen with the current background, so that the theme's shows
+    fn clear_screen(&self) {
+        let mut clear = String::new();
+        self.theme
+            .colors
+            .write_color(40, self.theme.background, &mut clear);
+        println!("{}{}", clear, termion::clear::All);
+    }
+
     pub async fn run(mut self) {
-        println!("{}", termion::clear::All);
+        self.clear_screen();
         let mut master_screen = crate::curses::Screen::new();
         self.draw(&mut master_screen);
         while let Some(event) = self.events.next().await {
             self.handle_event(event).await;
 
+            if self.redraw_all {
+                self.redraw_all = false;
+                self.clear_screen();
+                master_screen = crate::curses::Screen::new();
+            }
+
             self.draw(&mut master_screen);
 
             if self.shutdown {
@@ -1140,3 +2804,63 @@ impl Tui {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn substitutions_split_into_old_and_new() {
+        assert_eq!(parse_substitution("s/teh/the/"), Some(("teh", "the")));
+        assert_eq!(parse_substitution("s/typo//"), Some(("typo", "")));
+        assert_eq!(parse_substitution("s/a b/c d/"), Some(("a b", "c d")));
+        assert_eq!(parse_substitution("s/path/to/"), Some(("path", "to")));
+    }
+
+    #[test]
+    fn only_exact_substitutions_count() {
+        // Without the closing slash it's an ordinary message
+        assert_eq!(parse_substitution("s/teh/the"), None);
+        assert_eq!(parse_substitution("s//the/"), None);
+        assert_eq!(parse_substitution("s/a/b/c/"), None);
+        assert_eq!(parse_substitution("see s/teh/the/"), None);
+        assert_eq!(parse_substitution("s/"), None);
+    }
+
+    #[test]
+    fn mentions_are_whole_words() {
+        let keywords = vec!["deploy".to_string(), "on call".to_string()];
+        let mention = |text| is_mention(text, Some("ben"), &keywords);
+        assert!(mention("@ben can you look?"));
+        assert!(mention("thanks @Ben."));
+        assert!(mention("@here standup"));
+        assert!(mention("(@channel) lunch"));
+        assert!(mention("who's ON CALL tonight"));
+        assert!(mention("Deploy!"));
+        assert!(!mention("ben said so"));
+        assert!(!mention("@benjamin hi"));
+        assert!(!mention("email@ben_x"));
+        assert!(!mention("redeploy done"));
+        assert!(!mention("deployed"));
+        assert!(!mention("@hereafter"));
+    }
+
+    #[test]
+    fn mentions_need_something_to_match() {
+        assert!(!is_mention("@ben hi", None, &[]));
+        assert!(is_mention("@everyone hi", None, &[]));
+        assert!(!is_mention(
+            "anything at all",
+            Some("ben"),
+            &[String::new()]
+        ));
+    }
+
+    #[test]
+    fn our_own_messages_dont_mention_us() {
+        let keywords = vec!["deploy".to_string()];
+        assert!(mentions_us("amy", "@ben deploy", Some("ben"), &keywords));
+        assert!(!mentions_us("ben", "@ben deploy", Some("ben"), &keywords));
+        assert!(mentions_us("ben", "@here deploy", None, &keywords));
+    }
+}
@@ -0,0 +1,59 @@
+use crate::id::*;
+use crate::timestamp::Timestamp;
+
+/// Updates a message.
+///
+/// Wraps https://api.slack.com/methods/chat.update
+
+#[derive(Serialize, new)]
+pub struct UpdateRequest<'a> {
+    /// Channel containing the message to be updated.
+    pub channel: ConversationId,
+
+    /// Timestamp of the message to be updated.
+    pub ts: Timestamp,
+
+    /// New text for the message, using the default formatting rules.
+    pub text: &'a str,
+
+    /// Pass true to update the message as the authed user.
+    #[new(default)]
+    pub as_user: Option<bool>,
+}
+
+/// Deletes a message.
+///
+/// Wraps https://api.slack.com/methods/chat.delete
+
+#[derive(Serialize, new)]
+pub struct DeleteRequest {
+    /// Channel containing the message to be deleted.
+    pub channel: ConversationId,
+
+    /// Timestamp of the message to be deleted.
+    pub ts: Timestamp,
+
+    /// Pass true to delete the message as the authed user.
+    #[new(default)]
+    pub as_user: Option<bool>,
+}
+
+/// Retrieve a permalink URL for a specific extant message
+///
+/// Wraps https://api.slack.com/methods/chat.getPermalink
+
+#[derive(Serialize, new)]
+pub struct GetPermalinkRequest {
+    /// The ID of the conversation or channel containing the message
+    pub channel: ConversationId,
+
+    /// A message's ts value, uniquely identifying it within a channel
+    pub message_ts: Timestamp,
+}
+
+#[derive(Deserialize)]
+pub struct GetPermalinkResponse {
+    pub ok: bool,
+    pub channel: Option<ConversationId>,
+    pub permalink: Option<String>,
+}
@@ -34,7 +34,40 @@ pub struct HistoryRequest {
 
     /// Start of time range of messages to include in results.
     #[new(default)]
-    pub oldest: Option<u32>,
+    pub oldest: Option<Timestamp>,
+}
+
+/// Retrieve a thread of messages posted to a conversation
+///
+/// Wraps https://api.slack.com/methods/conversations.replies
+
+#[derive(Serialize, new)]
+pub struct RepliesRequest {
+    /// Conversation ID to fetch thread from.
+    pub channel: ConversationId,
+
+    /// Unique identifier of a thread's parent message.
+    pub ts: Timestamp,
+
+    /// Paginate through collections of data by setting the cursor parameter to a next_cursor attribute returned by a previous request's response_metadata. Default value fetches the first "page" of the collection. See pagination for more detail.
+    #[new(default)]
+    pub cursor: Option<Cursor>,
+
+    /// Include messages with latest or oldest timestamp in results only when either timestamp is specified
+    #[new(default)]
+    pub inclusive: Option<bool>,
+
+    /// End of time range of messages to include in results.
+    #[new(default)]
+    pub latest: Option<Timestamp>,
+
+    /// The maximum number of items to return. Fewer than the requested number of items may be returned, even if the end of the users list hasn't been reached.
+    #[new(default)]
+    pub limit: Option<u32>,
+
+    /// Start of time range of messages to include in results.
+    #[new(default)]
+    pub oldest: Option<Timestamp>,
 }
 
 /// Retrieve information about a conversation.
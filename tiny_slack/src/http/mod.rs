@@ -16,10 +16,12 @@ pub struct Paging {
 }
 
 pub mod channels;
+pub mod chat;
 pub mod conversations;
 pub mod emoji;
 pub mod groups;
 pub mod im;
 pub mod reactions;
 pub mod rtm;
+pub mod search;
 pub mod users;
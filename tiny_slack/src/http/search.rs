@@ -0,0 +1,80 @@
+use crate::http::Paging;
+use crate::id::*;
+use crate::timestamp::Timestamp;
+
+#[derive(Clone, Debug, Serialize)]
+pub enum SortDirection {
+    #[serde(rename = "asc")]
+    Ascending,
+    #[serde(rename = "desc")]
+    Descending,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortBy {
+    Score,
+    Timestamp,
+}
+
+/// Searches for messages matching a query.
+///
+/// Wraps https://api.slack.com/methods/search.messages
+
+#[derive(Serialize, new)]
+pub struct MessagesRequest<'a> {
+    /// Search query. May contains booleans, etc.
+    pub query: &'a str,
+
+    /// Return matches sorted by either score or timestamp.
+    #[new(default)]
+    pub sort: Option<SortBy>,
+
+    /// Change sort direction to ascending (asc) or descending (desc).
+    #[new(default)]
+    pub sort_dir: Option<SortDirection>,
+
+    /// Pass a value of true to enable query highlight markers.
+    #[new(default)]
+    pub highlight: Option<bool>,
+
+    /// Number of items to return per page.
+    #[new(default)]
+    pub count: Option<u32>,
+
+    /// Page number of results to return.
+    #[new(default)]
+    pub page: Option<u32>,
+}
+
+#[derive(Deserialize)]
+pub struct MessagesResponse {
+    pub ok: bool,
+    pub messages: MessagesResponseMessages,
+    pub query: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct MessagesResponseMessages {
+    #[serde(default)]
+    pub matches: Vec<Match>,
+    pub paging: Paging,
+    pub total: Option<u32>,
+}
+
+#[derive(Deserialize)]
+pub struct Match {
+    pub channel: MatchChannel,
+    pub user: Option<UserId>,
+    pub username: Option<String>,
+    pub ts: Timestamp,
+    pub text: String,
+    pub permalink: Option<String>,
+}
+
+/// The conversation a match was found in, names of direct messages are user IDs
+#[derive(Deserialize)]
+pub struct MatchChannel {
+    pub id: ConversationId,
+    pub name: String,
+}
@@ -35,7 +35,7 @@ pub struct ListResponse {
     pub is_limited: Option<bool>,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Debug)]
 pub struct User {
     pub id: UserId,
     pub name: String,
@@ -155,6 +155,29 @@ impl ConversationId {
     }
 }
 
+impl ::std::str::FromStr for ConversationId {
+    type Err = String;
+
+    #[inline]
+    fn from_str(input: &str) -> Result<Self, String> {
+        if input.len() > ID_LENGTH || input.is_empty() {
+            return Err(format!(
+                "ConversationId must be a 1-{} byte string, found {:?}",
+                ID_LENGTH, input
+            ));
+        }
+        match input.as_bytes()[0] {
+            b'C' => Ok(ConversationId::Channel(ChannelId::from(input))),
+            b'G' => Ok(ConversationId::Group(GroupId::from(input))),
+            b'D' => Ok(ConversationId::DirectMessage(DmId::from(input))),
+            _ => Err(format!(
+                "ConversationId must start with one of ['C', 'G', 'D'], found {:?}",
+                input
+            )),
+        }
+    }
+}
+
 impl ::std::fmt::Display for ConversationId {
     #[inline]
     fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
@@ -28,7 +28,11 @@ pub enum Event {
         attachments: Vec<Attachment>,
         #[serde(default)]
         files: Vec<File>,
-        message: Option<Message>,
+        message: Option<Box<Message>>,
+        deleted_ts: Option<Timestamp>,
+        /// Present on replies in a thread, and on the message that started it
+        thread_ts: Option<Timestamp>,
+        subtype: Option<String>,
     },
     ReactionAdded {
         item: Reactable,
@@ -38,17 +42,49 @@ pub enum Event {
         item: Reactable,
         reaction: String,
     },
+    UserChange {
+        user: crate::http::users::User,
+    },
+    TeamJoin {
+        user: crate::http::users::User,
+    },
+    ChannelCreated {
+        channel: NamedConversation,
+    },
+    ChannelRename {
+        channel: NamedConversation,
+    },
+    GroupRename {
+        channel: NamedConversation,
+    },
+    EmojiChanged {
+        subtype: String,
+        /// Set when an emoji is added
+        name: Option<String>,
+        /// Set when emoji are removed
+        #[serde(default)]
+        names: Vec<String>,
+    },
     Hello {},
     PrefChange {},
     UserTyping {},
 }
 
+#[derive(Deserialize, Debug)]
+pub struct NamedConversation {
+    pub id: ConversationId,
+    pub name: String,
+}
+
 #[derive(Deserialize, Debug)]
 pub struct Message {
     pub edited: Option<Edit>,
     pub text: Option<String>,
     pub ts: Timestamp,
     pub user: UserId,
+    pub thread_ts: Option<Timestamp>,
+    #[serde(default)]
+    pub reply_count: u32,
 }
 
 #[derive(Deserialize, Debug)]
@@ -1,4 +1,4 @@
-use serde::de::{self, Deserialize, Deserializer, Error, Visitor};
+use serde::de::{self, Deserialize, Deserializer, Visitor};
 use serde::ser::{Serialize, Serializer};
 use std::fmt;
 
@@ -20,38 +20,47 @@ impl<'de> Visitor<'de> for TimestampVisitor {
     where
         E: de::Error,
     {
+        value.parse().map_err(E::custom)
+    }
+
+    #[inline]
+    fn visit_u64<E>(self, value: u64) -> Result<Timestamp, E>
+    where
+        E: de::Error,
+    {
+        Ok(Timestamp {
+            microseconds: value as i64 * 1_000_000,
+        })
+    }
+}
+
+impl ::std::str::FromStr for Timestamp {
+    type Err = String;
+
+    #[inline]
+    fn from_str(value: &str) -> Result<Timestamp, String> {
         if value.len() <= 17 {
             // Split at the decimal point
             let dot_location = value
                 .find('.')
-                .ok_or_else(|| Error::custom("Got a string without a ."))?;
+                .ok_or_else(|| String::from("Got a string without a ."))?;
             let (seconds_str, micros_str) = value.split_at(dot_location);
             let seconds = seconds_str
                 .parse::<i64>()
-                .map_err(|_| Error::custom(format!("Cannot parse {} as a number", seconds_str)))?;
+                .map_err(|_| format!("Cannot parse {} as a number", seconds_str))?;
             let microseconds = micros_str[1..]
                 .parse::<i64>()
-                .map_err(|_| Error::custom(format!("Cannot parse {} as a number", micros_str)))?;
+                .map_err(|_| format!("Cannot parse {} as a number", micros_str))?;
             Ok(Timestamp {
                 microseconds: seconds * 1_000_000 + microseconds,
             })
         } else {
-            Err(E::custom(format!(
+            Err(format!(
                 "Timestamps must be string or number with 16 decimal places, got {}",
                 value
-            )))
+            ))
         }
     }
-
-    #[inline]
-    fn visit_u64<E>(self, value: u64) -> Result<Timestamp, E>
-    where
-        E: de::Error,
-    {
-        Ok(Timestamp {
-            microseconds: value as i64 * 1_000_000,
-        })
-    }
 }
 
 impl<'de> Deserialize<'de> for Timestamp {